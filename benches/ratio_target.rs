@@ -0,0 +1,50 @@
+//!
+//! Encoding speed with and without `set_ratio_target`: while the running ratio meets the
+//! target, bytes are emitted as literals without searching for matches.
+//!
+//! Run with `cargo bench --bench ratio_target`.
+//!
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use embedded_heatshrink::HeatshrinkEncoder;
+use std::hint::black_box;
+
+/// Size of the benchmarked input
+const INPUT_LEN: usize = 4 << 20;
+
+/// The real-world fixture, repeated up to `INPUT_LEN` bytes
+fn input() -> Vec<u8> {
+    let fixture = include_bytes!("../tsz-compressed-data.bin");
+    fixture.iter().copied().cycle().take(INPUT_LEN).collect()
+}
+
+fn encode(data: &[u8], ratio_target: Option<f32>) -> Vec<u8> {
+    let mut encoder = HeatshrinkEncoder::new(11, 4).expect("Failed to create encoder");
+    if let Some(target) = ratio_target {
+        encoder.set_ratio_target(target);
+    }
+    let mut compressed = vec![];
+    encoder
+        .compress_chunk(data, &mut compressed)
+        .expect("Failed to sink input");
+    encoder.finalize(&mut compressed).expect("Failed to finish");
+    compressed
+}
+
+fn ratio_target(c: &mut Criterion) {
+    let data = input();
+
+    let mut group = c.benchmark_group("ratio_target/4MiB");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+    group.bench_function("none", |b| b.iter(|| encode(black_box(&data), None)));
+    // The full search compresses the fixture by about 1.43, targets above it are never met
+    for target in [1.1, 1.25] {
+        group.bench_function(format!("{target:.2}"), |b| {
+            b.iter(|| encode(black_box(&data), Some(target)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, ratio_target);
+criterion_main!(benches);
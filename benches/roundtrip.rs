@@ -0,0 +1,236 @@
+//!
+//! Roundtrip throughput over the committed fixtures for a representative subset of
+//! window and lookahead sizes.
+//!
+//! Run with `cargo bench --bench roundtrip`, optionally filtered, e.g.
+//! `cargo bench --bench roundtrip -- 'encode/random-data.bin'`.
+//!
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use embedded_heatshrink::{
+    decode_all, encode_all, HSDFinishRes, HSDPollRes, HSDSinkRes, HSEFinishRes, HSEPollRes,
+    HSESinkRes, HeatshrinkDecoder, HeatshrinkEncoder,
+};
+use std::hint::black_box;
+
+/// The text, incompressible, and real-world fixtures
+const FIXTURES: [(&str, &[u8]); 3] = [
+    (
+        "heatshrink_encoder.rs",
+        include_bytes!("../src/heatshrink_encoder.rs"),
+    ),
+    ("random-data.bin", include_bytes!("../random-data.bin")),
+    (
+        "tsz-compressed-data.bin",
+        include_bytes!("../tsz-compressed-data.bin"),
+    ),
+];
+
+/// From the smallest to the largest window, including the (9, 7) default of the CLI
+const WINDOW_LOOKAHEAD_PAIRS: [(u8, u8); 5] = [(4, 3), (8, 4), (9, 7), (12, 6), (15, 8)];
+
+/// Length of the all-zeros input, where every position chains to the same byte value
+const ZEROS_LEN: usize = 1 << 20;
+
+/// Length of the repetitive input, which decodes almost entirely from backreferences
+const REPETITIVE_LEN: usize = 1 << 20;
+
+/// Read and input buffer size, large enough that the state machines dominate
+const READ_SZ: usize = 512;
+
+fn roundtrip(c: &mut Criterion) {
+    for (name, data) in FIXTURES {
+        let mut encode = c.benchmark_group(format!("encode/{name}"));
+        encode.throughput(Throughput::Bytes(data.len() as u64));
+        encode.sample_size(10);
+        for (window_sz2, lookahead_sz2) in WINDOW_LOOKAHEAD_PAIRS {
+            encode.bench_with_input(
+                BenchmarkId::from_parameter(format!("w{window_sz2}_l{lookahead_sz2}")),
+                &data,
+                |b, data| {
+                    b.iter(|| encode_all(black_box(data), window_sz2, lookahead_sz2, READ_SZ))
+                },
+            );
+        }
+        encode.finish();
+
+        let mut decode = c.benchmark_group(format!("decode/{name}"));
+        decode.throughput(Throughput::Bytes(data.len() as u64));
+        decode.sample_size(10);
+        for (window_sz2, lookahead_sz2) in WINDOW_LOOKAHEAD_PAIRS {
+            let compressed = encode_all(data, window_sz2, lookahead_sz2, READ_SZ);
+            decode.bench_with_input(
+                BenchmarkId::from_parameter(format!("w{window_sz2}_l{lookahead_sz2}")),
+                &compressed,
+                |b, compressed| {
+                    b.iter(|| {
+                        decode_all(
+                            black_box(compressed),
+                            READ_SZ,
+                            window_sz2,
+                            lookahead_sz2,
+                            READ_SZ,
+                        )
+                        .expect("Failed to decode")
+                    })
+                },
+            );
+        }
+        decode.finish();
+    }
+}
+
+fn zeros(c: &mut Criterion) {
+    let data = vec![0u8; ZEROS_LEN];
+    let mut encode = c.benchmark_group("encode/zeros-1MiB");
+    encode.throughput(Throughput::Bytes(data.len() as u64));
+    encode.sample_size(10);
+    for (window_sz2, lookahead_sz2) in WINDOW_LOOKAHEAD_PAIRS {
+        encode.bench_with_input(
+            BenchmarkId::from_parameter(format!("w{window_sz2}_l{lookahead_sz2}")),
+            &data,
+            |b, data| b.iter(|| encode_all(black_box(data), window_sz2, lookahead_sz2, READ_SZ)),
+        );
+    }
+    encode.finish();
+}
+
+/// Decode `compressed`, passing each run of output to `consume`, with backreferences taken
+/// from the window by `poll_borrowed` if `borrowed`, otherwise all copied out by `poll`
+fn decode_with(
+    compressed: &[u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    borrowed: bool,
+    mut consume: impl FnMut(&[u8]),
+) {
+    let mut decoder = HeatshrinkDecoder::new(READ_SZ as u16, window_sz2, lookahead_sz2)
+        .expect("Failed to create decoder");
+    let mut out = [0; READ_SZ];
+    let mut poll = |decoder: &mut HeatshrinkDecoder| loop {
+        if borrowed {
+            while let Some(bytes) = decoder.poll_borrowed() {
+                consume(bytes);
+            }
+        }
+        match decoder.poll(&mut out) {
+            HSDPollRes::Empty(sz) => {
+                consume(&out[..sz]);
+                break;
+            }
+            HSDPollRes::More(sz) => consume(&out[..sz]),
+            e => panic!("Failed to poll: {:?}", e),
+        }
+    };
+    for chunk in compressed.chunks(READ_SZ) {
+        assert_eq!(decoder.sink(chunk), HSDSinkRes::Ok(chunk.len()));
+        poll(&mut decoder);
+    }
+    while decoder.finish() == HSDFinishRes::More {
+        poll(&mut decoder);
+    }
+}
+
+fn borrowed(c: &mut Criterion) {
+    let data: Vec<u8> = b"sensor=12 value=0042 ok\n"
+        .iter()
+        .copied()
+        .cycle()
+        .take(REPETITIVE_LEN)
+        .collect();
+    let mut decode = c.benchmark_group("decode/repetitive-1MiB");
+    decode.throughput(Throughput::Bytes(data.len() as u64));
+    decode.sample_size(10);
+    for (window_sz2, lookahead_sz2) in [(8, 4), (12, 8)] {
+        let compressed = encode_all(&data, window_sz2, lookahead_sz2, READ_SZ);
+        for (path, borrowed) in [("poll", false), ("poll_borrowed", true)] {
+            decode.bench_with_input(
+                BenchmarkId::new(path, format!("w{window_sz2}_l{lookahead_sz2}")),
+                &compressed,
+                |b, compressed| {
+                    b.iter(|| {
+                        let mut checksum = 0u32;
+                        decode_with(
+                            black_box(compressed),
+                            window_sz2,
+                            lookahead_sz2,
+                            borrowed,
+                            |bytes| {
+                                checksum = bytes
+                                    .iter()
+                                    .fold(checksum, |sum, &b| sum.rotate_left(1) ^ b as u32)
+                            },
+                        );
+                        checksum
+                    })
+                },
+            );
+        }
+    }
+    decode.finish();
+}
+
+/// Encode `data` into a Vec, either polling into its spare capacity with `poll_uninit`
+/// if `uninit`, or into a zeroed buffer with `poll` and copying out
+fn encode_with(data: &[u8], window_sz2: u8, lookahead_sz2: u8, uninit: bool) -> Vec<u8> {
+    let mut encoder =
+        HeatshrinkEncoder::new(window_sz2, lookahead_sz2).expect("Failed to create encoder");
+    let mut compressed = Vec::new();
+    let mut buf = [0; READ_SZ];
+    let mut poll = |encoder: &mut HeatshrinkEncoder, out: &mut Vec<u8>| loop {
+        let res = if uninit {
+            out.reserve(READ_SZ);
+            let res = encoder.poll_uninit(out.spare_capacity_mut());
+            if let HSEPollRes::Empty(sz) | HSEPollRes::More(sz) = res {
+                // SAFETY: poll_uninit initialized the first `sz` bytes of spare capacity
+                unsafe { out.set_len(out.len() + sz) };
+            }
+            res
+        } else {
+            let res = encoder.poll(&mut buf);
+            if let HSEPollRes::Empty(sz) | HSEPollRes::More(sz) = res {
+                out.extend_from_slice(&buf[..sz]);
+            }
+            res
+        };
+        match res {
+            HSEPollRes::Empty(_) => break,
+            HSEPollRes::More(_) => {}
+            e => panic!("Failed to poll: {:?}", e),
+        }
+    };
+    for mut chunk in data.chunks(READ_SZ) {
+        while !chunk.is_empty() {
+            match encoder.sink(chunk) {
+                HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                e => panic!("Failed to sink: {:?}", e),
+            }
+            poll(&mut encoder, &mut compressed);
+        }
+    }
+    while encoder.finish() == HSEFinishRes::More {
+        poll(&mut encoder, &mut compressed);
+    }
+    compressed
+}
+
+fn uninit(c: &mut Criterion) {
+    let (name, data) = FIXTURES[2];
+    let mut encode = c.benchmark_group(format!("encode-poll/{name}"));
+    encode.throughput(Throughput::Bytes(data.len() as u64));
+    encode.sample_size(10);
+    for (window_sz2, lookahead_sz2) in [(8, 4), (9, 7)] {
+        for (path, uninit) in [("poll", false), ("poll_uninit", true)] {
+            encode.bench_with_input(
+                BenchmarkId::new(path, format!("w{window_sz2}_l{lookahead_sz2}")),
+                &data,
+                |b, data| {
+                    b.iter(|| encode_with(black_box(data), window_sz2, lookahead_sz2, uninit))
+                },
+            );
+        }
+    }
+    encode.finish();
+}
+
+criterion_group!(benches, roundtrip, zeros, borrowed, uninit);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+//!
+//! Compressing many overlapping slices of one buffer: independent `encode_all` calls, which
+//! index every slice from scratch, against `encode_slice` on a `SharedIndex` built once.
+//!
+//! Run with `cargo bench --bench shared_index`.
+//!
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use embedded_heatshrink::{encode_all, SharedIndex};
+use std::hint::black_box;
+
+/// Size of the shared buffer
+const DATA_LEN: usize = 256 << 10;
+
+/// Length of each slice
+const SLICE_LEN: usize = 4 << 10;
+
+/// Distance between the starts of consecutive slices, so that each byte is in four slices
+const SLICE_STEP: usize = 1 << 10;
+
+/// Read and input buffer size for `encode_all`
+const READ_SZ: usize = 512;
+
+/// The text fixture, repeated up to `DATA_LEN` bytes
+fn data() -> Vec<u8> {
+    let fixture = include_bytes!("../src/heatshrink_encoder.rs");
+    fixture.iter().copied().cycle().take(DATA_LEN).collect()
+}
+
+fn shared_index(c: &mut Criterion) {
+    let data = data();
+    let starts: Vec<usize> = (0..=DATA_LEN - SLICE_LEN).step_by(SLICE_STEP).collect();
+
+    for (window_sz2, lookahead_sz2) in [(8, 4), (12, 6)] {
+        let mut group = c.benchmark_group(format!("slices/w{window_sz2}_l{lookahead_sz2}"));
+        group.throughput(Throughput::Bytes((starts.len() * SLICE_LEN) as u64));
+        group.sample_size(10);
+        group.bench_function("encode_all", |b| {
+            b.iter(|| {
+                for &start in &starts {
+                    let slice = &data[start..start + SLICE_LEN];
+                    black_box(encode_all(slice, window_sz2, lookahead_sz2, READ_SZ));
+                }
+            })
+        });
+        group.bench_function("encode_slice", |b| {
+            b.iter(|| {
+                // the index is built once per pass over all the slices
+                let index = SharedIndex::new(&data, window_sz2, lookahead_sz2).unwrap();
+                for &start in &starts {
+                    black_box(index.encode_slice(start..start + SLICE_LEN));
+                }
+            })
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, shared_index);
+criterion_main!(benches);
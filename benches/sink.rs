@@ -0,0 +1,49 @@
+//!
+//! Per-call overhead of feeding a large input to the encoder: `encode_all`, which sinks and
+//! polls in `read_sz` chunks through a scratch buffer, against a single `compress_chunk` into
+//! a `Vec`.
+//!
+//! Run with `cargo bench --bench sink`.
+//!
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use embedded_heatshrink::{encode_all, HeatshrinkEncoder};
+use std::hint::black_box;
+
+/// Size of the benchmarked input
+const INPUT_LEN: usize = 4 << 20;
+
+/// The real-world fixture, repeated up to `INPUT_LEN` bytes
+fn input() -> Vec<u8> {
+    let fixture = include_bytes!("../tsz-compressed-data.bin");
+    fixture.iter().copied().cycle().take(INPUT_LEN).collect()
+}
+
+fn sink(c: &mut Criterion) {
+    let data = input();
+    let (window_sz2, lookahead_sz2) = (8, 4);
+
+    let mut group = c.benchmark_group("sink/4MiB");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+    for read_sz in [64, 512] {
+        group.bench_function(format!("encode_all_read_{read_sz}"), |b| {
+            b.iter(|| encode_all(black_box(&data), window_sz2, lookahead_sz2, read_sz))
+        });
+    }
+    group.bench_function("compress_chunk", |b| {
+        b.iter(|| {
+            let mut encoder = HeatshrinkEncoder::new(window_sz2, lookahead_sz2)
+                .expect("Failed to create encoder");
+            let mut compressed = vec![];
+            encoder
+                .compress_chunk(black_box(&data), &mut compressed)
+                .expect("Failed to sink input");
+            encoder.finalize(&mut compressed).expect("Failed to finish");
+            compressed
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, sink);
+criterion_main!(benches);
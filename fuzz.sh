@@ -120,4 +120,15 @@ seq $((total_iterations * 40 / 100)) | parallel --bar --halt now,fail=1 -j "$(np
 if [ $? -ne 0 ]; then
     echo "An error occurred during mixed data processing. Exiting."
     exit 1
-fi
\ No newline at end of file
+fi
+# Run the decoder on arbitrary bytes, if cargo-fuzz is available
+if cargo +nightly fuzz --version > /dev/null 2>&1; then
+  echo "Fuzzing the decoder with arbitrary bytes..."
+  (cd fuzz && cargo +nightly fuzz run fuzz_decode_arbitrary -- -runs=$((total_iterations * 1000)))
+  if [ $? -ne 0 ]; then
+      echo "An error occurred while fuzzing the decoder. Exiting."
+      exit 1
+  fi
+else
+  echo "cargo-fuzz not found, skipping the decoder fuzz target"
+fi
@@ -0,0 +1,33 @@
+#![no_main]
+
+use embedded_heatshrink::*;
+use libfuzzer_sys::fuzz_target;
+
+// Feed arbitrary bytes straight to the decoder. Corrupt input may decode to garbage or
+// return an error, but it must never panic, hang, or exhaust memory.
+fuzz_target!(|data: &[u8]| {
+    let [window_byte, lookahead_byte, input_buffer_byte, stream @ ..] = data else {
+        return;
+    };
+
+    // Spread the first bytes over every valid parameter combination
+    let window_sz2 = HEATSHRINK_MIN_WINDOW_BITS
+        + window_byte % (HEATSHRINK_MAX_WINDOW_BITS - HEATSHRINK_MIN_WINDOW_BITS + 1);
+    let lookahead_sz2 = HEATSHRINK_MIN_LOOKAHEAD_BITS
+        + lookahead_byte % (window_sz2 - HEATSHRINK_MIN_LOOKAHEAD_BITS);
+    let input_buffer_size = *input_buffer_byte as usize + 1;
+
+    if let Ok(decompressed) = decode_all(
+        stream,
+        input_buffer_size,
+        window_sz2,
+        lookahead_sz2,
+        input_buffer_size,
+    ) {
+        // Every token takes at least 1 + window_sz2 + lookahead_sz2 bits and emits at most
+        // 1 << lookahead_sz2 bytes
+        let token_bits = 1 + window_sz2 as usize + lookahead_sz2 as usize;
+        let max_tokens = (stream.len() * 8).div_ceil(token_bits.min(9));
+        assert!(decompressed.len() <= max_tokens << lookahead_sz2);
+    }
+});
@@ -1,7 +1,7 @@
 #![no_main]
 
+use embedded_heatshrink::*;
 use libfuzzer_sys::fuzz_target;
-use tsz_heatshrink::*;
 
 // chosen based on bar chart in 'average-compression-tsz-data.png'
 const DEFAULT_WINDOW_BITS: u8 = 9;
@@ -1,25 +1,29 @@
-///!
-///! This is a simple CLI that reads from stdin and writes to stdout.
-///!
-///! Stdin is buffered, sunk through a `HeatshrinkEncoder`, and then written to stdout.
-///!
-///! If the `-d` flag is passed, stdin is buffered, sunk through a `HeatshrinkDecoder`, and then written to stdout.
-///!
-use std::io::{self};
+//!
+//! This is a simple CLI that reads from stdin and writes to stdout.
+//!
+//! Stdin is buffered, sunk through a `HeatshrinkEncoder`, and then written to stdout.
+//!
+//! If the `-d` flag is passed, stdin is buffered, sunk through a `HeatshrinkDecoder`, and then written to stdout.
+//!
+//! If the `--frame` flag is passed, stdin is split into newline-delimited messages that are
+//! compressed separately, so `hsz -d --frame` can write them back out one per line. Each
+//! message is written as its compressed length in bytes, a LEB128 varint (7 bits per byte,
+//! least significant first, high bit set on all but the last byte), followed by the
+//! heatshrink stream of the message without its newline. See `encode_all_multi`.
+//!
+//! `hsz bench <file>` compresses the file with every valid window and lookahead size and
+//! prints a table of the compressed size, ratio, and time of each, smallest first, to help
+//! pick the sizes for that kind of data. With the `parallel` feature the sizes are tried in
+//! parallel, which makes the times noisier.
+//!
+use std::io::{self, Read, Write};
 use std::process;
+use std::time::{Duration, Instant};
 
 use embedded_heatshrink::*;
 
-// chosen based on bar chart in 'average-compression-tsz-data.png'
-const DEFAULT_WINDOW_BITS: u8 = 9;
-const DEFAULT_LOOKAHEAD_BITS: u8 = 7;
-
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() > 2 {
-        eprintln!("Usage: {} [-d]", args[0]);
-        process::exit(1);
-    }
 
     // Use stdin and stdout for I/O
     let stdin = io::stdin();
@@ -27,22 +31,150 @@ fn main() {
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
 
-    let decompress = args.len() == 2 && args[1] == "-d";
-    if decompress {
-        decode(
-            DEFAULT_WINDOW_BITS,
-            DEFAULT_LOOKAHEAD_BITS,
-            &mut stdin,
-            &mut stdout,
-        );
+    if let Err(e) = run(&args, &mut stdin, &mut stdout) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String], stdin: &mut impl Read, stdout: &mut impl Write) -> Result<(), String> {
+    if args.get(1).map(String::as_str) == Some("bench") {
+        let [_, _, path] = args else {
+            return Err(format!("Usage: {} bench <file>", args[0]));
+        };
+        return bench(path, stdout);
+    }
+
+    let mut decompress = false;
+    let mut frame = false;
+    for arg in &args[1..] {
+        match arg.as_str() {
+            "-d" if !decompress => decompress = true,
+            "--frame" if !frame => frame = true,
+            _ => return Err(format!("Usage: {} [-d] [--frame] | bench <file>", args[0])),
+        }
+    }
+
+    match (decompress, frame) {
+        (false, false) => encode(DEFAULT_WINDOW_BITS, DEFAULT_LOOKAHEAD_BITS, stdin, stdout),
+        (true, false) => decode(DEFAULT_WINDOW_BITS, DEFAULT_LOOKAHEAD_BITS, stdin, stdout),
+        (false, true) => encode_frames(stdin, stdout),
+        (true, true) => decode_frames(stdin, stdout)?,
+    }
+    Ok(())
+}
+
+const FRAME_READ_SZ: usize = recommended_read_sz(DEFAULT_WINDOW_BITS);
+
+/// Compress each line of stdin as its own message, a missing newline at the end still ends
+/// the last message
+fn encode_frames(stdin: &mut impl Read, stdout: &mut impl Write) {
+    let mut input = vec![];
+    stdin
+        .read_to_end(&mut input)
+        .expect("Failed to read from stdin");
+    // Empty stdin has no messages, while a lone newline is one empty message
+    let lines = input.strip_suffix(b"\n").unwrap_or(&input);
+    let messages: Vec<&[u8]> = if input.is_empty() {
+        vec![]
     } else {
-        encode(
-            DEFAULT_WINDOW_BITS,
-            DEFAULT_LOOKAHEAD_BITS,
-            &mut stdin,
-            &mut stdout,
+        lines.split(|&b| b == b'\n').collect()
+    };
+    let framed = encode_all_multi(
+        messages,
+        DEFAULT_WINDOW_BITS,
+        DEFAULT_LOOKAHEAD_BITS,
+        FRAME_READ_SZ,
+    );
+    stdout
+        .write_all(&framed)
+        .expect("Failed to write to stdout");
+}
+
+/// Decompress each message and write it to stdout followed by a newline
+fn decode_frames(stdin: &mut impl Read, stdout: &mut impl Write) -> Result<(), String> {
+    let mut input = vec![];
+    stdin
+        .read_to_end(&mut input)
+        .expect("Failed to read from stdin");
+    let messages = decode_all_multi(
+        &input,
+        FRAME_READ_SZ,
+        DEFAULT_WINDOW_BITS,
+        DEFAULT_LOOKAHEAD_BITS,
+        FRAME_READ_SZ,
+    )
+    .map_err(|e| format!("Failed to decode framed input: {}", e))?;
+    for message in messages {
+        stdout
+            .write_all(&message)
+            .and_then(|_| stdout.write_all(b"\n"))
+            .expect("Failed to write to stdout");
+    }
+    Ok(())
+}
+
+/// Compressed size and time of one window and lookahead size in `bench`
+struct BenchRow {
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    compressed_len: usize,
+    elapsed: Duration,
+}
+
+/// Compress the file at `path` with every valid window and lookahead size and print a
+/// table of the results, smallest output first
+fn bench(path: &str, stdout: &mut impl Write) -> Result<(), String> {
+    let input = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let configs: Vec<(u8, u8)> = (HEATSHRINK_MIN_WINDOW_BITS..=HEATSHRINK_MAX_WINDOW_BITS)
+        .flat_map(|w| (HEATSHRINK_MIN_LOOKAHEAD_BITS..w).map(move |l| (w, l)))
+        .collect();
+    let measure = |&(window_sz2, lookahead_sz2): &(u8, u8)| {
+        let start = Instant::now();
+        let compressed = encode_all(
+            &input,
+            window_sz2,
+            lookahead_sz2,
+            recommended_read_sz(window_sz2),
+        );
+        BenchRow {
+            window_sz2,
+            lookahead_sz2,
+            compressed_len: compressed.len(),
+            elapsed: start.elapsed(),
+        }
+    };
+    #[cfg(feature = "parallel")]
+    let mut rows: Vec<BenchRow> = {
+        use rayon::prelude::*;
+        configs.par_iter().map(measure).collect()
+    };
+    #[cfg(not(feature = "parallel"))]
+    let mut rows: Vec<BenchRow> = configs.iter().map(measure).collect();
+    rows.sort_by_key(|row| (row.compressed_len, row.elapsed));
+
+    let mut table = format!(
+        "{:>6} {:>9} {:>12} {:>7} {:>10}\n",
+        "window", "lookahead", "compressed", "ratio", "time_ms"
+    );
+    for row in &rows {
+        let ratio = if row.compressed_len == 0 {
+            "-".to_string()
+        } else {
+            format!("{:.3}", input.len() as f64 / row.compressed_len as f64)
+        };
+        table += &format!(
+            "{:>6} {:>9} {:>12} {:>7} {:>10.3}\n",
+            row.window_sz2,
+            row.lookahead_sz2,
+            row.compressed_len,
+            ratio,
+            row.elapsed.as_secs_f64() * 1000.0
         );
     }
+    stdout
+        .write_all(table.as_bytes())
+        .map_err(|e| format!("Failed to write to stdout: {}", e))
 }
 
 #[cfg(test)]
@@ -72,4 +204,83 @@ mod tests {
             assert_eq!(zeros, decompressed, "Failed at i = {}", i);
         }
     }
+
+    #[test]
+    fn frame_roundtrips_three_records() {
+        let args = |flags: &[&str]| {
+            let mut args = vec!["hsz".to_string()];
+            args.extend(flags.iter().map(|f| f.to_string()));
+            args
+        };
+        let records = b"first record\n\nthird record, third record, third record\n";
+
+        let mut compressed = vec![];
+        run(&args(&["--frame"]), &mut &records[..], &mut compressed).unwrap();
+        let mut decompressed = vec![];
+        run(
+            &args(&["-d", "--frame"]),
+            &mut compressed.as_slice(),
+            &mut decompressed,
+        )
+        .unwrap();
+        assert_eq!(decompressed, records);
+
+        let messages = decode_all_multi(
+            &compressed,
+            FRAME_READ_SZ,
+            DEFAULT_WINDOW_BITS,
+            DEFAULT_LOOKAHEAD_BITS,
+            FRAME_READ_SZ,
+        )
+        .unwrap();
+        assert_eq!(
+            messages,
+            [
+                &b"first record"[..],
+                b"",
+                b"third record, third record, third record"
+            ]
+        );
+
+        // A truncated stream is an error, and so are unknown flags
+        assert!(run(
+            &args(&["-d", "--frame"]),
+            &mut &compressed[..compressed.len() - 1],
+            &mut vec![],
+        )
+        .is_err());
+        assert!(run(&args(&["--frame", "-x"]), &mut &b""[..], &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn bench_prints_sorted_table() {
+        let args = |rest: &[&str]| {
+            let mut args = vec!["hsz".to_string()];
+            args.extend(rest.iter().map(|f| f.to_string()));
+            args
+        };
+
+        let mut table = vec![];
+        run(
+            &args(&["bench", "src/bin/hsz.rs"]),
+            &mut &b""[..],
+            &mut table,
+        )
+        .unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].contains("compressed"));
+        let configs = (HEATSHRINK_MIN_WINDOW_BITS..=HEATSHRINK_MAX_WINDOW_BITS)
+            .map(|w| (w - HEATSHRINK_MIN_LOOKAHEAD_BITS) as usize)
+            .sum::<usize>();
+        assert_eq!(lines.len(), 1 + configs);
+        let sizes: Vec<usize> = lines[1..]
+            .iter()
+            .map(|line| line.split_whitespace().nth(2).unwrap().parse().unwrap())
+            .collect();
+        assert!(sizes.windows(2).all(|w| w[0] <= w[1]));
+
+        assert!(run(&args(&["bench"]), &mut &b""[..], &mut vec![]).is_err());
+        assert!(run(&args(&["bench", "missing.bin"]), &mut &b""[..], &mut vec![]).is_err());
+    }
 }
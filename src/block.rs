@@ -0,0 +1,273 @@
+//!
+//! Block format for random access into large compressed inputs.
+//!
+//! The input is split into `block_size` chunks that are compressed independently, so any
+//! block can be decompressed without touching the others. The compressed blocks are
+//! concatenated and described by a table of `BlockOffset`s that the caller stores
+//! alongside the data.
+//!
+//! The CRC block format is self-describing instead: every compressed block is stored as a
+//! `[length][crc][compressed bytes]` record, so a damaged block is detected and skipped
+//! while the blocks around it still decode.
+//!
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::{
+    common::{crc32, read_varint, write_varint},
+    decode_all, HSEFinishRes, HSESinkRes, HeatshrinkEncoder, HeatshrinkError,
+};
+
+/// Read size used to stream a compressed block through the decoder
+const BLOCK_READ_SIZE: usize = 1024;
+
+/// Bytes of the little-endian CRC-32 in a CRC block record
+const CRC_LEN: usize = 4;
+
+/// Location of one independently compressed block
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BlockOffset {
+    /// offset of the block's first byte in the original input
+    pub uncompressed_offset: usize,
+    /// offset of the block's first byte in the compressed data
+    pub compressed_offset: usize,
+    /// number of compressed bytes in the block
+    pub compressed_len: usize,
+}
+
+/// Compress `input` as independent `block_size` blocks, returning the concatenated
+/// compressed blocks and the index describing where each block lives.
+///
+/// With the `parallel` feature, blocks are compressed in parallel with rayon.
+///
+/// Panics if `block_size` is 0 or the window and lookahead sizes are invalid.
+pub fn encode_blocks(
+    input: &[u8],
+    block_size: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+) -> (Vec<u8>, Vec<BlockOffset>) {
+    assert!(block_size > 0, "block_size must be greater than 0");
+
+    #[cfg(feature = "parallel")]
+    let blocks: Vec<Vec<u8>> = input
+        .par_chunks(block_size)
+        .map_init(
+            || HeatshrinkEncoder::new(window_sz2, lookahead_sz2).expect("Failed to create encoder"),
+            |encoder, block| {
+                let mut compressed = Vec::new();
+                encode_block(encoder, block, &mut compressed);
+                compressed
+            },
+        )
+        .collect();
+
+    #[cfg(not(feature = "parallel"))]
+    let blocks: Vec<Vec<u8>> = {
+        let mut encoder =
+            HeatshrinkEncoder::new(window_sz2, lookahead_sz2).expect("Failed to create encoder");
+        input
+            .chunks(block_size)
+            .map(|block| {
+                let mut compressed = Vec::new();
+                encode_block(&mut encoder, block, &mut compressed);
+                compressed
+            })
+            .collect()
+    };
+
+    let mut data = Vec::with_capacity(blocks.iter().map(Vec::len).sum());
+    let mut index = Vec::with_capacity(blocks.len());
+    for (i, block) in blocks.iter().enumerate() {
+        index.push(BlockOffset {
+            uncompressed_offset: i * block_size,
+            compressed_offset: data.len(),
+            compressed_len: block.len(),
+        });
+        data.extend_from_slice(block);
+    }
+
+    (data, index)
+}
+
+/// Decompress only block `i` of data produced by `encode_blocks`.
+///
+/// Returns `HeatshrinkError::BlockOutOfRange` if `i` is not in `index` or the index
+/// points outside of `data`.
+pub fn decode_block(
+    data: &[u8],
+    index: &[BlockOffset],
+    i: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+) -> Result<Vec<u8>, HeatshrinkError> {
+    let block = index.get(i).ok_or(HeatshrinkError::BlockOutOfRange)?;
+    let compressed = block
+        .compressed_offset
+        .checked_add(block.compressed_len)
+        .and_then(|end| data.get(block.compressed_offset..end))
+        .ok_or(HeatshrinkError::BlockOutOfRange)?;
+    decode_all(
+        compressed,
+        BLOCK_READ_SIZE,
+        window_sz2,
+        lookahead_sz2,
+        BLOCK_READ_SIZE,
+    )
+}
+
+/// Compress `input` as independent `block_size` blocks like `encode_blocks`, and store each
+/// one as a record of its compressed length as a LEB128 varint, the little-endian CRC-32 of
+/// its compressed bytes, and the compressed bytes, for `decode_crc_blocks`.
+///
+/// Panics if `block_size` is 0 or the window and lookahead sizes are invalid.
+pub fn encode_crc_blocks(
+    input: &[u8],
+    block_size: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+) -> Vec<u8> {
+    let (data, index) = encode_blocks(input, block_size, window_sz2, lookahead_sz2);
+    let mut records = Vec::with_capacity(data.len() + index.len() * (CRC_LEN + 3));
+    for block in index {
+        let compressed = &data[block.compressed_offset..][..block.compressed_len];
+        write_varint(&mut records, compressed.len());
+        records.extend_from_slice(&crc32(compressed).to_le_bytes());
+        records.extend_from_slice(compressed);
+    }
+    records
+}
+
+/// Decompress every block of data produced by `encode_crc_blocks`, one result per block.
+///
+/// A block whose bytes do not match their CRC is reported as
+/// `HeatshrinkError::BlockCrcMismatch` with its index, and the following blocks are still
+/// decoded. If a record header is damaged so that the records cannot be told apart anymore,
+/// the last result is `HeatshrinkError::Corrupt` and decoding stops there.
+pub fn decode_crc_blocks(
+    data: &[u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+) -> Vec<Result<Vec<u8>, HeatshrinkError>> {
+    let mut blocks = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let Some((compressed, crc, next)) = split_crc_record(rest) else {
+            blocks.push(Err(HeatshrinkError::Corrupt));
+            break;
+        };
+        blocks.push(if crc32(compressed) == crc {
+            decode_all(
+                compressed,
+                BLOCK_READ_SIZE,
+                window_sz2,
+                lookahead_sz2,
+                BLOCK_READ_SIZE,
+            )
+        } else {
+            Err(HeatshrinkError::BlockCrcMismatch(blocks.len()))
+        });
+        rest = next;
+    }
+    blocks
+}
+
+/// Split the record at the front of `data` into its compressed bytes, its stored CRC, and
+/// the data after it, or `None` if the record is truncated
+fn split_crc_record(data: &[u8]) -> Option<(&[u8], u32, &[u8])> {
+    let (len, prefix_len) = read_varint(data)?;
+    let rest = &data[prefix_len..];
+    let crc = u32::from_le_bytes(rest.get(..CRC_LEN)?.try_into().ok()?);
+    let rest = &rest[CRC_LEN..];
+    let compressed = rest.get(..len)?;
+    Some((compressed, crc, &rest[len..]))
+}
+
+/// Compress `block` as a complete stream, resetting `encoder` first
+fn encode_block(encoder: &mut HeatshrinkEncoder, block: &[u8], out: &mut Vec<u8>) {
+    encoder.reset();
+    let mut block = block;
+    while !block.is_empty() {
+        match encoder.sink(block) {
+            HSESinkRes::Ok(sz) => block = &block[sz..],
+            e => unreachable!("Logic error: {:?}", e),
+        }
+        encoder.poll_to(out).expect("Vec output cannot fill");
+    }
+    while encoder.finish() == HSEFinishRes::More {
+        encoder.poll_to(out).expect("Vec output cannot fill");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode_all;
+
+    #[test]
+    fn decode_middle_block() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let block_size = 4096;
+        let (data, index) = encode_blocks(input, block_size, 10, 5);
+        assert_eq!(index.len(), input.len().div_ceil(block_size));
+
+        let i = index.len() / 2;
+        let block = decode_block(&data, &index, i, 10, 5).expect("Failed to decode block");
+        let start = index[i].uncompressed_offset;
+        assert_eq!(block, &input[start..start + block_size]);
+
+        // Every block is a standalone stream
+        let last = index.last().unwrap();
+        assert_eq!(
+            &data[last.compressed_offset..],
+            encode_all(&input[last.uncompressed_offset..], 10, 5, 512)
+        );
+
+        assert_eq!(
+            decode_block(&data, &index, index.len(), 10, 5),
+            Err(HeatshrinkError::BlockOutOfRange)
+        );
+    }
+
+    #[test]
+    fn crc_of_check_string() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn corrupt_crc_block_is_flagged_alone() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let block_size = 4096;
+        let mut data = encode_crc_blocks(input, block_size, 10, 5);
+
+        let blocks = decode_crc_blocks(&data, 10, 5);
+        assert_eq!(blocks.len(), input.len().div_ceil(block_size));
+        for (block, expected) in blocks.iter().zip(input.chunks(block_size)) {
+            assert_eq!(block.as_deref(), Ok(expected));
+        }
+
+        // Flip a byte in the middle of the compressed bytes of block 2
+        let mut offset = 0;
+        for _ in 0..2 {
+            let (_, _, next) = split_crc_record(&data[offset..]).expect("Truncated record");
+            offset = data.len() - next.len();
+        }
+        let (compressed, _, next) = split_crc_record(&data[offset..]).expect("Truncated record");
+        let middle = data.len() - next.len() - compressed.len() / 2;
+        data[middle] ^= 0x55;
+
+        let blocks = decode_crc_blocks(&data, 10, 5);
+        assert_eq!(blocks.len(), input.len().div_ceil(block_size));
+        for (i, (block, expected)) in blocks.iter().zip(input.chunks(block_size)).enumerate() {
+            if i == 2 {
+                assert_eq!(block, &Err(HeatshrinkError::BlockCrcMismatch(2)));
+            } else {
+                assert_eq!(block.as_deref(), Ok(expected));
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//!
+//! A single value holding the window and lookahead sizes that both sides of a stream use,
+//! for callers who just want to compress and decompress buffers.
+//!
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use crate::{
+    decode_all, encode_all, heatshrink_encoder::valid_params, recommended_read_sz, Config,
+    HeatshrinkError,
+};
+
+/// Inputs shorter than this are stored by `Codec::encode` instead of compressed. Compressing
+/// them saves at most one byte over storing them behind `STORED_MARKER`, and allocates an
+/// encoder with buffers sized for the whole window.
+pub const STORED_INPUT_LEN: usize = 4;
+
+/// First byte of an input stored by `Codec::encode`, followed by the input bytes. A stream
+/// from this crate's encoder starts with a literal, whose set tag bit makes the first byte at
+/// least `0x80`, so `Codec::decode` cannot mistake one for a stored input.
+pub const STORED_MARKER: u8 = 0x00;
+
+/// Encodes and decodes whole buffers with one window and lookahead size, so the two sides
+/// cannot disagree. The read and input buffer sizes are picked internally.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Codec {
+    window_sz2: u8,
+    lookahead_sz2: u8,
+}
+
+impl Codec {
+    /// Returns `HeatshrinkError::InvalidParams` if the encoder would reject the sizes.
+    pub fn new(window_sz2: u8, lookahead_sz2: u8) -> Result<Self, HeatshrinkError> {
+        if !valid_params(window_sz2, lookahead_sz2) {
+            return Err(HeatshrinkError::InvalidParams);
+        }
+        Ok(Self {
+            window_sz2,
+            lookahead_sz2,
+        })
+    }
+
+    /// The window and lookahead sizes, e.g. to record next to the compressed data.
+    pub fn config(&self) -> Config {
+        Config {
+            window_sz2: self.window_sz2,
+            lookahead_sz2: self.lookahead_sz2,
+        }
+    }
+
+    /// Compress all of `input`. A non-empty input shorter than `STORED_INPUT_LEN` bytes is
+    /// stored as `STORED_MARKER` followed by its bytes, without creating an encoder.
+    pub fn encode(&self, input: &[u8]) -> Vec<u8> {
+        if !input.is_empty() && input.len() < STORED_INPUT_LEN {
+            let mut stored = Vec::with_capacity(1 + input.len());
+            stored.push(STORED_MARKER);
+            stored.extend_from_slice(input);
+            return stored;
+        }
+        encode_all(input, self.window_sz2, self.lookahead_sz2, self.read_sz())
+    }
+
+    /// Decompress all of `input`, the output of `encode`, returning
+    /// `HeatshrinkError::Corrupt` if it is not a valid stream for these sizes.
+    ///
+    /// Up to `STORED_INPUT_LEN` bytes starting with `STORED_MARKER` are taken as a stored
+    /// input. Heatshrink streams that start with a backref, like those of an encoder primed
+    /// with a dictionary, can be that short too, decode those with `decode_all` instead.
+    pub fn decode(&self, input: &[u8]) -> Result<Vec<u8>, HeatshrinkError> {
+        if let [STORED_MARKER, stored @ ..] = input {
+            if stored.len() < STORED_INPUT_LEN {
+                return Ok(stored.to_vec());
+            }
+        }
+        let read_sz = self.read_sz();
+        decode_all(
+            input,
+            read_sz.min(u16::MAX as usize),
+            self.window_sz2,
+            self.lookahead_sz2,
+            read_sz,
+        )
+    }
+
+    fn read_sz(&self) -> usize {
+        recommended_read_sz(self.window_sz2)
+    }
+}
+
+/// The blessed defaults, `DEFAULT_WINDOW_BITS` and `DEFAULT_LOOKAHEAD_BITS`.
+impl Default for Codec {
+    fn default() -> Self {
+        let Config {
+            window_sz2,
+            lookahead_sz2,
+        } = Config::default();
+        Self::new(window_sz2, lookahead_sz2).expect("Default parameters are valid")
+    }
+}
+
+impl TryFrom<Config> for Codec {
+    type Error = HeatshrinkError;
+
+    fn try_from(config: Config) -> Result<Self, Self::Error> {
+        Self::new(config.window_sz2, config.lookahead_sz2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_payloads() {
+        let codec = Codec::default();
+        let text = include_bytes!("codec.rs");
+        let random = include_bytes!("../random-data.bin");
+        for payload in [&b""[..], b"a", b"abcabcabcabc", text, random] {
+            let compressed = codec.encode(payload);
+            assert_eq!(codec.decode(&compressed), Ok(payload.to_vec()));
+        }
+
+        assert_eq!(Codec::new(4, 4), Err(HeatshrinkError::InvalidParams));
+        let wide = Codec::try_from(Config::from((12, 6))).expect("Failed to create codec");
+        assert_eq!(wide.config(), Config::from((12, 6)));
+        assert_eq!(wide.decode(&wide.encode(text)), Ok(text.to_vec()));
+    }
+
+    #[test]
+    fn tiny_inputs_are_stored() {
+        let codec = Codec::default();
+        for payload in [&b"a"[..], b"\x00\x00", b"abc"] {
+            // The encoder never emits a first byte below 0x80, so the marker shows that the
+            // input was stored, tests/codec_alloc.rs checks that no encoder was allocated
+            let stored = codec.encode(payload);
+            assert_eq!(stored[0], STORED_MARKER);
+            assert_eq!(&stored[1..], payload);
+            assert_eq!(codec.decode(&stored), Ok(payload.to_vec()));
+        }
+
+        assert_eq!(codec.encode(b""), b"");
+        let compressed = codec.encode(b"abcd");
+        assert!(compressed[0] >= 0x80);
+        assert_eq!(codec.decode(&compressed), Ok(b"abcd".to_vec()));
+    }
+}
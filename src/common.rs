@@ -1,16 +1,366 @@
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use core::mem::MaybeUninit;
+
+use crate::{HeatshrinkError, RingSink};
+
 // Heatshrink internal constants
 pub(crate) const HEATSHRINK_LITERAL_MARKER: u8 = 1;
 pub(crate) const HEATSHRINK_BACKREF_MARKER: u8 = 0;
 
+/// Check an invariant that the correctness of the output depends on. This is a
+/// `debug_assert!`, except with the `runtime-checks` feature, where it is always checked and
+/// returns `$fail`, the error state, from the enclosing state handler when it does not hold.
+macro_rules! check_invariant {
+    ($cond:expr, $fail:expr) => {
+        #[cfg(feature = "runtime-checks")]
+        if !$cond {
+            return $fail;
+        }
+        #[cfg(not(feature = "runtime-checks"))]
+        debug_assert!($cond);
+    };
+}
+pub(crate) use check_invariant;
+
+/// Destination memory that the state machines can poll bytes into
+pub(crate) trait OutputBuffer {
+    /// total number of bytes that fit in the buffer
+    fn capacity(&self) -> usize;
+    /// write `byte` at `index`, where `index < capacity()`
+    fn write(&mut self, index: usize, byte: u8);
+}
+
+impl OutputBuffer for [u8] {
+    #[inline(always)]
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    #[inline(always)]
+    fn write(&mut self, index: usize, byte: u8) {
+        debug_assert!(index < self.len());
+        unsafe { *self.get_unchecked_mut(index) = byte };
+    }
+}
+
+impl OutputBuffer for [MaybeUninit<u8>] {
+    #[inline(always)]
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    #[inline(always)]
+    fn write(&mut self, index: usize, byte: u8) {
+        debug_assert!(index < self.len());
+        unsafe { self.get_unchecked_mut(index).write(byte) };
+    }
+}
+
+/// Polling into a `RingSink` pushes each byte, there is room for one more byte as long
+/// as the ring is not full
+pub(crate) struct RingOutput<'a, R: RingSink + ?Sized> {
+    pub ring: &'a mut R,
+    /// bytes pushed to the ring, so far
+    pub pushed: usize,
+}
+
+impl<R: RingSink + ?Sized> OutputBuffer for RingOutput<'_, R> {
+    #[inline(always)]
+    fn capacity(&self) -> usize {
+        self.pushed + !self.ring.is_full() as usize
+    }
+
+    #[inline(always)]
+    fn write(&mut self, index: usize, byte: u8) {
+        debug_assert_eq!(index, self.pushed);
+        let pushed = self.ring.push(byte);
+        debug_assert!(pushed, "RingSink reported room but rejected a byte");
+        self.pushed += 1;
+    }
+}
+
+/// Polling through a callback hands each byte to it, there is always room for one more
+pub(crate) struct CallbackOutput<F: FnMut(u8)> {
+    pub sink: F,
+}
+
+impl<F: FnMut(u8)> OutputBuffer for CallbackOutput<F> {
+    #[inline(always)]
+    fn capacity(&self) -> usize {
+        usize::MAX
+    }
+
+    #[inline(always)]
+    fn write(&mut self, _index: usize, byte: u8) {
+        (self.sink)(byte);
+    }
+}
+
+/// Polling through a callback that reports back-pressure hands each byte to it, and there is
+/// room for one more byte until it returns `false`
+pub(crate) struct BackpressureOutput<F: FnMut(u8) -> bool> {
+    pub sink: F,
+    /// bytes passed to the callback, so far
+    pub pushed: usize,
+    /// whether the last call to the callback accepted more bytes
+    pub open: bool,
+}
+
+impl<F: FnMut(u8) -> bool> OutputBuffer for BackpressureOutput<F> {
+    #[inline(always)]
+    fn capacity(&self) -> usize {
+        self.pushed + self.open as usize
+    }
+
+    #[inline(always)]
+    fn write(&mut self, index: usize, byte: u8) {
+        debug_assert_eq!(index, self.pushed);
+        self.open = (self.sink)(byte);
+        self.pushed += 1;
+    }
+}
+
 // Heatshrink internal types
 #[derive(Debug)]
-pub(crate) struct OutputInfo<'a> {
+pub(crate) struct OutputInfo<'a, B: OutputBuffer + ?Sized> {
     /// output buffer
-    pub buf: &'a mut [u8],
+    pub buf: &'a mut B,
     /// bytes pushed to the buffer, so far
     pub output_size: &'a mut usize,
 }
 
+impl<B: OutputBuffer + ?Sized> OutputInfo<'_, B> {
+    /// bytes that can still be pushed to the buffer
+    #[inline(always)]
+    pub fn remaining(&self) -> usize {
+        self.buf.capacity() - *self.output_size
+    }
+
+    /// whether the buffer can take another byte
+    #[inline(always)]
+    pub fn has_room(&self) -> bool {
+        *self.output_size < self.buf.capacity()
+    }
+
+    /// push a byte to the end of the output, the caller must check `has_room` first
+    #[inline(always)]
+    pub fn write_byte(&mut self, byte: u8) {
+        self.buf.write(*self.output_size, byte);
+        *self.output_size += 1;
+    }
+}
+
+/// Append `v` as a LEB128 varint, 7 bits per byte with the high bit set on all but the last
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut v: usize) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+/// Parse a LEB128 varint from the front of `bytes`, returning it and the bytes it took,
+/// or `None` if it is truncated or does not fit in a `usize`
+pub(crate) fn read_varint(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut v: usize = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+        let shift = 7 * i as u32;
+        let bits = (byte & 0x7f) as usize;
+        if shift >= usize::BITS || (bits << shift) >> shift != bits {
+            return None;
+        }
+        v |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Some((v, i + 1));
+        }
+    }
+    None
+}
+
+/// Running CRC-32 (IEEE 802.3, as in zlib and PNG), computed bitwise to stay table-free
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub(crate) fn new() -> Self {
+        Self(!0)
+    }
+
+    /// Continue the CRC over `bytes`
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u32;
+            for _ in 0..8 {
+                self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & (self.0 & 1).wrapping_neg());
+            }
+        }
+    }
+
+    /// The CRC of all bytes passed to `update`
+    pub(crate) fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+/// CRC-32 of `bytes`, see `Crc32`
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+/// Packs bits MSB first, like the encoder's output
+pub(crate) struct BitWriter {
+    bytes: Vec<u8>,
+    /// bits not written to `bytes` yet, in the low `count` bits
+    pending: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Self {
+            bytes: Vec::with_capacity(capacity),
+            pending: 0,
+            count: 0,
+        }
+    }
+
+    /// Append the low `count` bits of `bits`, at most 16
+    #[inline]
+    pub(crate) fn push(&mut self, bits: usize, count: u8) {
+        debug_assert!(count <= 16);
+        self.pending = (self.pending << count) | (bits as u32 & ((1 << count) - 1));
+        self.count += count;
+        while self.count >= 8 {
+            self.count -= 8;
+            self.bytes.push((self.pending >> self.count) as u8);
+        }
+    }
+
+    /// Append the first `count` bits of `bytes`
+    pub(crate) fn push_slice(&mut self, bytes: &[u8], count: usize) {
+        let (whole, rest) = (count / 8, (count % 8) as u8);
+        for &byte in &bytes[..whole] {
+            self.push(byte as usize, 8);
+        }
+        if rest > 0 {
+            self.push((bytes[whole] >> (8 - rest)) as usize, rest);
+        }
+    }
+
+    /// The packed bytes, with the last one padded with zero bits
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push((self.pending << (8 - self.count)) as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Offsets and counts within the window, as wide as a backreference distance
+pub(crate) type WindowIndex = crate::Distance;
+
+/// Version tag leading every saved encoder or decoder state, the `wide-window` feature
+/// saves wider fields and the `literal-runs` feature saves the run in progress, so each
+/// sets its own bit. Version 2 added the encoder's total of sunk bytes,
+/// version 3 the decoder's total of emitted bytes, version 4 the encoder's
+/// `set_literal_runs` setting.
+pub(crate) const STATE_VERSION: u8 =
+    4 | if cfg!(feature = "wide-window") {
+        0x80
+    } else {
+        0
+    } | if cfg!(feature = "literal-runs") {
+        0x40
+    } else {
+        0
+    };
+
+/// Little-endian serialization of encoder and decoder state
+#[derive(Debug, Default)]
+pub(crate) struct StateWriter {
+    pub bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn put_u8(&mut self, v: u8) {
+        self.bytes.push(v);
+    }
+
+    pub fn put_u16(&mut self, v: u16) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn put_window_index(&mut self, v: WindowIndex) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, v: u64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// sizes are saved as `u32`, which holds every buffer offset of the largest window
+    pub fn put_usize(&mut self, v: usize) {
+        self.bytes.extend_from_slice(&(v as u32).to_le_bytes());
+    }
+
+    pub fn put_bytes(&mut self, v: &[u8]) {
+        self.bytes.extend_from_slice(v);
+    }
+}
+
+/// Reads back what a `StateWriter` wrote, failing with `InvalidState` if it runs out
+#[derive(Debug)]
+pub(crate) struct StateReader<'a> {
+    pub bytes: &'a [u8],
+}
+
+impl StateReader<'_> {
+    pub fn take(&mut self, n: usize) -> Result<&[u8], HeatshrinkError> {
+        if self.bytes.len() < n {
+            return Err(HeatshrinkError::InvalidState);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, HeatshrinkError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, HeatshrinkError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn get_window_index(&mut self) -> Result<WindowIndex, HeatshrinkError> {
+        let b = self.take(core::mem::size_of::<WindowIndex>())?;
+        Ok(WindowIndex::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, HeatshrinkError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn get_usize(&mut self) -> Result<usize, HeatshrinkError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    }
+
+    /// check that every byte was read
+    pub fn finish(&self) -> Result<(), HeatshrinkError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(HeatshrinkError::InvalidState)
+        }
+    }
+}
+
 #[inline]
 #[cold]
 fn cold() {}
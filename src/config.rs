@@ -0,0 +1,41 @@
+//!
+//! The window and lookahead sizes that the encoder and decoder of a stream must agree on.
+//!
+use crate::{DEFAULT_LOOKAHEAD_BITS, DEFAULT_WINDOW_BITS};
+
+/// Window and lookahead sizes as powers of two, as taken by `HeatshrinkEncoder::new` and
+/// `HeatshrinkDecoder::new`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Config {
+    /// 2^n size of window
+    pub window_sz2: u8,
+    /// 2^n size of lookahead
+    pub lookahead_sz2: u8,
+}
+
+/// The blessed defaults, `DEFAULT_WINDOW_BITS` and `DEFAULT_LOOKAHEAD_BITS`.
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            window_sz2: DEFAULT_WINDOW_BITS,
+            lookahead_sz2: DEFAULT_LOOKAHEAD_BITS,
+        }
+    }
+}
+
+/// From a `(window_sz2, lookahead_sz2)` pair.
+impl From<(u8, u8)> for Config {
+    fn from((window_sz2, lookahead_sz2): (u8, u8)) -> Self {
+        Self {
+            window_sz2,
+            lookahead_sz2,
+        }
+    }
+}
+
+/// Into a `(window_sz2, lookahead_sz2)` pair.
+impl From<Config> for (u8, u8) {
+    fn from(config: Config) -> Self {
+        (config.window_sz2, config.lookahead_sz2)
+    }
+}
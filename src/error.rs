@@ -0,0 +1,139 @@
+use core::fmt;
+
+/// Errors returned by the one-shot helpers built on top of the sink/poll API.
+///
+/// Every variant is a plain tag or carries a block index, so the error is `Copy` and
+/// formatting it with `Display` needs neither `alloc` nor `std`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum HeatshrinkError {
+    /// window, lookahead, or input buffer size rejected by the constructor
+    InvalidParams,
+    /// the decoder could not make sense of its input
+    Corrupt,
+    /// the encoder or decoder reported misuse of its API
+    Misuse,
+    /// the destination cannot hold all of the output
+    OutputTooSmall,
+    /// decoding the encoded output did not reproduce the original input
+    RoundtripMismatch,
+    /// the requested block is not described by the block index
+    BlockOutOfRange,
+    /// saved encoder or decoder state is truncated, from another version, or inconsistent
+    InvalidState,
+    /// the decoded bytes are not valid UTF-8
+    InvalidUtf8,
+    /// a `read_sz` of 0 would never make progress
+    ZeroReadSize,
+    /// the stream ended before decoding the expected number of bytes
+    UnexpectedEof,
+    /// the block at this index of a CRC block stream does not match its checksum
+    BlockCrcMismatch(usize),
+    /// the stream has no header and no window and lookahead sizes were given
+    MissingParams,
+    /// the encoder polled more bytes than the limit of `set_expansion_limit`, the input
+    /// does not compress
+    ExpansionLimit,
+}
+
+impl fmt::Display for HeatshrinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HeatshrinkError::InvalidParams => "invalid window, lookahead, or input buffer size",
+            HeatshrinkError::Corrupt => "corrupt compressed stream",
+            HeatshrinkError::Misuse => "encoder or decoder API misuse",
+            HeatshrinkError::OutputTooSmall => "output buffer too small",
+            HeatshrinkError::RoundtripMismatch => "decoded output does not match the input",
+            HeatshrinkError::BlockOutOfRange => "block is not in the block index",
+            HeatshrinkError::InvalidState => "invalid saved state",
+            HeatshrinkError::InvalidUtf8 => "decoded bytes are not valid UTF-8",
+            HeatshrinkError::ZeroReadSize => "read size must be greater than 0",
+            HeatshrinkError::UnexpectedEof => "stream ended before the expected length",
+            HeatshrinkError::MissingParams => "stream has no header and no sizes were given",
+            HeatshrinkError::ExpansionLimit => "compressed output exceeds the expansion limit",
+            HeatshrinkError::BlockCrcMismatch(block) => {
+                return write!(f, "block {} does not match its CRC", block)
+            }
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for HeatshrinkError {}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use super::*;
+
+    /// Fixed-size `fmt::Write` sink, formatting without `alloc` like on a microcontroller
+    struct StackBuf {
+        bytes: [u8; 64],
+        len: usize,
+    }
+
+    impl Write for StackBuf {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.bytes
+                .get_mut(self.len..end)
+                .ok_or(fmt::Error)?
+                .copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn display_without_alloc() {
+        let all = [
+            HeatshrinkError::InvalidParams,
+            HeatshrinkError::Corrupt,
+            HeatshrinkError::Misuse,
+            HeatshrinkError::OutputTooSmall,
+            HeatshrinkError::RoundtripMismatch,
+            HeatshrinkError::BlockOutOfRange,
+            HeatshrinkError::InvalidState,
+            HeatshrinkError::InvalidUtf8,
+            HeatshrinkError::ZeroReadSize,
+            HeatshrinkError::UnexpectedEof,
+            HeatshrinkError::BlockCrcMismatch(3),
+            HeatshrinkError::MissingParams,
+            HeatshrinkError::ExpansionLimit,
+        ];
+        let mut messages = [([0; 64], 0); 13];
+        for (i, (&error, message)) in all.iter().zip(messages.iter_mut()).enumerate() {
+            // inside the crate the match is exhaustive, so a new variant must be listed
+            let index = match error {
+                HeatshrinkError::InvalidParams => 0,
+                HeatshrinkError::Corrupt => 1,
+                HeatshrinkError::Misuse => 2,
+                HeatshrinkError::OutputTooSmall => 3,
+                HeatshrinkError::RoundtripMismatch => 4,
+                HeatshrinkError::BlockOutOfRange => 5,
+                HeatshrinkError::InvalidState => 6,
+                HeatshrinkError::InvalidUtf8 => 7,
+                HeatshrinkError::ZeroReadSize => 8,
+                HeatshrinkError::UnexpectedEof => 9,
+                HeatshrinkError::BlockCrcMismatch(_) => 10,
+                HeatshrinkError::MissingParams => 11,
+                HeatshrinkError::ExpansionLimit => 12,
+            };
+            assert_eq!(index, i);
+
+            let mut buf = StackBuf {
+                bytes: [0; 64],
+                len: 0,
+            };
+            write!(buf, "{}", error).expect("Failed to format");
+            assert!(buf.len > 0);
+            *message = (buf.bytes, buf.len);
+        }
+
+        // every variant has its own message
+        for (i, a) in messages.iter().enumerate() {
+            assert!(messages[i + 1..].iter().all(|b| a != b));
+        }
+    }
+}
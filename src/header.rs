@@ -0,0 +1,110 @@
+//!
+//! Self-describing streams that carry their own window and lookahead sizes.
+//!
+//! A headered stream is `HEADER_MAGIC`, the window and lookahead sizes as one byte each,
+//! and the heatshrink stream. `decode_auto` reads both headered and plain streams, so tools
+//! that ingest either kind need a single entry point.
+//!
+//! The first token of a stream from this crate's encoder is always a literal, whose tag bit
+//! is set, so a plain stream starts with a byte of at least `0x80` and is never mistaken for
+//! the magic, which starts below it. Only the streams of an encoder primed with a dictionary
+//! can start with a backref.
+//!
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use crate::{decode_all, encode_all, Config, HeatshrinkError};
+
+/// Bytes that start every headered stream
+pub const HEADER_MAGIC: [u8; 3] = *b"HSZ";
+
+/// Length of the header, the magic followed by the window and lookahead sizes
+pub const HEADER_LEN: usize = HEADER_MAGIC.len() + 2;
+
+/// Compress `input` like `encode_all`, preceded by a header with the window and lookahead
+/// sizes, so that `decode_auto` needs no sizes to decode it.
+///
+/// Panics if the window and lookahead sizes are invalid.
+pub fn encode_all_with_header(
+    input: &[u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> Vec<u8> {
+    let compressed = encode_all(input, window_sz2, lookahead_sz2, read_sz);
+    let mut stream = Vec::with_capacity(HEADER_LEN + compressed.len());
+    stream.extend_from_slice(&HEADER_MAGIC);
+    stream.extend_from_slice(&[window_sz2, lookahead_sz2]);
+    stream.extend_from_slice(&compressed);
+    stream
+}
+
+/// Split a headered stream into the sizes from its header and the heatshrink stream, or
+/// `None` if `data` does not start with `HEADER_MAGIC` and a complete header.
+pub fn read_header(data: &[u8]) -> Option<(Config, &[u8])> {
+    let (header, stream) = data.split_at_checked(HEADER_LEN)?;
+    if header[..HEADER_MAGIC.len()] != HEADER_MAGIC {
+        return None;
+    }
+    let config = Config {
+        window_sz2: header[HEADER_MAGIC.len()],
+        lookahead_sz2: header[HEADER_MAGIC.len() + 1],
+    };
+    Some((config, stream))
+}
+
+/// Decompress a headered stream with the sizes from its header, or a plain stream with
+/// `params`, like `decode_all` with `read_sz` bytes per read.
+///
+/// Returns `HeatshrinkError::MissingParams` if `data` has no header and `params` is `None`,
+/// and otherwise the errors of `decode_all`.
+pub fn decode_auto(
+    data: &[u8],
+    read_sz: usize,
+    params: Option<Config>,
+) -> Result<Vec<u8>, HeatshrinkError> {
+    let (config, stream) = match read_header(data) {
+        Some(headered) => headered,
+        None => (params.ok_or(HeatshrinkError::MissingParams)?, data),
+    };
+    decode_all(
+        stream,
+        read_sz.min(u16::MAX as usize),
+        config.window_sz2,
+        config.lookahead_sz2,
+        read_sz,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_auto_headered_and_plain() {
+        let input = include_bytes!("header.rs");
+
+        let headered = encode_all_with_header(input, 11, 6, 64);
+        assert_eq!(decode_auto(&headered, 64, None).unwrap(), input);
+        // the header wins over the sizes the caller supplies
+        assert_eq!(
+            decode_auto(&headered, 64, Some(Config::default())).unwrap(),
+            input
+        );
+
+        let plain = encode_all(input, 8, 4, 64);
+        assert!(plain[0] >= 0x80);
+        assert!(read_header(&plain).is_none());
+        assert_eq!(decode_auto(&plain, 64, Some((8, 4).into())).unwrap(), input);
+        assert_eq!(
+            decode_auto(&plain, 64, None),
+            Err(HeatshrinkError::MissingParams)
+        );
+
+        // A truncated header is not a header
+        assert_eq!(
+            decode_auto(&headered[..HEADER_LEN - 1], 64, None),
+            Err(HeatshrinkError::MissingParams)
+        );
+    }
+}
@@ -1,18 +1,27 @@
 #[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+#[cfg(not(feature = "std"))]
 use alloc::vec;
 #[cfg(not(feature = "std"))]
 use alloc::vec::Vec;
 
+use core::mem::MaybeUninit;
+
+#[cfg(feature = "trace")]
+use crate::StateLogger;
 use crate::{
-    common::*, HEATSHRINK_MAX_WINDOW_BITS, HEATSHRINK_MIN_LOOKAHEAD_BITS,
-    HEATSHRINK_MIN_WINDOW_BITS,
+    common::*, HeatshrinkError, ReadResult, Readable, Token, HEATSHRINK_MAX_WINDOW_BITS,
+    HEATSHRINK_MIN_LOOKAHEAD_BITS, HEATSHRINK_MIN_WINDOW_BITS,
 };
 
 /// Represents a case where no bits are available.
 const NO_BITS: u16 = u16::MAX;
 
+/// Spare capacity reserved in the output `Vec` before each poll in `poll_into`.
+const POLL_INTO_RESERVE: usize = 1024;
+
 /// Result types for decoding operations.
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HSDSinkRes {
     /// Data sunk, ready to poll.
     /// Returns the number of bytes sunk
@@ -21,10 +30,12 @@ pub enum HSDSinkRes {
     Full,
     /// NULL argument error.
     ErrorNull,
+    /// Misuse of API, e.g. sinking after `finish` returned `Done` without a `reset`.
+    ErrorMisuse,
 }
 
 /// Result types for polling operations.
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HSDPollRes {
     /// Input exhausted.
     /// Returns the number of bytes output
@@ -39,7 +50,7 @@ pub enum HSDPollRes {
 }
 
 /// Result types for finish operations.
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HSDFinishRes {
     /// Output is done.
     Done,
@@ -47,6 +58,8 @@ pub enum HSDFinishRes {
     More,
     /// NULL arguments error.
     ErrorNull,
+    /// The decoder is in the error state, `reset` it to decode again.
+    ErrorUnknown,
 }
 
 /// States for the decoder state machine.
@@ -66,18 +79,59 @@ enum HSDState {
     BackrefCountLSB,
     /// Ready to yield back-reference.
     YieldBackref,
+    /// The input stream is corrupt, sticky until `reset`.
+    Error,
+    /// Ready to yield the raw bytes of a literal run.
+    #[cfg(feature = "literal-runs")]
+    YieldLiteralRun,
+}
+
+impl HSDState {
+    /// Every state, in declaration order, so that `ALL[state as usize] == state`.
+    const ALL: &'static [HSDState] = &[
+        HSDState::TagBit,
+        HSDState::YieldLiteral,
+        HSDState::BackrefIndexMSB,
+        HSDState::BackrefIndexLSB,
+        HSDState::BackrefCountMSB,
+        HSDState::BackrefCountLSB,
+        HSDState::YieldBackref,
+        HSDState::Error,
+        #[cfg(feature = "literal-runs")]
+        HSDState::YieldLiteralRun,
+    ];
+
+    /// The variant name, for the `StateLogger`.
+    #[cfg(feature = "trace")]
+    fn name(self) -> &'static str {
+        match self {
+            HSDState::TagBit => "TagBit",
+            HSDState::YieldLiteral => "YieldLiteral",
+            HSDState::BackrefIndexMSB => "BackrefIndexMSB",
+            HSDState::BackrefIndexLSB => "BackrefIndexLSB",
+            HSDState::BackrefCountMSB => "BackrefCountMSB",
+            HSDState::BackrefCountLSB => "BackrefCountLSB",
+            HSDState::YieldBackref => "YieldBackref",
+            HSDState::Error => "Error",
+            #[cfg(feature = "literal-runs")]
+            HSDState::YieldLiteralRun => "YieldLiteralRun",
+        }
+    }
 }
 
 /// Structure representing the heatshrink decoder.
-pub struct HeatshrinkDecoder {
+///
+/// The input and window buffers are owned by a `Vec` by default, or borrowed from a
+/// caller-provided arena with `HeatshrinkDecoder::new_in`.
+pub struct HeatshrinkDecoder<S = Vec<u8>> {
     /// Bytes in input buffer.
     input_size: u16,
     /// Offset to the next unprocessed input byte.
     input_index: u16,
     /// Number of bytes to output.
-    output_count: u16,
+    output_count: WindowIndex,
     /// Index for bytes to output.
-    output_index: u16,
+    output_index: WindowIndex,
     /// Head of window buffer.
     head_index: u16,
     /// Current state machine node.
@@ -94,8 +148,32 @@ pub struct HeatshrinkDecoder {
     /// Input buffer size.
     input_buffer_size: u16,
 
+    /// Whether `finish` has reported the end of the stream.
+    finished: bool,
+    /// Whether backreferences beyond the written window are an error.
+    strict_backrefs: bool,
+    /// Bytes of the window that have been written, saturating at the window size.
+    window_len: WindowIndex,
+    /// Bytes decoded since the decoder was created or reset.
+    total_emitted: u64,
+    /// Byte the window is filled with before anything is decoded.
+    window_fill: u8,
+
     /// Input buffer, then expansion window buffer.
-    buffers: Vec<u8>,
+    buffers: S,
+    /// Called with each token as it is decoded, if set.
+    token_observer: Option<Box<dyn FnMut(Token) + Send>>,
+    /// Told about every state change in `poll`, if set.
+    #[cfg(feature = "trace")]
+    state_logger: Option<Box<dyn StateLogger>>,
+}
+
+/// Whether the decoder supports the given buffer sizes
+fn valid_params(input_buffer_size: u16, window_sz2: u8, lookahead_sz2: u8) -> bool {
+    (HEATSHRINK_MIN_WINDOW_BITS..=HEATSHRINK_MAX_WINDOW_BITS).contains(&window_sz2)
+        && input_buffer_size != 0
+        && lookahead_sz2 >= HEATSHRINK_MIN_LOOKAHEAD_BITS
+        && lookahead_sz2 < window_sz2
 }
 
 impl HeatshrinkDecoder {
@@ -112,17 +190,168 @@ impl HeatshrinkDecoder {
     ///
     /// An option containing the new `HeatshrinkDecoder`, or `None` if the parameters are invalid.
     pub fn new(input_buffer_size: u16, window_sz2: u8, lookahead_sz2: u8) -> Option<Self> {
-        if window_sz2 < HEATSHRINK_MIN_WINDOW_BITS
-            || window_sz2 > HEATSHRINK_MAX_WINDOW_BITS
-            || input_buffer_size == 0
-            || lookahead_sz2 < HEATSHRINK_MIN_LOOKAHEAD_BITS
-            || lookahead_sz2 >= window_sz2
-        {
+        if !valid_params(input_buffer_size, window_sz2, lookahead_sz2) {
             return None;
         }
 
         let buffers_sz = (1 << window_sz2) + input_buffer_size as usize;
-        Some(Self {
+        Some(Self::with_buffers(
+            vec![0; buffers_sz],
+            input_buffer_size,
+            window_sz2,
+            lookahead_sz2,
+        ))
+    }
+
+    ///
+    /// Constructs a new `HeatshrinkDecoder` like `new`, with the window filled with `fill`
+    /// instead of zeros, also after `reset`.
+    ///
+    /// Backreferences to bytes before the start of the stream, which only corrupt streams
+    /// or streams from an encoder that relies on a zeroed window contain, then decode to
+    /// `fill`, e.g. `0xAA` to make them stand out in a dump. The fill byte is not part of
+    /// the saved state.
+    pub fn new_with_fill(
+        input_buffer_size: u16,
+        window_sz2: u8,
+        lookahead_sz2: u8,
+        fill: u8,
+    ) -> Option<Self> {
+        let mut decoder = Self::new(input_buffer_size, window_sz2, lookahead_sz2)?;
+        decoder.window_fill = fill;
+        decoder.clear_buffers();
+        Some(decoder)
+    }
+
+    ///
+    /// Decodes the stream read from `src` as an iterator of decoded chunks of up to `chunk`
+    /// bytes, reading up to `chunk` compressed bytes at a time, e.g. to process the output
+    /// as it arrives. The iterator ends once `src` is exhausted and `finish` is `Done`.
+    ///
+    /// A decoder error is yielded as `HeatshrinkError::Corrupt` and ends the iteration.
+    ///
+    /// When `src` reads `Pending`, the iterator yields the partial chunk decoded so far, or
+    /// `None` if there is none, and reads `src` again on the next call, so `next` may return
+    /// chunks after `None`. `is_done` tells the two kinds of `None` apart.
+    ///
+    /// Panics if `chunk` is 0.
+    pub fn decode_iter<R: Readable>(self, src: R, chunk: usize) -> DecodeIter<R> {
+        assert!(chunk > 0, "chunk must be greater than 0");
+        DecodeIter {
+            decoder: self,
+            src,
+            chunk,
+            input: vec![0; chunk],
+            input_pos: 0,
+            input_len: 0,
+            finishing: false,
+            done: false,
+        }
+    }
+
+    ///
+    /// Restores a decoder from the bytes produced by `save_state`.
+    ///
+    /// # Returns
+    ///
+    /// The restored decoder, `HeatshrinkError::InvalidParams` if the saved sizes are not
+    /// accepted by `new`, or `HeatshrinkError::InvalidState` if the bytes are truncated,
+    /// from another version, or describe an impossible state.
+    pub fn load_state(bytes: &[u8]) -> Result<Self, HeatshrinkError> {
+        let mut r = StateReader { bytes };
+        if r.get_u8()? != STATE_VERSION {
+            return Err(HeatshrinkError::InvalidState);
+        }
+        let input_buffer_size = r.get_u16()?;
+        let window_sz2 = r.get_u8()?;
+        let lookahead_sz2 = r.get_u8()?;
+        let mut decoder = Self::new(input_buffer_size, window_sz2, lookahead_sz2)
+            .ok_or(HeatshrinkError::InvalidParams)?;
+
+        decoder.input_size = r.get_u16()?;
+        decoder.input_index = r.get_u16()?;
+        decoder.output_count = r.get_window_index()?;
+        decoder.output_index = r.get_window_index()?;
+        decoder.head_index = r.get_u16()?;
+        decoder.state = *HSDState::ALL
+            .get(r.get_u8()? as usize)
+            .ok_or(HeatshrinkError::InvalidState)?;
+        decoder.current_byte = r.get_u8()?;
+        decoder.bit_index = r.get_u8()?;
+        let finished = r.get_u8()?;
+        let strict_backrefs = r.get_u8()?;
+        decoder.window_len = r.get_window_index()?;
+        decoder.total_emitted = r.get_u64()?;
+        let buffers_len = decoder.buffers.len();
+        decoder.buffers.copy_from_slice(r.take(buffers_len)?);
+        r.finish()?;
+
+        let window_sz = 1usize << window_sz2;
+        // a literal run counts down from up to a window of bytes, a backref from a lookahead
+        #[cfg(feature = "literal-runs")]
+        let max_output_count = if decoder.state == HSDState::YieldLiteralRun {
+            window_sz
+        } else {
+            1 << lookahead_sz2
+        };
+        #[cfg(not(feature = "literal-runs"))]
+        let max_output_count = 1 << lookahead_sz2;
+        if decoder.input_size > input_buffer_size
+            || decoder.input_index > decoder.input_size
+            || decoder.output_count as usize > max_output_count
+            || decoder.output_index as usize > window_sz
+            || (decoder.bit_index != 0 && !decoder.bit_index.is_power_of_two())
+            || finished > 1
+            || strict_backrefs > 1
+            || decoder.window_len as usize > window_sz
+            // the MSB states only read the bits above the low byte of a wider field
+            || (decoder.state == HSDState::BackrefIndexMSB && window_sz2 <= 8)
+            || (decoder.state == HSDState::BackrefCountMSB && lookahead_sz2 <= 8)
+            || (decoder.state == HSDState::YieldBackref && decoder.output_index == 0)
+        {
+            return Err(HeatshrinkError::InvalidState);
+        }
+        decoder.finished = finished == 1;
+        decoder.strict_backrefs = strict_backrefs == 1;
+        Ok(decoder)
+    }
+}
+
+impl<'a> HeatshrinkDecoder<&'a mut [u8]> {
+    ///
+    /// Constructs a new `HeatshrinkDecoder` that borrows its working memory from `arena`
+    /// instead of allocating it.
+    ///
+    /// The first `1 << window_sz2` bytes of the arena hold the window and the rest is the
+    /// input buffer, so `arena` must be larger than the window. The input buffer size is
+    /// capped at `u16::MAX` and any bytes beyond that are left unused.
+    ///
+    /// # Returns
+    ///
+    /// An option containing the new `HeatshrinkDecoder`, or `None` if the parameters are
+    /// invalid or `arena` is too small.
+    pub fn new_in(arena: &'a mut [u8], window_sz2: u8, lookahead_sz2: u8) -> Option<Self> {
+        let window_sz = 1usize.checked_shl(window_sz2 as u32)?;
+        let input_buffer_size = arena.len().checked_sub(window_sz)?.min(u16::MAX as usize) as u16;
+        if !valid_params(input_buffer_size, window_sz2, lookahead_sz2) {
+            return None;
+        }
+
+        let buffers = &mut arena[..window_sz + input_buffer_size as usize];
+        buffers.fill(0);
+        Some(Self::with_buffers(
+            buffers,
+            input_buffer_size,
+            window_sz2,
+            lookahead_sz2,
+        ))
+    }
+}
+
+impl<S: AsRef<[u8]> + AsMut<[u8]>> HeatshrinkDecoder<S> {
+    /// Wraps zeroed `buffers` of `(1 << window_sz2) + input_buffer_size` bytes
+    fn with_buffers(buffers: S, input_buffer_size: u16, window_sz2: u8, lookahead_sz2: u8) -> Self {
+        Self {
             input_size: 0,
             input_index: 0,
             output_count: 0,
@@ -134,8 +363,146 @@ impl HeatshrinkDecoder {
             window_sz2,
             lookahead_sz2,
             input_buffer_size,
-            buffers: vec![0; buffers_sz],
-        })
+            finished: false,
+            strict_backrefs: false,
+            window_len: 0,
+            total_emitted: 0,
+            window_fill: 0,
+            buffers,
+            token_observer: None,
+            #[cfg(feature = "trace")]
+            state_logger: None,
+        }
+    }
+
+    ///
+    /// Resets the decoder to its freshly constructed state so that it can decode a new stream.
+    ///
+    /// The window is cleared, so backreferences never reach into the previous stream.
+    pub fn reset(&mut self) {
+        self.input_size = 0;
+        self.input_index = 0;
+        self.output_count = 0;
+        self.output_index = 0;
+        self.head_index = 0;
+        self.state = HSDState::TagBit;
+        self.current_byte = 0;
+        self.bit_index = 0;
+        self.finished = false;
+        self.window_len = 0;
+        self.total_emitted = 0;
+        self.clear_buffers();
+    }
+
+    /// Zeroes the input buffer and fills the window with `window_fill`
+    fn clear_buffers(&mut self) {
+        let (input, window) = self
+            .buffers
+            .as_mut()
+            .split_at_mut(self.input_buffer_size as usize);
+        input.fill(0);
+        window.fill(self.window_fill);
+    }
+
+    ///
+    /// Primes the decoder's window with a preset dictionary before any input is sunk.
+    ///
+    /// This must match the dictionary passed to `HeatshrinkEncoder::prime`, so that
+    /// backreferences into the dictionary resolve to the same bytes.
+    ///
+    /// # Returns
+    ///
+    /// The number of dictionary bytes loaded (the last `1 << window_sz2` bytes of `dict`),
+    /// or `ErrorMisuse` if the decoder has already started decoding.
+    pub fn prime(&mut self, dict: &[u8]) -> HSDSinkRes {
+        if self.input_size != 0
+            || self.head_index != 0
+            || self.bit_index != 0
+            || self.state != HSDState::TagBit
+            || self.finished
+        {
+            return HSDSinkRes::ErrorMisuse;
+        }
+
+        // The window is circular and the head starts at 0, so the most recent
+        // dictionary byte belongs at the very end of the window.
+        let window_sz = 1 << self.window_sz2;
+        let dict = &dict[dict.len().saturating_sub(window_sz)..];
+        let window_end = self.input_buffer_size as usize + window_sz;
+        self.buffers.as_mut()[window_end - dict.len()..window_end].copy_from_slice(dict);
+        self.window_len = dict.len() as WindowIndex;
+        HSDSinkRes::Ok(dict.len())
+    }
+
+    ///
+    /// Sets whether a backreference to bytes that were never written to the window
+    /// (neither decoded nor primed) is treated as corruption.
+    ///
+    /// The decoder's window starts zeroed, and the reference heatshrink encoder may emit
+    /// backreferences into that zeroed window when the input starts with `0x00` bytes, so
+    /// this is off by default. Streams from this crate's encoder never do, so enabling it
+    /// turns corrupt backreferences into `ErrorUnknown` instead of silently emitting zeros.
+    pub fn set_strict_backrefs(&mut self, strict: bool) {
+        self.strict_backrefs = strict;
+    }
+
+    ///
+    /// Serializes the complete decoder state, including the window and any input that
+    /// was sunk but not decoded yet, so that decoding can resume later, even in another
+    /// process, with `HeatshrinkDecoder::load_state`.
+    ///
+    /// The token observer is not part of the state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::default();
+        w.put_u8(STATE_VERSION);
+        w.put_u16(self.input_buffer_size);
+        w.put_u8(self.window_sz2);
+        w.put_u8(self.lookahead_sz2);
+        w.put_u16(self.input_size);
+        w.put_u16(self.input_index);
+        w.put_window_index(self.output_count);
+        w.put_window_index(self.output_index);
+        w.put_u16(self.head_index);
+        w.put_u8(self.state as u8);
+        w.put_u8(self.current_byte);
+        w.put_u8(self.bit_index);
+        w.put_u8(self.finished as u8);
+        w.put_u8(self.strict_backrefs as u8);
+        w.put_window_index(self.window_len);
+        w.put_u64(self.total_emitted);
+        w.put_bytes(self.buffers.as_ref());
+        w.bytes
+    }
+
+    ///
+    /// Calls `f` with every literal as it is decoded and every backreference as soon as its
+    /// distance and length are known, before its bytes are emitted.
+    ///
+    /// Comparing this against `HeatshrinkEncoder::set_token_observer` shows where the
+    /// decoder's interpretation of a stream departs from the encoder's intent.
+    /// The observer survives `reset`.
+    pub fn set_token_observer(&mut self, f: impl FnMut(Token) + Send + 'static) {
+        self.token_observer = Some(Box::new(f));
+    }
+
+    ///
+    /// Removes the token observer set with `set_token_observer`.
+    pub fn clear_token_observer(&mut self) {
+        self.token_observer = None;
+    }
+
+    ///
+    /// Tells `logger` about every state change while polling. The logger survives `reset`.
+    #[cfg(feature = "trace")]
+    pub fn set_state_logger(&mut self, logger: impl StateLogger + 'static) {
+        self.state_logger = Some(Box::new(logger));
+    }
+
+    ///
+    /// Removes the logger set with `set_state_logger`.
+    #[cfg(feature = "trace")]
+    pub fn clear_state_logger(&mut self) {
+        self.state_logger = None;
     }
 
     ///
@@ -149,10 +516,14 @@ impl HeatshrinkDecoder {
     ///
     /// An `HSDSinkRes` indicating the result of the sink operation.
     /// The `Ok(usize)` variant includes the number of bytes that were successfully sunk.
+    /// `ErrorMisuse` is returned once `finish` has returned `Done`, until the decoder is `reset`.
     pub fn sink(&mut self, in_buf: &[u8]) -> HSDSinkRes {
         if in_buf.is_empty() {
             return HSDSinkRes::ErrorNull;
         }
+        if self.finished {
+            return HSDSinkRes::ErrorMisuse;
+        }
 
         let rem = self.input_buffer_size as usize - self.input_size as usize;
         if rem == 0 {
@@ -160,7 +531,7 @@ impl HeatshrinkDecoder {
         }
 
         let size = rem.min(in_buf.len());
-        self.buffers[self.input_size as usize..self.input_size as usize + size]
+        self.buffers.as_mut()[self.input_size as usize..self.input_size as usize + size]
             .copy_from_slice(&in_buf[..size]);
         self.input_size += size as u16;
         HSDSinkRes::Ok(size)
@@ -176,8 +547,133 @@ impl HeatshrinkDecoder {
     /// # Returns
     ///
     /// An `HSDPollRes` indicating the result of the poll operation.
+    /// Once the decoder detects corruption, every poll returns `ErrorUnknown` until `reset`.
+    ///
+    /// Every call returns after consuming at most the sunk input and filling at most
+    /// `out_buf`, even for hostile input, because a single backreference never emits more
+    /// than `1 << lookahead_sz2` bytes.
     pub fn poll(&mut self, out_buf: &mut [u8]) -> HSDPollRes {
-        if out_buf.is_empty() {
+        self.poll_into_buffer(out_buf)
+    }
+
+    ///
+    /// Polls the decoder for output data like `poll`, but emits at most `max_bytes` bytes
+    /// even if `out_buf` is larger, so that a long backreference is spread over several
+    /// calls instead of blocking a cooperative scheduler.
+    ///
+    /// # Returns
+    ///
+    /// `More(n)` once `max_bytes` bytes were emitted, then poll again to continue.
+    /// `ErrorNull` if `max_bytes` or `out_buf` is empty.
+    pub fn poll_budget(&mut self, out_buf: &mut [u8], max_bytes: usize) -> HSDPollRes {
+        let len = out_buf.len().min(max_bytes);
+        self.poll_into_buffer(&mut out_buf[..len])
+    }
+
+    ///
+    /// Polls the decoder for output data into memory that has not been initialized,
+    /// such as the spare capacity of a `Vec`.
+    ///
+    /// # Arguments
+    ///
+    /// * `out_buf` - The output buffer to fill with decompressed data.
+    ///
+    /// # Returns
+    ///
+    /// An `HSDPollRes` indicating the result of the poll operation.
+    /// The first `n` bytes of `out_buf` are initialized for `Empty(n)` and `More(n)`.
+    pub fn poll_uninit(&mut self, out_buf: &mut [MaybeUninit<u8>]) -> HSDPollRes {
+        self.poll_into_buffer(out_buf)
+    }
+
+    ///
+    /// Polls the decoder for output data like `poll`, but hands each decoded byte to `sink`
+    /// instead of copying it into a slice, e.g. to feed a hash or a device register. The
+    /// closure is taken by value and never boxed, so this works without `alloc`.
+    ///
+    /// # Returns
+    ///
+    /// `Empty(n)` once the decoder needs more input, where `n` is the number of bytes
+    /// passed to `sink`, as the callback never runs out of room.
+    pub fn poll_with(&mut self, sink: impl FnMut(u8)) -> HSDPollRes {
+        self.poll_into_buffer(&mut CallbackOutput { sink })
+    }
+
+    ///
+    /// Sinks all of `input` and appends the bytes it decodes to `out`, polling whenever the
+    /// input buffer fills, without finishing the stream. Call it once per chunk as input
+    /// arrives, in chunks of any size, then `finalize` after the last chunk.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once all of `input` is sunk, `HeatshrinkError::Misuse` if the stream was
+    /// already finalized, or `HeatshrinkError::Corrupt` if the decoder reports `ErrorUnknown`.
+    pub fn decompress_chunk(
+        &mut self,
+        mut input: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), HeatshrinkError> {
+        while !input.is_empty() {
+            match self.sink(input) {
+                HSDSinkRes::Ok(sz) => input = &input[sz..],
+                HSDSinkRes::Full => {}
+                HSDSinkRes::ErrorNull | HSDSinkRes::ErrorMisuse => {
+                    return Err(HeatshrinkError::Misuse)
+                }
+            }
+            self.poll_into(out)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Polls all of the currently available output from the decoder, appending it to `out`.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once the decoder needs more input, or `HeatshrinkError::Corrupt` if the
+    /// decoder reports `ErrorUnknown`.
+    pub fn poll_into(&mut self, out: &mut Vec<u8>) -> Result<(), HeatshrinkError> {
+        loop {
+            out.reserve(POLL_INTO_RESERVE);
+            match self.poll_uninit(out.spare_capacity_mut()) {
+                HSDPollRes::Empty(sz) => {
+                    // SAFETY: poll_uninit initialized the first `sz` bytes of spare capacity
+                    unsafe { out.set_len(out.len() + sz) };
+                    return Ok(());
+                }
+                HSDPollRes::More(sz) => {
+                    // SAFETY: poll_uninit initialized the first `sz` bytes of spare capacity
+                    unsafe { out.set_len(out.len() + sz) };
+                }
+                HSDPollRes::ErrorNull => return Err(HeatshrinkError::Misuse),
+                HSDPollRes::ErrorUnknown => return Err(HeatshrinkError::Corrupt),
+            }
+        }
+    }
+
+    ///
+    /// Notifies the decoder that the input stream is finished and appends all of the
+    /// remaining output to `out`, repeating `finish` and `poll` until `finish` is `Done`.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` when the stream is fully decoded, or `HeatshrinkError::Corrupt` if the
+    /// decoder reports `ErrorUnknown`.
+    pub fn finalize(&mut self, out: &mut Vec<u8>) -> Result<(), HeatshrinkError> {
+        loop {
+            match self.finish() {
+                HSDFinishRes::Done => return Ok(()),
+                HSDFinishRes::More => self.poll_into(out)?,
+                HSDFinishRes::ErrorNull => return Err(HeatshrinkError::Misuse),
+                HSDFinishRes::ErrorUnknown => return Err(HeatshrinkError::Corrupt),
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn poll_into_buffer<B: OutputBuffer + ?Sized>(&mut self, out_buf: &mut B) -> HSDPollRes {
+        if out_buf.capacity() == 0 {
             return HSDPollRes::ErrorNull;
         }
         let mut output_size = 0;
@@ -196,10 +692,20 @@ impl HeatshrinkDecoder {
                 HSDState::BackrefCountMSB => self.state = self.st_backref_count_msb(),
                 HSDState::BackrefCountLSB => self.state = self.st_backref_count_lsb(),
                 HSDState::YieldBackref => self.state = self.st_yield_backref(&mut oi),
+                HSDState::Error => return HSDPollRes::ErrorUnknown,
+                #[cfg(feature = "literal-runs")]
+                HSDState::YieldLiteralRun => self.state = self.st_yield_literal_run(&mut oi),
+            }
+
+            #[cfg(feature = "trace")]
+            if self.state != in_state {
+                if let Some(logger) = &mut self.state_logger {
+                    logger.on_transition(in_state.name(), self.state.name());
+                }
             }
 
             if self.state == in_state {
-                if *oi.output_size == oi.buf.len() {
+                if !oi.has_room() {
                     return HSDPollRes::More(output_size);
                 }
                 return HSDPollRes::Empty(output_size);
@@ -207,6 +713,111 @@ impl HeatshrinkDecoder {
         }
     }
 
+    ///
+    /// The circular window of recently decoded bytes, for debugging. The byte `d` back
+    /// from the most recent one is at `(head_index() - d) & (len - 1)`, so a backreference
+    /// with distance `d` starts there.
+    pub fn window_snapshot(&self) -> &[u8] {
+        &self.buffers.as_ref()[self.input_buffer_size as usize..]
+    }
+
+    ///
+    /// Total number of bytes decoded since the decoder was created or `reset`, across
+    /// polls, e.g. to drive a progress bar against a known decompressed length.
+    pub fn total_emitted(&self) -> u64 {
+        self.total_emitted
+    }
+
+    ///
+    /// The offset in `window_snapshot` where the next decoded byte will be written.
+    pub fn head_index(&self) -> usize {
+        self.head_index as usize & ((1 << self.window_sz2) - 1)
+    }
+
+    ///
+    /// Whether a `poll` would emit at least one byte from input that was already sunk,
+    /// without running the state machine.
+    ///
+    /// This is true while a backreference is being copied out, or when the next literal
+    /// is fully buffered, e.g. after `poll` returned `More` on a full output buffer.
+    /// Newly sunk input is not decoded until the next `poll`, so it is not reported here.
+    pub fn output_pending(&self) -> bool {
+        match self.state {
+            HSDState::YieldBackref => self.output_count > 0,
+            HSDState::YieldLiteral => self.available_bits() >= 8,
+            #[cfg(feature = "literal-runs")]
+            HSDState::YieldLiteralRun => self.output_count > 0 && self.available_bits() >= 8,
+            _ => false,
+        }
+    }
+
+    ///
+    /// Decodes the header of the next token, without emitting any of its bytes, to tell how
+    /// many bytes it will produce, e.g. to reserve output space before polling.
+    ///
+    /// # Returns
+    ///
+    /// `Some(1)` for a literal, or the bytes left in the backreference being emitted.
+    /// `None` if more input is needed to read the header, or if the input is corrupt.
+    pub fn peek_next_len(&mut self) -> Option<usize> {
+        loop {
+            let in_state = self.state;
+            self.state = match in_state {
+                HSDState::TagBit => self.st_tag_bit(),
+                HSDState::BackrefIndexMSB => self.st_backref_index_msb(),
+                HSDState::BackrefIndexLSB => self.st_backref_index_lsb(),
+                HSDState::BackrefCountMSB => self.st_backref_count_msb(),
+                HSDState::BackrefCountLSB => self.st_backref_count_lsb(),
+                HSDState::YieldLiteral => return Some(1),
+                HSDState::YieldBackref => return Some(self.output_count as usize),
+                #[cfg(feature = "literal-runs")]
+                HSDState::YieldLiteralRun => return Some(self.output_count as usize),
+                HSDState::Error => return None,
+            };
+            if self.state == in_state {
+                return None;
+            }
+        }
+    }
+
+    ///
+    /// Decodes the next bytes of a backreference into the window and returns them as a
+    /// borrowed slice of the window, instead of copying them into an output buffer.
+    ///
+    /// The slice holds as many bytes of the backreference as fit before the end of the
+    /// circular window, so a long backreference may take several calls.
+    ///
+    /// # Returns
+    ///
+    /// The decoded bytes, or `None` if the next token is a literal, more input is needed,
+    /// or the input is corrupt. Then `poll` to continue, which emits the same bytes, so the
+    /// two can be mixed freely.
+    pub fn poll_borrowed(&mut self) -> Option<&[u8]> {
+        self.peek_next_len()?;
+        if self.state != HSDState::YieldBackref || self.output_count == 0 {
+            return None;
+        }
+        let window_sz = 1 << self.window_sz2;
+        let start = self.head_index as usize & (window_sz - 1);
+        let count = (self.output_count as usize).min(window_sz - start);
+        self.copy_backref(count, |_| {});
+        if self.output_count == 0 {
+            self.state = HSDState::TagBit;
+        }
+        let buf_offset = self.input_buffer_size as usize;
+        Some(&self.buffers.as_ref()[buf_offset + start..][..count])
+    }
+
+    /// Number of input bits that `get_bits` can still read.
+    fn available_bits(&self) -> usize {
+        let current = if self.bit_index == 0 {
+            0
+        } else {
+            self.bit_index.trailing_zeros() as usize + 1
+        };
+        (self.input_size - self.input_index) as usize * 8 + current
+    }
+
     /// Finishes the decoding process.
     ///
     /// Notify the dencoder that the input stream is finished.
@@ -215,35 +826,21 @@ impl HeatshrinkDecoder {
     ///
     /// # Returns
     ///
-    /// An `HSDFinishRes` indicating whether more output remains.
+    /// An `HSDFinishRes` indicating whether more output remains,
+    /// or `ErrorUnknown` if the decoder detected corruption.
     pub fn finish(&mut self) -> HSDFinishRes {
-        match self.state {
-            HSDState::TagBit => {
-                if self.input_size == 0 {
-                    HSDFinishRes::Done
-                } else {
-                    HSDFinishRes::More
-                }
-            }
-            HSDState::BackrefIndexLSB
-            | HSDState::BackrefIndexMSB
-            | HSDState::BackrefCountLSB
-            | HSDState::BackrefCountMSB => {
-                if self.input_size == 0 {
-                    HSDFinishRes::Done
-                } else {
-                    HSDFinishRes::More
-                }
-            }
-            HSDState::YieldLiteral => {
-                if self.input_size == 0 {
-                    HSDFinishRes::Done
-                } else {
-                    HSDFinishRes::More
-                }
-            }
+        let res = match self.state {
+            HSDState::Error => HSDFinishRes::ErrorUnknown,
+            // the rest of a backref is copied from the window, without needing more input
+            HSDState::YieldBackref => HSDFinishRes::More,
+            // every other state reads input next, so the stream ends once it is consumed
+            _ if self.input_size == 0 => HSDFinishRes::Done,
             _ => HSDFinishRes::More,
+        };
+        if res == HSDFinishRes::Done {
+            self.finished = true;
         }
+        res
     }
 
     /// Handles the `TagBit` state, determining whether to yield a literal or handle backreferences.
@@ -262,27 +859,55 @@ impl HeatshrinkDecoder {
     }
 
     /// Handles the `YieldLiteral` state, emitting a literal byte to the output.
-    fn st_yield_literal(&mut self, oi: &mut OutputInfo) -> HSDState {
-        if *oi.output_size < oi.buf.len() {
-            let byte = self.get_bits(8);
-            if byte == NO_BITS {
-                return HSDState::YieldLiteral;
-            }
-            let buf_offset = self.input_buffer_size as usize;
-            let mask = (1 << self.window_sz2) - 1;
-            let c = byte as u8;
-            self.buffers[(self.head_index & mask) as usize + buf_offset] = c;
-            self.head_index = self.head_index.wrapping_add(1);
-            if *oi.output_size < oi.buf.len() {
-                oi.buf[*oi.output_size] = c;
-                *oi.output_size += 1;
-            }
+    fn st_yield_literal<B: OutputBuffer + ?Sized>(&mut self, oi: &mut OutputInfo<B>) -> HSDState {
+        if oi.has_room() && self.yield_literal_byte(oi) {
             HSDState::TagBit
         } else {
             HSDState::YieldLiteral
         }
     }
 
+    /// Handles the `YieldLiteralRun` state, emitting the `output_count` raw bytes left in
+    /// a literal run to the output.
+    #[cfg(feature = "literal-runs")]
+    fn st_yield_literal_run<B: OutputBuffer + ?Sized>(
+        &mut self,
+        oi: &mut OutputInfo<B>,
+    ) -> HSDState {
+        while self.output_count > 0 && oi.has_room() {
+            if !self.yield_literal_byte(oi) {
+                return HSDState::YieldLiteralRun;
+            }
+            self.output_count -= 1;
+        }
+        if self.output_count == 0 {
+            HSDState::TagBit
+        } else {
+            HSDState::YieldLiteralRun
+        }
+    }
+
+    /// Reads a literal byte into the window and the output, which must have room,
+    /// returning false if the input ran out first.
+    fn yield_literal_byte<B: OutputBuffer + ?Sized>(&mut self, oi: &mut OutputInfo<B>) -> bool {
+        let byte = self.get_bits(8);
+        if byte == NO_BITS {
+            return false;
+        }
+        let buf_offset = self.input_buffer_size as usize;
+        let mask = (1 << self.window_sz2) - 1;
+        let c = byte as u8;
+        self.buffers.as_mut()[(self.head_index as usize & mask) + buf_offset] = c;
+        self.head_index = self.head_index.wrapping_add(1);
+        if self.window_len < 1 << self.window_sz2 {
+            self.window_len += 1;
+        }
+        oi.write_byte(c);
+        self.total_emitted += 1;
+        self.observe(Token::Literal(c));
+        true
+    }
+
     /// Handles the `BackrefIndexMSB` state, retrieving the most significant byte of the backreference index.
     fn st_backref_index_msb(&mut self) -> HSDState {
         let bit_ct = self.window_sz2;
@@ -291,7 +916,7 @@ impl HeatshrinkDecoder {
         if bits == NO_BITS {
             HSDState::BackrefIndexMSB
         } else {
-            self.output_index = bits << 8;
+            self.output_index = (bits as WindowIndex) << 8;
             HSDState::BackrefIndexLSB
         }
     }
@@ -303,8 +928,15 @@ impl HeatshrinkDecoder {
         if bits == NO_BITS {
             HSDState::BackrefIndexLSB
         } else {
-            self.output_index |= bits;
+            self.output_index |= bits as WindowIndex;
             self.output_index += 1;
+            // with literal runs, the index may be a run length, checked with the count
+            if !cfg!(feature = "literal-runs")
+                && self.strict_backrefs
+                && self.output_index > self.window_len
+            {
+                return HSDState::Error;
+            }
             let br_bit_ct = self.lookahead_sz2;
             self.output_count = 0;
             if br_bit_ct > 8 {
@@ -323,7 +955,7 @@ impl HeatshrinkDecoder {
         if bits == NO_BITS {
             HSDState::BackrefCountMSB
         } else {
-            self.output_count = bits << 8;
+            self.output_count = (bits as WindowIndex) << 8;
             HSDState::BackrefCountLSB
         }
     }
@@ -335,47 +967,49 @@ impl HeatshrinkDecoder {
         if bits == NO_BITS {
             HSDState::BackrefCountLSB
         } else {
-            self.output_count |= bits;
+            self.output_count |= bits as WindowIndex;
             self.output_count += 1;
+            check_invariant!(
+                self.output_count as usize <= 1 << self.lookahead_sz2,
+                HSDState::Error
+            );
+            #[cfg(feature = "literal-runs")]
+            {
+                // a length 1 backref is never worth emitting, it is the header of a run
+                // of raw literals with the run length in place of the distance
+                if self.output_count == 1 {
+                    self.output_count = self.output_index;
+                    return HSDState::YieldLiteralRun;
+                }
+                if self.strict_backrefs && self.output_index > self.window_len {
+                    return HSDState::Error;
+                }
+            }
+            self.observe(Token::Backref {
+                distance: self.output_index,
+                length: self.output_count as usize,
+            });
             HSDState::YieldBackref
         }
     }
 
     /// Handles the `YieldBackref` state, emitting bytes from the backreference.
-    fn st_yield_backref(&mut self, oi: &mut OutputInfo) -> HSDState {
+    fn st_yield_backref<B: OutputBuffer + ?Sized>(&mut self, oi: &mut OutputInfo<B>) -> HSDState {
+        // a distance past the window would wrap around to the wrong bytes
+        check_invariant!(
+            (1..=1 << self.window_sz2).contains(&(self.output_index as usize)),
+            HSDState::Error
+        );
         // Determine how much space is left in the output buffer
-        let mut count = oi.buf.len() - *oi.output_size;
+        let mut count = oi.remaining();
 
         // Only proceed if there is space left to write
         if count > 0 {
             // Limit the number of bytes to output_count, ensuring no overflow
-            if self.output_count < count as u16 {
+            if (self.output_count as usize) < count {
                 count = self.output_count as usize;
             }
-
-            // Offset in the buffer where backreference starts
-            let buf_offset = self.input_buffer_size as usize;
-            let buf = &mut self.buffers[buf_offset..];
-            let mask = (1 << self.window_sz2) - 1;
-            let neg_offset = self.output_index as usize;
-
-            // Emitting the backreferenced bytes
-            for _ in 0..count {
-                // Wrap the index calculation to prevent overflow
-                let index = (self.head_index as usize).wrapping_sub(neg_offset) & mask;
-                let c = buf[index];
-
-                // Push byte to output buffer
-                oi.buf[*oi.output_size] = c;
-                *oi.output_size += 1;
-
-                // Add the byte to the circular buffer
-                buf[self.head_index as usize & mask] = c;
-                self.head_index = self.head_index.wrapping_add(1);
-            }
-
-            // Reduce the count of remaining bytes to output
-            self.output_count -= count as u16;
+            self.copy_backref(count, |c| oi.write_byte(c));
 
             // If all bytes have been emitted, return to `TagBit` state
             if self.output_count == 0 {
@@ -386,6 +1020,46 @@ impl HeatshrinkDecoder {
         HSDState::YieldBackref
     }
 
+    /// Copies the next `count` bytes of the backreference being emitted to the head of the
+    /// window, passing each one to `out`.
+    #[inline(always)]
+    fn copy_backref(&mut self, count: usize, mut out: impl FnMut(u8)) {
+        debug_assert!(count > 0 && count <= self.output_count as usize);
+
+        // Offset in the buffer where backreference starts
+        let buf_offset = self.input_buffer_size as usize;
+        let buf = &mut self.buffers.as_mut()[buf_offset..];
+        let mask = (1 << self.window_sz2) - 1;
+        let neg_offset = self.output_index as usize;
+
+        // Emitting the backreferenced bytes
+        for _ in 0..count {
+            // Wrap the index calculation to prevent overflow
+            let index = (self.head_index as usize).wrapping_sub(neg_offset) & mask;
+            let c = buf[index];
+
+            // Push byte to output
+            out(c);
+
+            // Add the byte to the circular buffer
+            buf[self.head_index as usize & mask] = c;
+            self.head_index = self.head_index.wrapping_add(1);
+        }
+
+        // Reduce the count of remaining bytes to output
+        self.output_count -= count as WindowIndex;
+        self.total_emitted += count as u64;
+        self.window_len = (self.window_len as usize + count).min(mask + 1) as WindowIndex;
+    }
+
+    /// Reports a decoded token to the observer, if any.
+    #[inline(always)]
+    fn observe(&mut self, token: Token) {
+        if let Some(observer) = &mut self.token_observer {
+            observer(token);
+        }
+    }
+
     /// Retrieves the next `count` bits from the input buffer, saving incremental progress.
     /// Returns `NO_BITS` if end of input is reached, or if more than 15 bits are requested.
     fn get_bits(&mut self, count: u8) -> u16 {
@@ -403,7 +1077,7 @@ impl HeatshrinkDecoder {
                 if self.input_size == 0 {
                     return NO_BITS;
                 }
-                self.current_byte = self.buffers[self.input_index as usize];
+                self.current_byte = self.buffers.as_ref()[self.input_index as usize];
                 self.input_index += 1;
                 if self.input_index == self.input_size {
                     self.input_index = 0;
@@ -422,3 +1096,906 @@ impl HeatshrinkDecoder {
         accumulator
     }
 }
+
+/// A decoder for an endless stream, e.g. from an encoder on a persistent connection that is
+/// never finished. It is never finished either, so its window persists across `feed` calls
+/// and backreferences may reach into the output of earlier calls.
+pub struct StreamDecoder {
+    decoder: HeatshrinkDecoder,
+}
+
+impl StreamDecoder {
+    /// Returns `None` if the sizes are invalid, like `HeatshrinkDecoder::new`.
+    pub fn new(input_buffer_size: u16, window_sz2: u8, lookahead_sz2: u8) -> Option<Self> {
+        Some(Self {
+            decoder: HeatshrinkDecoder::new(input_buffer_size, window_sz2, lookahead_sz2)?,
+        })
+    }
+
+    /// Sink all of `input` and return all of the output it completes. A token that is split
+    /// across calls is decoded once the rest of it is fed.
+    ///
+    /// Returns `HeatshrinkError::Corrupt` if the decoder fails, after which every call
+    /// fails the same way.
+    pub fn feed(&mut self, mut input: &[u8]) -> Result<Vec<u8>, HeatshrinkError> {
+        let mut out = Vec::new();
+        while !input.is_empty() {
+            match self.decoder.sink(input) {
+                HSDSinkRes::Ok(sz) => input = &input[sz..],
+                _ => unreachable!(),
+            }
+            self.decoder.poll_into(&mut out)?;
+        }
+        Ok(out)
+    }
+
+    /// Total number of bytes decoded across all `feed` calls
+    pub fn total_emitted(&self) -> u64 {
+        self.decoder.total_emitted()
+    }
+}
+
+/// Iterator over the decoded chunks of a stream, from `HeatshrinkDecoder::decode_iter`
+///
+/// It is not fused: after `src` reads `Pending`, it may yield more chunks after `None`.
+pub struct DecodeIter<R> {
+    decoder: HeatshrinkDecoder,
+    src: R,
+    chunk: usize,
+    /// compressed bytes read from `src`, sunk up to `input_pos`
+    input: Vec<u8>,
+    input_pos: usize,
+    input_len: usize,
+    /// whether `src` is exhausted
+    finishing: bool,
+    /// whether the stream is decoded or failed
+    done: bool,
+}
+
+impl<R> DecodeIter<R> {
+    /// Whether the stream is decoded or failed, rather than waiting for `src`
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+impl<R: Readable> Iterator for DecodeIter<R> {
+    type Item = Result<Vec<u8>, HeatshrinkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut out = vec![0; self.chunk];
+        let mut len = 0;
+        while len < out.len() {
+            match self.decoder.poll(&mut out[len..]) {
+                HSDPollRes::More(sz) => len += sz,
+                HSDPollRes::Empty(sz) => {
+                    len += sz;
+                    if self.input_pos < self.input_len {
+                        match self
+                            .decoder
+                            .sink(&self.input[self.input_pos..self.input_len])
+                        {
+                            HSDSinkRes::Ok(sz) => self.input_pos += sz,
+                            _ => unreachable!(),
+                        }
+                    } else if !self.finishing {
+                        match self.src.read(&mut self.input) {
+                            ReadResult::Read(data) => {
+                                self.input_pos = 0;
+                                self.input_len = data.len();
+                            }
+                            ReadResult::Eof => self.finishing = true,
+                            ReadResult::Pending => break,
+                        }
+                    } else {
+                        match self.decoder.finish() {
+                            HSDFinishRes::Done => {
+                                self.done = true;
+                                break;
+                            }
+                            HSDFinishRes::More => {}
+                            HSDFinishRes::ErrorNull => unreachable!(),
+                            HSDFinishRes::ErrorUnknown => {
+                                self.done = true;
+                                return Some(Err(HeatshrinkError::Corrupt));
+                            }
+                        }
+                    }
+                }
+                HSDPollRes::ErrorNull => unreachable!(),
+                HSDPollRes::ErrorUnknown => {
+                    self.done = true;
+                    return Some(Err(HeatshrinkError::Corrupt));
+                }
+            }
+        }
+        if len == 0 {
+            return None;
+        }
+        out.truncate(len);
+        Some(Ok(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_all, HSEFinishRes, HSESinkRes, HeatshrinkEncoder};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn sink_after_finish_is_misuse() {
+        let compressed = encode_all(b"heatshrink heatshrink heatshrink", 8, 4, 16);
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+        let mut output = [0; 64];
+        assert_eq!(decoder.sink(&compressed), HSDSinkRes::Ok(compressed.len()));
+        assert!(matches!(decoder.poll(&mut output), HSDPollRes::Empty(_)));
+        assert_eq!(decoder.finish(), HSDFinishRes::Done);
+
+        assert_eq!(decoder.sink(&compressed), HSDSinkRes::ErrorMisuse);
+
+        decoder.reset();
+        assert_eq!(decoder.sink(&compressed), HSDSinkRes::Ok(compressed.len()));
+    }
+
+    #[test]
+    fn window_snapshot_holds_backref_source() {
+        let input = b"hello, hello";
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let observed = Arc::new(Mutex::new(vec![]));
+        let sink = observed.clone();
+        encoder.set_token_observer(move |token| sink.lock().unwrap().push(token));
+        let mut compressed = vec![];
+        encoder
+            .compress_chunk(input, &mut compressed)
+            .expect("Failed to compress");
+        encoder
+            .finalize(&mut compressed)
+            .expect("Failed to finalize");
+        let distance = match observed.lock().unwrap().last() {
+            Some(Token::Backref { distance, .. }) => *distance as usize,
+            t => panic!("Expected a backref, got {:?}", t),
+        };
+        assert_eq!(distance, 7);
+
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+        let mut decompressed = vec![];
+        decoder
+            .decompress_chunk(&compressed, &mut decompressed)
+            .expect("Failed to decompress");
+        decoder
+            .finalize(&mut decompressed)
+            .expect("Failed to finalize");
+        assert_eq!(decompressed, input);
+
+        // The backref copied "hello" starting 7 bytes before its own first byte
+        let window = decoder.window_snapshot();
+        let mask = window.len() - 1;
+        assert_eq!(decoder.head_index(), input.len());
+        let backref_start = decoder.head_index() - 5;
+        assert_eq!(window[(backref_start - distance) & mask], b'h');
+        assert_eq!(&window[..input.len()], input);
+    }
+
+    #[test]
+    fn sink_poll_into_finalize() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 10, 5, 128);
+
+        let mut decoder = HeatshrinkDecoder::new(100, 10, 5).expect("Failed to create decoder");
+        let mut decompressed = vec![];
+        for chunk in compressed.chunks(100) {
+            assert_eq!(decoder.sink(chunk), HSDSinkRes::Ok(chunk.len()));
+            decoder
+                .poll_into(&mut decompressed)
+                .expect("Failed to poll");
+        }
+        decoder
+            .finalize(&mut decompressed)
+            .expect("Failed to finish");
+
+        assert_eq!(input, decompressed.as_slice());
+    }
+
+    #[test]
+    fn total_emitted_counts_output() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 10, 5, 128);
+        let (head, tail) = compressed.split_at(compressed.len() / 2);
+
+        let mut decoder = HeatshrinkDecoder::new(100, 10, 5).expect("Failed to create decoder");
+        let mut decompressed = vec![];
+        decoder
+            .decompress_chunk(head, &mut decompressed)
+            .expect("Failed to decompress chunk");
+        assert_eq!(decoder.total_emitted(), decompressed.len() as u64);
+
+        // The count is part of the saved state
+        let mut decoder =
+            HeatshrinkDecoder::load_state(&decoder.save_state()).expect("Failed to load state");
+        decoder
+            .decompress_chunk(tail, &mut decompressed)
+            .expect("Failed to decompress chunk");
+        decoder
+            .finalize(&mut decompressed)
+            .expect("Failed to finalize");
+        assert_eq!(decompressed, input);
+        assert_eq!(decoder.total_emitted(), input.len() as u64);
+
+        decoder.reset();
+        assert_eq!(decoder.total_emitted(), 0);
+    }
+
+    #[cfg(feature = "runtime-checks")]
+    #[test]
+    fn runtime_checks_catch_backref_past_window() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 8, 4, 128);
+        let mut decoder = HeatshrinkDecoder::new(100, 8, 4).expect("Failed to create decoder");
+        let mut decompressed = vec![];
+        decoder
+            .decompress_chunk(&compressed[..500], &mut decompressed)
+            .expect("Failed to decompress chunk");
+
+        // A backref one byte past the window, which would wrap around to recent bytes
+        decoder.state = HSDState::YieldBackref;
+        decoder.output_index = (1 << 8) + 1;
+        decoder.output_count = 4;
+        assert_eq!(decoder.poll(&mut [0; 64]), HSDPollRes::ErrorUnknown);
+        assert_eq!(decoder.finish(), HSDFinishRes::ErrorUnknown);
+    }
+
+    #[test]
+    fn window_fill_marks_reads_before_stream_start() {
+        // A backref of 3 bytes at distance 1 before anything was decoded
+        let stream = [0x00, 0x10];
+        let decode = |decoder: &mut HeatshrinkDecoder| {
+            let mut out = vec![];
+            decoder
+                .decompress_chunk(&stream, &mut out)
+                .expect("Failed to decompress chunk");
+            decoder.finalize(&mut out).expect("Failed to finalize");
+            out
+        };
+
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+        assert_eq!(decode(&mut decoder), [0; 3]);
+
+        let mut decoder =
+            HeatshrinkDecoder::new_with_fill(64, 8, 4, 0xAA).expect("Failed to create decoder");
+        assert_eq!(decode(&mut decoder), [0xAA; 3]);
+        decoder.reset();
+        assert_eq!(decode(&mut decoder), [0xAA; 3]);
+
+        // Valid streams never read the fill
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let mut decoder =
+            HeatshrinkDecoder::new_with_fill(64, 8, 4, 0xAA).expect("Failed to create decoder");
+        let mut out = vec![];
+        decoder
+            .decompress_chunk(&encode_all(input, 8, 4, 64), &mut out)
+            .expect("Failed to decompress chunk");
+        decoder.finalize(&mut out).expect("Failed to finalize");
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    fn poll_borrowed_matches_poll() {
+        let input = b"0123456789abcdef".repeat(400);
+        let mut borrowed_bytes = 0;
+        for (window_sz2, lookahead_sz2) in [(4, 3), (8, 4), (10, 8)] {
+            let compressed = encode_all(&input, window_sz2, lookahead_sz2, 128);
+            let mut decoder = HeatshrinkDecoder::new(64, window_sz2, lookahead_sz2)
+                .expect("Failed to create decoder");
+            let mut decompressed = vec![];
+            let mut out = [0; 7];
+            let mut poll = |decoder: &mut HeatshrinkDecoder, decompressed: &mut Vec<u8>| loop {
+                // Take backrefs from the window, and copy the literals
+                while let Some(bytes) = decoder.poll_borrowed() {
+                    assert!(!bytes.is_empty());
+                    borrowed_bytes += bytes.len();
+                    decompressed.extend_from_slice(bytes);
+                }
+                match decoder.poll(&mut out) {
+                    HSDPollRes::Empty(sz) => {
+                        decompressed.extend_from_slice(&out[..sz]);
+                        break;
+                    }
+                    HSDPollRes::More(sz) => decompressed.extend_from_slice(&out[..sz]),
+                    e => panic!("Failed to poll: {:?}", e),
+                }
+            };
+            for chunk in compressed.chunks(50) {
+                assert_eq!(decoder.sink(chunk), HSDSinkRes::Ok(chunk.len()));
+                poll(&mut decoder, &mut decompressed);
+            }
+            while decoder.finish() == HSDFinishRes::More {
+                poll(&mut decoder, &mut decompressed);
+            }
+            assert_eq!(decompressed, input);
+            assert_eq!(decoder.total_emitted(), input.len() as u64);
+        }
+        assert!(borrowed_bytes > input.len());
+    }
+
+    #[test]
+    fn poll_with_callback() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 10, 5, 128);
+
+        let mut decoder = HeatshrinkDecoder::new(100, 10, 5).expect("Failed to create decoder");
+        let mut decompressed = vec![];
+        for chunk in compressed.chunks(100) {
+            assert_eq!(decoder.sink(chunk), HSDSinkRes::Ok(chunk.len()));
+            let before = decompressed.len();
+            match decoder.poll_with(|byte| decompressed.push(byte)) {
+                HSDPollRes::Empty(sz) => assert_eq!(sz, decompressed.len() - before),
+                res => panic!("Unexpected poll result: {:?}", res),
+            }
+        }
+        while decoder.finish() == HSDFinishRes::More {
+            decoder.poll_with(|byte| decompressed.push(byte));
+        }
+
+        let expected = crate::decode_all(&compressed, 100, 10, 5, 128).expect("Failed to decode");
+        assert_eq!(decompressed, expected);
+    }
+
+    #[test]
+    fn decompress_odd_chunks_then_finalize() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 10, 5, 128);
+
+        // Chunks larger than the 64 byte input buffer fill it before they are sunk
+        let mut decoder = HeatshrinkDecoder::new(64, 10, 5).expect("Failed to create decoder");
+        let mut decompressed = vec![];
+        let mut rest = &compressed[..];
+        for chunk_len in [1, 7, 63, 64, 65, 301].iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (chunk, tail) = rest.split_at((*chunk_len).min(rest.len()));
+            decoder
+                .decompress_chunk(chunk, &mut decompressed)
+                .expect("Failed to decompress chunk");
+            rest = tail;
+        }
+        decoder
+            .finalize(&mut decompressed)
+            .expect("Failed to finalize");
+
+        let expected = crate::decode_all(&compressed, 64, 10, 5, 128).expect("Failed to decode");
+        assert_eq!(decompressed, expected);
+        assert_eq!(
+            decoder.decompress_chunk(&compressed, &mut decompressed),
+            Err(HeatshrinkError::Misuse)
+        );
+    }
+
+    #[test]
+    fn strict_backrefs_reject_unwritten_window() {
+        // Tag bit 0 for a backref with index 4 (distance 5), count 1 (length 2),
+        // before any byte has been decoded
+        let crafted = [0b0000_0010, 0b0000_1000];
+
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+        let mut output = [0; 16];
+        assert_eq!(decoder.sink(&crafted), HSDSinkRes::Ok(crafted.len()));
+        assert_eq!(decoder.poll(&mut output), HSDPollRes::Empty(2));
+        assert_eq!(output[..2], [0, 0]);
+
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+        decoder.set_strict_backrefs(true);
+        assert_eq!(decoder.sink(&crafted), HSDSinkRes::Ok(crafted.len()));
+        assert_eq!(decoder.poll(&mut output), HSDPollRes::ErrorUnknown);
+    }
+
+    #[test]
+    fn strict_backrefs_accept_encoder_output() {
+        let mut input = vec![0; 300];
+        input.extend_from_slice(include_bytes!("heatshrink_decoder.rs"));
+        let compressed = encode_all(&input, 8, 4, 128);
+
+        let mut decoder = HeatshrinkDecoder::new(100, 8, 4).expect("Failed to create decoder");
+        decoder.set_strict_backrefs(true);
+        let mut decompressed = vec![];
+        for chunk in compressed.chunks(100) {
+            assert_eq!(decoder.sink(chunk), HSDSinkRes::Ok(chunk.len()));
+            decoder
+                .poll_into(&mut decompressed)
+                .expect("Failed to poll");
+        }
+        decoder
+            .finalize(&mut decompressed)
+            .expect("Failed to finish");
+        assert_eq!(input, decompressed);
+    }
+
+    #[test]
+    fn error_state_is_sticky_until_reset() {
+        // Backref to distance 5 before any byte has been decoded
+        let crafted = [0b0000_0010, 0b0000_1000];
+
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+        decoder.set_strict_backrefs(true);
+        let mut output = [0; 16];
+        assert_eq!(decoder.sink(&crafted), HSDSinkRes::Ok(crafted.len()));
+        for _ in 0..3 {
+            assert_eq!(decoder.poll(&mut output), HSDPollRes::ErrorUnknown);
+            assert_eq!(decoder.finish(), HSDFinishRes::ErrorUnknown);
+        }
+        let mut decompressed = vec![];
+        assert_eq!(
+            decoder.finalize(&mut decompressed),
+            Err(HeatshrinkError::Corrupt)
+        );
+
+        decoder.reset();
+        let compressed = encode_all(b"abcabcabc", 8, 4, 16);
+        assert_eq!(decoder.sink(&compressed), HSDSinkRes::Ok(compressed.len()));
+        decoder
+            .poll_into(&mut decompressed)
+            .expect("Failed to poll");
+        decoder
+            .finalize(&mut decompressed)
+            .expect("Failed to finish");
+        assert_eq!(decompressed, b"abcabcabc");
+    }
+
+    #[test]
+    fn output_pending_transitions() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(&input[..512], 8, 4, 64);
+
+        let mut decoder = HeatshrinkDecoder::new(1024, 8, 4).expect("Failed to create decoder");
+        let mut output = [0; 1024];
+        assert!(!decoder.output_pending());
+
+        assert_eq!(decoder.sink(&compressed), HSDSinkRes::Ok(compressed.len()));
+        assert!(!decoder.output_pending());
+
+        // A full output buffer leaves decoded output behind
+        assert_eq!(decoder.poll(&mut output[..1]), HSDPollRes::More(1));
+        assert!(decoder.output_pending());
+        assert_eq!(decoder.poll(&mut output[1..]), HSDPollRes::Empty(511));
+        assert!(!decoder.output_pending());
+        assert_eq!(&output[..512], &input[..512]);
+
+        assert_eq!(decoder.finish(), HSDFinishRes::Done);
+        assert!(!decoder.output_pending());
+    }
+
+    #[test]
+    fn peek_next_len_matches_poll() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(&input[..4096], 8, 4, 64);
+
+        let mut decoder = HeatshrinkDecoder::new(256, 8, 4).expect("Failed to create decoder");
+        assert_eq!(decoder.peek_next_len(), None);
+
+        // Poll exactly as many bytes as each token was peeked to produce
+        let mut output = [0; 256];
+        let mut decompressed = vec![];
+        let (mut literals, mut backrefs) = (0, 0);
+        for chunk in compressed.chunks(256) {
+            assert_eq!(decoder.sink(chunk), HSDSinkRes::Ok(chunk.len()));
+            while let Some(len) = decoder.peek_next_len() {
+                assert_eq!(decoder.peek_next_len(), Some(len));
+                match decoder.poll(&mut output[..len]) {
+                    HSDPollRes::More(sz) | HSDPollRes::Empty(sz) if sz == len => {
+                        decompressed.extend_from_slice(&output[..sz]);
+                    }
+                    // the rest of the literal is in the next chunk
+                    HSDPollRes::Empty(0) if len == 1 => break,
+                    e => panic!("Failed to poll {} bytes: {:?}", len, e),
+                }
+                if len == 1 {
+                    literals += 1;
+                } else {
+                    backrefs += 1;
+                }
+            }
+        }
+        assert_eq!(decoder.finish(), HSDFinishRes::Done);
+        assert_eq!(&decompressed[..], &input[..4096]);
+        assert!(literals > 100 && backrefs > 100);
+    }
+
+    #[test]
+    fn token_observer_matches_encoder() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+
+        let encoded_tokens = Arc::new(Mutex::new(vec![]));
+        let observed = encoded_tokens.clone();
+        let mut encoder = HeatshrinkEncoder::new(9, 5).expect("Failed to create encoder");
+        encoder.set_token_observer(move |token| observed.lock().unwrap().push(token));
+        let mut compressed = vec![];
+        let mut remaining = &input[..];
+        while !remaining.is_empty() {
+            match encoder.sink(remaining) {
+                HSESinkRes::Ok(sz) => remaining = &remaining[sz..],
+                e => panic!("Failed to sink data: {:?}", e),
+            }
+            encoder.poll_to(&mut compressed).expect("Failed to poll");
+        }
+        while encoder.finish() == HSEFinishRes::More {
+            encoder.poll_to(&mut compressed).expect("Failed to poll");
+        }
+
+        let decoded_tokens = Arc::new(Mutex::new(vec![]));
+        let observed = decoded_tokens.clone();
+        let mut decoder = HeatshrinkDecoder::new(256, 9, 5).expect("Failed to create decoder");
+        decoder.set_token_observer(move |token| observed.lock().unwrap().push(token));
+        let mut decompressed = vec![];
+        for chunk in compressed.chunks(256) {
+            assert_eq!(decoder.sink(chunk), HSDSinkRes::Ok(chunk.len()));
+            decoder
+                .poll_into(&mut decompressed)
+                .expect("Failed to poll");
+        }
+        decoder
+            .finalize(&mut decompressed)
+            .expect("Failed to finish");
+
+        assert_eq!(&decompressed[..], &input[..]);
+        let encoded_tokens = encoded_tokens.lock().unwrap();
+        assert!(encoded_tokens.len() > 100);
+        assert_eq!(*encoded_tokens, *decoded_tokens.lock().unwrap());
+    }
+
+    #[test]
+    fn poll_budget_splits_long_backref() {
+        // Literal 'a', then a 128 byte backref at distance 1 and 7 bits of padding
+        let crafted = [0xb0, 0x80, 0x3f, 0x80];
+
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 7).expect("Failed to create decoder");
+        let mut output = [0; 256];
+        assert_eq!(decoder.poll_budget(&mut output, 0), HSDPollRes::ErrorNull);
+        assert_eq!(decoder.sink(&crafted), HSDSinkRes::Ok(crafted.len()));
+
+        let mut decompressed = vec![];
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            match decoder.poll_budget(&mut output, 10) {
+                HSDPollRes::More(sz) => {
+                    assert_eq!(sz, 10);
+                    decompressed.extend_from_slice(&output[..sz]);
+                }
+                HSDPollRes::Empty(sz) => {
+                    assert!(sz <= 10);
+                    decompressed.extend_from_slice(&output[..sz]);
+                    if decoder.finish() == HSDFinishRes::Done {
+                        break;
+                    }
+                }
+                e => panic!("Failed to poll: {:?}", e),
+            }
+        }
+        assert!(polls >= 13);
+        let unbudgeted = crate::decode_all(&crafted, 64, 8, 7, 256).expect("Failed to decode");
+        assert_eq!(decompressed, unbudgeted);
+        assert_eq!(decompressed, [b'a'; 129]);
+    }
+
+    #[test]
+    fn max_count_backref_at_stream_end_terminates() {
+        // Literal 'a', then a backref at distance 1 with the largest count for a 7 bit
+        // lookahead (128 bytes), then 7 bits of padding
+        let crafted = [0xb0, 0x80, 0x3f, 0x80];
+
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 7).expect("Failed to create decoder");
+        let mut output = [0; 16];
+        let mut decompressed = vec![];
+        assert_eq!(decoder.sink(&crafted), HSDSinkRes::Ok(crafted.len()));
+
+        // Each poll fills the output buffer or drains the backref, so this is bounded by
+        // 129 / 16 polls plus the finishing round trips
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            assert!(polls <= 12, "decoder did not make progress");
+            match decoder.poll(&mut output) {
+                HSDPollRes::More(sz) => decompressed.extend_from_slice(&output[..sz]),
+                HSDPollRes::Empty(sz) => {
+                    decompressed.extend_from_slice(&output[..sz]);
+                    if decoder.finish() == HSDFinishRes::Done {
+                        break;
+                    }
+                }
+                e => panic!("Failed to poll: {:?}", e),
+            }
+        }
+        assert_eq!(decompressed, [b'a'; 129]);
+    }
+
+    #[test]
+    fn lookahead_one_below_window_max_length_backrefs() {
+        // The count field is one bit narrower than the index, so at a window of 9 and 15
+        // both are split across the MSB and LSB states, and at 15 the index takes the most
+        // bits `get_bits` returns in one call
+        for window_sz2 in [5, 9, 15] {
+            let lookahead_sz2 = window_sz2 - 1;
+            let lookahead = 1usize << lookahead_sz2;
+            let mut input: Vec<u8> = (0..lookahead * 3 + 7).map(|i| (i % 3) as u8).collect();
+            input.extend((0..lookahead * 2).map(|i| (i * 7 % 251) as u8));
+
+            let encoded_tokens = Arc::new(Mutex::new(vec![]));
+            let observed = encoded_tokens.clone();
+            let mut encoder = HeatshrinkEncoder::new(window_sz2, lookahead_sz2)
+                .expect("Failed to create encoder");
+            encoder.set_token_observer(move |token| observed.lock().unwrap().push(token));
+            let mut compressed = vec![];
+            encoder
+                .compress_chunk(&input, &mut compressed)
+                .expect("Failed to compress");
+            encoder
+                .finalize(&mut compressed)
+                .expect("Failed to finalize");
+            let encoded_tokens = encoded_tokens.lock().unwrap();
+            assert!(encoded_tokens.iter().any(|token| matches!(
+                token,
+                Token::Backref { length, .. } if *length == lookahead
+            )));
+
+            for input_buffer_size in [1, 256] {
+                let decoded_tokens = Arc::new(Mutex::new(vec![]));
+                let observed = decoded_tokens.clone();
+                let mut decoder =
+                    HeatshrinkDecoder::new(input_buffer_size, window_sz2, lookahead_sz2)
+                        .expect("Failed to create decoder");
+                decoder.set_token_observer(move |token| observed.lock().unwrap().push(token));
+                let mut decompressed = vec![];
+                for chunk in compressed.chunks(input_buffer_size as usize) {
+                    decoder
+                        .decompress_chunk(chunk, &mut decompressed)
+                        .expect("Failed to decompress");
+                }
+                decoder
+                    .finalize(&mut decompressed)
+                    .expect("Failed to finalize");
+                assert!(
+                    decompressed == input,
+                    "w={window_sz2} ibs={input_buffer_size}"
+                );
+                assert_eq!(*encoded_tokens, *decoded_tokens.lock().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn save_and_load_state_resumes_stream() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 10, 6, 128);
+
+        // Checkpoint with input still buffered, mid-way through the stream
+        let (first, second) = compressed.split_at(compressed.len() / 2 + 33);
+        let (first, pending) = first.split_at(first.len() - 100);
+        let mut decoder = HeatshrinkDecoder::new(200, 10, 6).expect("Failed to create decoder");
+        let mut decompressed = vec![];
+        for chunk in first.chunks(150) {
+            assert_eq!(decoder.sink(chunk), HSDSinkRes::Ok(chunk.len()));
+            decoder
+                .poll_into(&mut decompressed)
+                .expect("Failed to poll");
+        }
+        assert_eq!(decoder.sink(pending), HSDSinkRes::Ok(pending.len()));
+        let mut output = [0; 100];
+        match decoder.poll(&mut output) {
+            HSDPollRes::More(sz) => decompressed.extend_from_slice(&output[..sz]),
+            e => panic!("Expected more output: {:?}", e),
+        }
+        let state = decoder.save_state();
+        drop(decoder);
+
+        let mut decoder = HeatshrinkDecoder::load_state(&state).expect("Failed to load state");
+        decoder
+            .poll_into(&mut decompressed)
+            .expect("Failed to poll");
+        for chunk in second.chunks(150) {
+            assert_eq!(decoder.sink(chunk), HSDSinkRes::Ok(chunk.len()));
+            decoder
+                .poll_into(&mut decompressed)
+                .expect("Failed to poll");
+        }
+        decoder
+            .finalize(&mut decompressed)
+            .expect("Failed to finish");
+        assert_eq!(&decompressed[..], &input[..]);
+
+        assert_eq!(
+            HeatshrinkDecoder::load_state(&state[..state.len() - 1]).err(),
+            Some(HeatshrinkError::InvalidState)
+        );
+        let mut bad_params = state.clone();
+        bad_params[4] = bad_params[3];
+        assert_eq!(
+            HeatshrinkDecoder::load_state(&bad_params).err(),
+            Some(HeatshrinkError::InvalidParams)
+        );
+    }
+
+    #[test]
+    fn load_state_rejects_state_for_other_sizes() {
+        // Each state is only reachable with the sizes or index that polling it relies on
+        for (window_sz2, lookahead_sz2, state, output_index) in [
+            (8, 4, HSDState::BackrefIndexMSB, 0),
+            (4, 3, HSDState::BackrefIndexMSB, 0),
+            (10, 8, HSDState::BackrefCountMSB, 0),
+            (9, 4, HSDState::BackrefCountMSB, 0),
+            (9, 4, HSDState::YieldBackref, 0),
+        ] {
+            let mut decoder = HeatshrinkDecoder::new(64, window_sz2, lookahead_sz2)
+                .expect("Failed to create decoder");
+            decoder.state = state;
+            decoder.output_index = output_index;
+            assert_eq!(
+                HeatshrinkDecoder::load_state(&decoder.save_state()).err(),
+                Some(HeatshrinkError::InvalidState),
+                "w={window_sz2} l={lookahead_sz2} {state:?}"
+            );
+        }
+
+        // The same states load with sizes wide enough for them
+        for (window_sz2, lookahead_sz2, state, output_index) in [
+            (9, 4, HSDState::BackrefIndexMSB, 0),
+            (10, 9, HSDState::BackrefCountMSB, 0),
+            (9, 4, HSDState::YieldBackref, 1),
+        ] {
+            let mut decoder = HeatshrinkDecoder::new(64, window_sz2, lookahead_sz2)
+                .expect("Failed to create decoder");
+            decoder.state = state;
+            decoder.output_index = output_index;
+            assert!(HeatshrinkDecoder::load_state(&decoder.save_state()).is_ok());
+        }
+    }
+
+    #[test]
+    fn stream_decoder_window_spans_feeds() {
+        let text = include_bytes!("heatshrink_decoder.rs");
+        let input: Vec<u8> = text.iter().chain(text.iter()).copied().collect();
+
+        // One continuous encoder session that is only finished after the last message
+        let mut encoder = HeatshrinkEncoder::new(10, 5).expect("Failed to create encoder");
+        let mut decoder = StreamDecoder::new(32, 10, 5).expect("Failed to create decoder");
+        let mut decoded = Vec::new();
+        for message in input.chunks(700) {
+            let mut compressed = Vec::new();
+            let mut message = message;
+            while !message.is_empty() {
+                if let HSESinkRes::Ok(sz) = encoder.sink(message) {
+                    message = &message[sz..];
+                }
+                encoder.poll_into(&mut compressed).unwrap();
+            }
+            // Split tokens across feeds as well
+            for piece in compressed.chunks(7) {
+                decoded.extend(decoder.feed(piece).unwrap());
+            }
+            assert_eq!(decoded, &input[..decoded.len()]);
+        }
+        assert!(decoded.len() > input.len() / 2);
+
+        let mut tail = Vec::new();
+        encoder.finalize(&mut tail).unwrap();
+        decoded.extend(decoder.feed(&tail).unwrap());
+        assert_eq!(decoded, input);
+        assert_eq!(decoder.total_emitted(), input.len() as u64);
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn state_logger_follows_state_path() {
+        struct Path(Arc<Mutex<Vec<String>>>);
+        impl StateLogger for Path {
+            fn on_transition(&mut self, from: &str, to: &str) {
+                self.0.lock().unwrap().push(format!("{from}->{to}"));
+            }
+        }
+
+        let path = Arc::new(Mutex::new(Vec::new()));
+        let mut decoder = HeatshrinkDecoder::new(64, 4, 3).expect("Failed to create decoder");
+        decoder.set_state_logger(Path(path.clone()));
+        let mut decoded = vec![];
+        decoder
+            .decompress_chunk(&encode_all(b"aaaaab", 4, 3, 64), &mut decoded)
+            .unwrap();
+        decoder.finalize(&mut decoded).unwrap();
+        assert_eq!(decoded, b"aaaaab");
+
+        // A literal, a backref for the next 4 bytes, and a literal. The 4 bit index and 3 bit
+        // count fit in their LSB states. The 6 zero bits of padding start another backref
+        // that runs out of input in its count.
+        assert_eq!(
+            *path.lock().unwrap(),
+            [
+                "TagBit->YieldLiteral",
+                "YieldLiteral->TagBit",
+                "TagBit->BackrefIndexLSB",
+                "BackrefIndexLSB->BackrefCountLSB",
+                "BackrefCountLSB->YieldBackref",
+                "YieldBackref->TagBit",
+                "TagBit->YieldLiteral",
+                "YieldLiteral->TagBit",
+                "TagBit->BackrefIndexLSB",
+                "BackrefIndexLSB->BackrefCountLSB",
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_iter_yields_decoded_chunks() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 8, 4, 64);
+        for chunk in [1, 7, 100, 4096] {
+            let decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+            let chunks: Vec<Vec<u8>> = decoder
+                .decode_iter(compressed.as_slice(), chunk)
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert!(chunks.iter().all(|c| !c.is_empty() && c.len() <= chunk));
+            assert_eq!(
+                chunks.concat(),
+                crate::decode_all(&compressed, 64, 8, 4, 64).unwrap()
+            );
+        }
+
+        let decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+        assert_eq!(decoder.decode_iter(&b""[..], 16).count(), 0);
+
+        // A backref before the start of the stream fails and ends the iteration
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+        decoder.set_strict_backrefs(true);
+        let mut iter = decoder.decode_iter(&[0x00, 0x10][..], 16);
+        assert_eq!(iter.next(), Some(Err(HeatshrinkError::Corrupt)));
+        assert_eq!(iter.next(), None);
+        assert!(iter.is_done());
+    }
+
+    #[test]
+    fn decode_iter_retries_pending_source() {
+        // Reads `Pending` before each chunk of up to 5 bytes
+        struct Uart<'a> {
+            data: &'a [u8],
+            ready: bool,
+        }
+        impl Readable for Uart<'_> {
+            fn read<'r>(&mut self, buf: &'r mut [u8]) -> ReadResult<'r> {
+                self.ready = !self.ready;
+                if !self.ready {
+                    return ReadResult::Pending;
+                }
+                let n = self.data.len().min(buf.len()).min(5);
+                if n == 0 {
+                    return ReadResult::Eof;
+                }
+                buf[..n].copy_from_slice(&self.data[..n]);
+                self.data = &self.data[n..];
+                ReadResult::Read(&buf[..n])
+            }
+        }
+
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 8, 4, 64);
+        let uart = Uart {
+            data: &compressed,
+            ready: true,
+        };
+        let decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+        let mut iter = decoder.decode_iter(uart, 64);
+        let mut decompressed = vec![];
+        let mut pending = 0;
+        while !iter.is_done() {
+            match iter.next() {
+                Some(chunk) => decompressed.extend_from_slice(&chunk.unwrap()),
+                None => pending += 1,
+            }
+        }
+        assert!(pending > 0);
+        assert_eq!(iter.next(), None);
+        assert_eq!(decompressed, input);
+    }
+}
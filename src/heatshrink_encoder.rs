@@ -1,29 +1,30 @@
 #[cfg(not(feature = "std"))]
-use alloc::vec;
+use alloc::boxed::Box;
 #[cfg(not(feature = "std"))]
 use alloc::vec::Vec;
 
 use core::cmp::min;
+use core::mem::MaybeUninit;
 use core::ptr;
 
 use crate::{
-    common::*, HEATSHRINK_MAX_WINDOW_BITS, HEATSHRINK_MIN_LOOKAHEAD_BITS,
-    HEATSHRINK_MIN_WINDOW_BITS,
+    common::*, Config, HeatshrinkError, RingSink, Token, Writable, HEATSHRINK_MAX_WINDOW_BITS,
+    HEATSHRINK_MIN_LOOKAHEAD_BITS, HEATSHRINK_MIN_WINDOW_BITS,
 };
 
 // Define result types for encoding operations
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HSESinkRes {
     /// data sunk into input buffer
     /// returns the number of bytes actually sunk
     Ok(usize),
-    /// NULL argument
+    /// NULL argument, or an empty input
     ErrorNull,
     /// misuse of API
     ErrorMisuse,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HSEPollRes {
     /// input exhausted
     /// returns the number of bytes actually copied
@@ -35,9 +36,15 @@ pub enum HSEPollRes {
     ErrorNull,
     /// misuse of API
     ErrorMisuse,
+    /// the encoder is in the error state after a `runtime-checks` invariant failed,
+    /// `reset` it to encode again
+    ErrorUnknown,
+    /// the output exceeded the limit set with `set_expansion_limit`, `reset` the encoder to
+    /// encode again
+    Aborted,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HSEFinishRes {
     /// encoding is completed
     Done,
@@ -45,6 +52,8 @@ pub enum HSEFinishRes {
     More,
     /// NULL argument
     ErrorNull,
+    /// the encoder is in the error state, `reset` it to encode again
+    ErrorUnknown,
 }
 
 // Define the states for the encoder state machine
@@ -70,19 +79,236 @@ enum HSEState {
     FlushBits,
     /// done
     Done,
+    /// an invariant failed, only with `runtime-checks`
+    Error,
 }
 
+impl HSEState {
+    /// every state, in declaration order, so that `ALL[state as usize] == state`
+    const ALL: [HSEState; 11] = [
+        HSEState::NotFull,
+        HSEState::Filled,
+        HSEState::Search,
+        HSEState::YieldTagBit,
+        HSEState::YieldLiteral,
+        HSEState::YieldBrIndex,
+        HSEState::YieldBrLength,
+        HSEState::SaveBacklog,
+        HSEState::FlushBits,
+        HSEState::Done,
+        HSEState::Error,
+    ];
+
+    /// The variant name, for the `StateLogger`
+    #[cfg(feature = "trace")]
+    fn name(self) -> &'static str {
+        HSE_STATE_NAMES[self as usize]
+    }
+}
+
+/// Names of the encoder states, in the order of `HeatshrinkEncoder::state_visit_counts`
+#[cfg(any(feature = "trace", feature = "test-introspection"))]
+pub const HSE_STATE_NAMES: [&str; HSEState::ALL.len()] = [
+    "NotFull",
+    "Filled",
+    "Search",
+    "YieldTagBit",
+    "YieldLiteral",
+    "YieldBrIndex",
+    "YieldBrLength",
+    "SaveBacklog",
+    "FlushBits",
+    "Done",
+    "Error",
+];
+
 // Define constants for match not found
-const MATCH_NOT_FOUND: u16 = u16::MAX;
+const MATCH_NOT_FOUND: WindowIndex = WindowIndex::MAX;
+
+/// Position of the previous occurrence of a byte in the search index, or `NO_LINK` for none,
+/// unsigned so that every position of the 64 KiB buffer of a 32 KiB window fits
+#[cfg(not(feature = "wide-window"))]
+type SearchLink = u16;
+/// Position of the previous occurrence of a byte in the search index, or `NO_LINK` for none,
+/// wide enough for the 128 KiB buffer of a 64 KiB window
+#[cfg(feature = "wide-window")]
+type SearchLink = u32;
+
+/// Shortest run of literals worth a run header: the header costs a tag bit and a backref's
+/// index and length bits, and saves the tag bit of every literal in the run
+#[cfg(feature = "literal-runs")]
+const fn literal_run_min(window_sz2: u8, lookahead_sz2: u8) -> usize {
+    2 + window_sz2 as usize + lookahead_sz2 as usize
+}
+
+/// Size of the stack buffer `poll_to` polls through before forwarding to the destination
+const POLL_TO_CHUNK_SIZE: usize = 64;
+
+/// Spare capacity reserved in the output `Vec` when it is full in `poll_into`.
+const POLL_INTO_RESERVE: usize = 1024;
+
+/// Whether the encoder supports the given window and lookahead sizes
+pub(crate) fn valid_params(window_sz2: u8, lookahead_sz2: u8) -> bool {
+    (HEATSHRINK_MIN_WINDOW_BITS..=HEATSHRINK_MAX_WINDOW_BITS).contains(&window_sz2)
+        && lookahead_sz2 >= HEATSHRINK_MIN_LOOKAHEAD_BITS
+        && lookahead_sz2 < window_sz2
+}
+
+/// Bytes an encoder with the given window allocates for its buffers: the `2 << window_sz2`
+/// byte window plus one search index link per window byte. The fixed-size fields of the
+/// encoder itself are not included, see `encoder_bytes` for the total.
+pub const fn encoder_buffer_bytes(window_sz2: u8) -> usize {
+    let buffer_len = 2usize << window_sz2;
+    buffer_len + buffer_len * core::mem::size_of::<SearchLink>()
+}
+
+/// Bytes an encoder with the given window takes in total: the `HeatshrinkEncoder` value
+/// itself plus the buffers of `encoder_buffer_bytes`, unless `smallvec` keeps them inline.
+pub const fn encoder_bytes(window_sz2: u8) -> usize {
+    #[cfg(feature = "smallvec")]
+    if 2usize << window_sz2 <= SMALL_STORAGE_SIZE {
+        return core::mem::size_of::<HeatshrinkEncoder>();
+    }
+    core::mem::size_of::<HeatshrinkEncoder>() + encoder_buffer_bytes(window_sz2)
+}
+
+/// Backing storage for the window and search index
+#[cfg(not(feature = "smallvec"))]
+type Storage<T> = Vec<T>;
+/// Backing storage for the window and search index, inline up to `window_sz2 == 8`
+#[cfg(feature = "smallvec")]
+type Storage<T> = smallvec::SmallVec<[T; SMALL_STORAGE_SIZE]>;
+
+/// Inline capacity of `Storage`, enough for both windows of `window_sz2 == 8`
+#[cfg(feature = "smallvec")]
+const SMALL_STORAGE_SIZE: usize = 2 << 8;
+
+/// Storage with `len` copies of `value`
+fn filled<T: Clone>(len: usize, value: T) -> Storage<T> {
+    let mut storage = Storage::new();
+    storage.resize(len, value);
+    storage
+}
+
+/// Search index entry that ends a chain, for a position without an earlier occurrence of its
+/// byte or that is not indexed yet, so that a state saved before the first search still
+/// passes the link check of `load_state`. Links point strictly backwards, so the last
+/// buffer position, the only one that could equal it, is never a link target.
+const NO_LINK: SearchLink = <SearchLink as Link>::NONE;
+
+/// Receives the out-of-range buffer accesses that the `debug-bounds` feature catches in the
+/// encoder's hot path, before the encoder panics on them.
+#[cfg(feature = "debug-bounds")]
+pub trait BoundsLogger: Send {
+    /// `access` names the access, `range` is the indices it was about to touch, and `len` is
+    /// the length of the buffer it indexes.
+    fn out_of_bounds(&self, access: &'static str, range: core::ops::Range<usize>, len: usize);
+}
+
+/// Told about every state change of the encoder's or decoder's state machine during `poll`,
+/// with the `trace` feature, e.g. to log the path through the states without `println!`.
+#[cfg(feature = "trace")]
+pub trait StateLogger: Send {
+    /// The state machine moved from the state named `from` to the state named `to`
+    fn on_transition(&mut self, from: &str, to: &str);
+}
+
+/// A search index entry, the position of the previous occurrence of the same byte or `NONE`
+pub(crate) trait Link: Copy + PartialEq {
+    const NONE: Self;
+
+    fn pos(self) -> usize;
+}
+
+impl Link for u16 {
+    const NONE: Self = u16::MAX;
+
+    #[inline(always)]
+    fn pos(self) -> usize {
+        self as usize
+    }
+}
+
+impl Link for u32 {
+    const NONE: Self = u32::MAX;
+
+    #[inline(always)]
+    fn pos(self) -> usize {
+        self as usize
+    }
+}
+
+/// Longest match shorter than this many bytes costs more than the literals it replaces
+#[inline(always)]
+pub(crate) fn break_even_point(window_sz2: u8, lookahead_sz2: u8) -> usize {
+    ((1 + window_sz2 + lookahead_sz2) / 8) as usize
+}
+
+/// Walk the chain of `links` from `end` back to `start` for the longest match of the bytes at
+/// `end`, up to `maxlen` long, returning the position of the latest of the longest matches
+/// and its length, 0 if there is none. `buf` holds at least `maxlen` bytes from `end`.
+#[inline(always)]
+pub(crate) fn longest_match<L: Link>(
+    buf: &[u8],
+    links: &[L],
+    start: usize,
+    end: usize,
+    maxlen: usize,
+) -> (usize, usize) {
+    let mut match_maxlen = 0;
+    let mut match_index = 0;
+
+    debug_assert!(buf.len() >= end + maxlen);
+    debug_assert!(links.len() > end);
+    let needlepoint = &buf[end..];
+    let mut pos = links[end];
+    unsafe {
+        // fuzz with debug assertions
+        while pos != L::NONE && pos.pos() >= start {
+            let posidx = pos.pos();
+            debug_assert!(posidx < buf.len());
+            let pospoint = buf.get_unchecked(posidx..);
+
+            debug_assert!(pospoint.len() >= match_maxlen);
+            debug_assert!(needlepoint.len() >= match_maxlen);
+            if pospoint.get_unchecked(match_maxlen) != needlepoint.get_unchecked(match_maxlen) {
+                pos = *links.get_unchecked(posidx);
+                continue;
+            }
+
+            let mut len = 1;
+            while len < maxlen {
+                debug_assert!(pospoint.len() >= len);
+                debug_assert!(needlepoint.len() >= len);
+                if pospoint.get_unchecked(len) != needlepoint.get_unchecked(len) {
+                    break;
+                }
+                len += 1;
+            }
+
+            if len > match_maxlen {
+                match_maxlen = len;
+                match_index = posidx;
+                if len == maxlen {
+                    break;
+                }
+            }
+
+            debug_assert!(posidx < links.len());
+            pos = *links.get_unchecked(posidx);
+        }
+    }
+    (match_index, match_maxlen)
+}
 
 pub struct HeatshrinkEncoder {
     /// bytes in input buffer
     input_size: usize,
     match_scan_index: usize,
     match_length: usize,
-    match_pos: u16,
+    match_pos: WindowIndex,
     /// enqueued outgoing bits
-    outgoing_bits: u16,
+    outgoing_bits: WindowIndex,
     outgoing_bits_count: u8,
     flags: u8,
     /// current state machine node
@@ -99,12 +325,54 @@ pub struct HeatshrinkEncoder {
     input_buffer_size: usize,
     /// size of lookahead
     lookahead_size: usize,
+    /// bytes of the previous-input window that hold real (or primed) data
+    backlog_size: usize,
     /// search index
-    /// using dynamic allocation
-    search_index: Vec<i16>,
+    /// using dynamic allocation, unless small enough to be inline with `smallvec`
+    search_index: Storage<SearchLink>,
+    /// buffer positions covered by `search_index` and `last_seen`, 0 to rebuild the index
+    indexed: usize,
+    /// latest position of each byte value below `indexed`, or `NO_LINK`, which the last
+    /// buffer position of the largest window also equals, see `shift_index`
+    last_seen: [SearchLink; 256],
     /// input buffer and / sliding window for expansion
-    /// using dynamic allocation
-    buffer: Vec<u8>,
+    /// using dynamic allocation, unless small enough to be inline with `smallvec`
+    buffer: Storage<u8>,
+    /// literals left to emit raw after a run header
+    #[cfg(feature = "literal-runs")]
+    literal_run: usize,
+    /// positions from `match_scan_index` on that are known to have no match
+    #[cfg(feature = "literal-runs")]
+    unmatched: usize,
+    /// whether `set_literal_runs` enabled run headers, off for standard heatshrink streams
+    #[cfg(feature = "literal-runs")]
+    literal_runs: bool,
+    /// bytes taken by `sink` and `stage` since the encoder was created or reset
+    total_sunk: u64,
+    /// output polled by `poll_packet` that does not fill a packet yet
+    packet: Vec<u8>,
+    /// bytes polled since the encoder was created or reset
+    total_polled: u64,
+    /// most bytes to poll before aborting, if set
+    expansion_limit: Option<u64>,
+    /// input to output ratio after which the search is skipped, if set
+    ratio_target: Option<f32>,
+    /// input bytes and output bits of the tokens emitted so far, for `ratio_target`
+    ratio_bytes: u64,
+    ratio_bits: u64,
+    /// whether the tokens so far meet `ratio_target`, so the next byte is a literal
+    literals_only: bool,
+    /// called with each token as it is finalized, if set
+    token_observer: Option<Box<dyn FnMut(Token) + Send>>,
+    /// told about out-of-range accesses before the encoder panics, if set
+    #[cfg(feature = "debug-bounds")]
+    bounds_logger: Option<Box<dyn BoundsLogger>>,
+    /// told about every state change in `poll`, if set
+    #[cfg(feature = "trace")]
+    state_logger: Option<Box<dyn StateLogger>>,
+    /// times `poll` entered each state, indexed like `HSE_STATE_NAMES`
+    #[cfg(feature = "test-introspection")]
+    state_visits: [u32; HSEState::ALL.len()],
 }
 
 impl HeatshrinkEncoder {
@@ -115,15 +383,11 @@ impl HeatshrinkEncoder {
     ///    * 1<<lookahead_sz2 byte lookahead
     ///
     /// ```rust
-    /// use tsz_heatshrink::HeatshrinkEncoder;
+    /// use embedded_heatshrink::HeatshrinkEncoder;
     /// let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
     /// ```
     pub fn new(window_sz2: u8, lookahead_sz2: u8) -> Option<Self> {
-        if window_sz2 < HEATSHRINK_MIN_WINDOW_BITS
-            || window_sz2 > HEATSHRINK_MAX_WINDOW_BITS
-            || lookahead_sz2 < HEATSHRINK_MIN_LOOKAHEAD_BITS
-            || lookahead_sz2 >= window_sz2
-        {
+        if !valid_params(window_sz2, lookahead_sz2) {
             return None;
         }
 
@@ -147,11 +411,392 @@ impl HeatshrinkEncoder {
             lookahead_sz2,
             input_buffer_size: 1 << window_sz2,
             lookahead_size: 1 << lookahead_sz2,
-            search_index: vec![0; buf_sz],
-            buffer: vec![0; buf_sz],
+            backlog_size: 0,
+            search_index: filled(buf_sz, NO_LINK),
+            indexed: 0,
+            last_seen: [NO_LINK; 256],
+            buffer: filled(buf_sz, 0),
+            #[cfg(feature = "literal-runs")]
+            literal_run: 0,
+            #[cfg(feature = "literal-runs")]
+            unmatched: 0,
+            #[cfg(feature = "literal-runs")]
+            literal_runs: false,
+            total_sunk: 0,
+            total_polled: 0,
+            expansion_limit: None,
+            ratio_target: None,
+            ratio_bytes: 0,
+            ratio_bits: 0,
+            literals_only: false,
+            packet: Vec::new(),
+            token_observer: None,
+            #[cfg(feature = "debug-bounds")]
+            bounds_logger: None,
+            #[cfg(feature = "trace")]
+            state_logger: None,
+            #[cfg(feature = "test-introspection")]
+            state_visits: [0; HSEState::ALL.len()],
+        })
+    }
+
+    ///
+    /// Initialize the `HeatshrinkEncoder` with the blessed `DEFAULT_WINDOW_BITS` and
+    /// `DEFAULT_LOOKAHEAD_BITS`, for users who do not want to tune the sizes.
+    pub fn with_default() -> Self {
+        let Config {
+            window_sz2,
+            lookahead_sz2,
+        } = Config::default();
+        Self::new(window_sz2, lookahead_sz2).expect("Default parameters are valid")
+    }
+
+    ///
+    /// Initialize the `HeatshrinkEncoder` like `new`, with the window and lookahead sizes
+    /// checked at compile time, so that a fixed firmware configuration needs no `unwrap`.
+    /// The buffers are still allocated at runtime, so this is not a `const fn`.
+    ///
+    /// ```rust
+    /// use embedded_heatshrink::HeatshrinkEncoder;
+    /// let encoder = HeatshrinkEncoder::new_const::<8, 4>();
+    /// ```
+    ///
+    /// An invalid configuration fails to build:
+    ///
+    /// ```compile_fail,E0080
+    /// use embedded_heatshrink::HeatshrinkEncoder;
+    /// let encoder = HeatshrinkEncoder::new_const::<8, 8>();
+    /// ```
+    pub fn new_const<const W: u8, const L: u8>() -> Self {
+        const {
+            assert!(
+                W >= HEATSHRINK_MIN_WINDOW_BITS
+                    && W <= HEATSHRINK_MAX_WINDOW_BITS
+                    && L >= HEATSHRINK_MIN_LOOKAHEAD_BITS
+                    && L < W,
+                "invalid window or lookahead size"
+            )
+        };
+        Self::new(W, L).expect("Parameters were checked at compile time")
+    }
+
+    ///
+    /// Initialize an encoder for all of `input` at once, so that the caller only polls it
+    /// and never sinks. The encoder borrows `input` and sinks it whenever the input buffer
+    /// drains.
+    ///
+    /// ```rust
+    /// use embedded_heatshrink::{HSEFinishRes, HSEPollRes, HeatshrinkEncoder};
+    /// let mut encoder = HeatshrinkEncoder::from_input(b"abcabcabc", 8, 4).unwrap();
+    /// let mut compressed = vec![];
+    /// let mut out = [0; 64];
+    /// loop {
+    ///     match encoder.poll(&mut out) {
+    ///         HSEPollRes::More(sz) => compressed.extend_from_slice(&out[..sz]),
+    ///         HSEPollRes::Empty(sz) => {
+    ///             compressed.extend_from_slice(&out[..sz]);
+    ///             if encoder.finish() == HSEFinishRes::Done {
+    ///                 break;
+    ///             }
+    ///         }
+    ///         e => panic!("Failed to poll: {:?}", e),
+    ///     }
+    /// }
+    /// ```
+    pub fn from_input(input: &[u8], window_sz2: u8, lookahead_sz2: u8) -> Option<SliceEncoder<'_>> {
+        Some(SliceEncoder {
+            encoder: Self::new(window_sz2, lookahead_sz2)?,
+            input,
         })
     }
 
+    ///
+    /// Reset the encoder to its freshly constructed state so that it can encode a new
+    /// stream, reusing the allocated buffers.
+    ///
+    pub fn reset(&mut self) {
+        self.input_size = 0;
+        self.match_scan_index = 0;
+        self.match_length = 0;
+        self.match_pos = 0;
+        self.outgoing_bits = 0;
+        self.outgoing_bits_count = 0;
+        self.flags = 0;
+        self.state = HSEState::NotFull;
+        self.current_byte = 0;
+        self.bit_index = 0x80;
+        self.backlog_size = 0;
+        self.search_index.fill(NO_LINK);
+        self.indexed = 0;
+        self.buffer.fill(0);
+        #[cfg(feature = "literal-runs")]
+        {
+            self.literal_run = 0;
+            self.unmatched = 0;
+        }
+        self.total_sunk = 0;
+        self.total_polled = 0;
+        self.ratio_bytes = 0;
+        self.ratio_bits = 0;
+        self.literals_only = false;
+        self.packet.clear();
+        #[cfg(feature = "test-introspection")]
+        {
+            self.state_visits = [0; HSEState::ALL.len()];
+        }
+    }
+
+    ///
+    /// Reset the encoder to encode a new stream with a different window and lookahead,
+    /// reusing the allocated buffers when they are already large enough.
+    ///
+    /// Returns `HeatshrinkError::InvalidParams`, leaving the encoder untouched, if `new`
+    /// would reject the sizes.
+    pub fn resize(&mut self, window_sz2: u8, lookahead_sz2: u8) -> Result<(), HeatshrinkError> {
+        if !valid_params(window_sz2, lookahead_sz2) {
+            return Err(HeatshrinkError::InvalidParams);
+        }
+
+        let buf_sz = (2 << window_sz2) as usize;
+        self.window_sz2 = window_sz2;
+        self.lookahead_sz2 = lookahead_sz2;
+        self.input_buffer_size = 1 << window_sz2;
+        self.lookahead_size = 1 << lookahead_sz2;
+
+        // clear first so that reset has nothing to zero and resize zeroes every byte
+        self.search_index.clear();
+        self.buffer.clear();
+        self.reset();
+        self.search_index.resize(buf_sz, NO_LINK);
+        self.buffer.resize(buf_sz, 0);
+        Ok(())
+    }
+
+    ///
+    /// Serialize the complete encoder state, including the window and search index, so
+    /// that a stream can be paused and resumed later, even in another process, with
+    /// `load_state`.
+    ///
+    /// The token observer and a partial packet of `poll_packet` are not part of the state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::default();
+        w.put_u8(STATE_VERSION);
+        w.put_u8(self.window_sz2);
+        w.put_u8(self.lookahead_sz2);
+        w.put_usize(self.input_size);
+        w.put_usize(self.match_scan_index);
+        w.put_usize(self.match_length);
+        w.put_window_index(self.match_pos);
+        w.put_window_index(self.outgoing_bits);
+        w.put_u8(self.outgoing_bits_count);
+        w.put_u8(self.flags);
+        w.put_u8(self.state as u8);
+        w.put_u8(self.current_byte);
+        w.put_u8(self.bit_index);
+        w.put_usize(self.backlog_size);
+        w.put_u64(self.total_sunk);
+        #[cfg(feature = "literal-runs")]
+        {
+            w.put_usize(self.literal_run);
+            w.put_usize(self.unmatched);
+            w.put_u8(self.literal_runs as u8);
+        }
+        for &pos in self.search_index.iter() {
+            w.put_bytes(&pos.to_le_bytes());
+        }
+        w.put_bytes(&self.buffer);
+        w.bytes
+    }
+
+    ///
+    /// Restore an encoder from the bytes produced by `save_state`.
+    ///
+    /// Returns `HeatshrinkError::InvalidParams` if the saved window and lookahead sizes are
+    /// not accepted by `new`, and `HeatshrinkError::InvalidState` if the bytes are
+    /// truncated, from another version, or describe an impossible state.
+    pub fn load_state(bytes: &[u8]) -> Result<Self, HeatshrinkError> {
+        let mut r = StateReader { bytes };
+        if r.get_u8()? != STATE_VERSION {
+            return Err(HeatshrinkError::InvalidState);
+        }
+        let window_sz2 = r.get_u8()?;
+        let lookahead_sz2 = r.get_u8()?;
+        let mut encoder =
+            Self::new(window_sz2, lookahead_sz2).ok_or(HeatshrinkError::InvalidParams)?;
+
+        encoder.input_size = r.get_usize()?;
+        encoder.match_scan_index = r.get_usize()?;
+        encoder.match_length = r.get_usize()?;
+        encoder.match_pos = r.get_window_index()?;
+        encoder.outgoing_bits = r.get_window_index()?;
+        encoder.outgoing_bits_count = r.get_u8()?;
+        encoder.flags = r.get_u8()?;
+        encoder.state = *HSEState::ALL
+            .get(r.get_u8()? as usize)
+            .ok_or(HeatshrinkError::InvalidState)?;
+        encoder.current_byte = r.get_u8()?;
+        encoder.bit_index = r.get_u8()?;
+        encoder.backlog_size = r.get_usize()?;
+        encoder.total_sunk = r.get_u64()?;
+        #[cfg(feature = "literal-runs")]
+        {
+            encoder.literal_run = r.get_usize()?;
+            encoder.unmatched = r.get_usize()?;
+            let literal_runs = r.get_u8()?;
+            encoder.literal_runs = literal_runs == 1;
+            if encoder.match_scan_index + encoder.literal_run.max(encoder.unmatched)
+                > encoder.input_size + 1
+                || literal_runs > 1
+            {
+                return Err(HeatshrinkError::InvalidState);
+            }
+        }
+        for (i, pos) in encoder.search_index.iter_mut().enumerate() {
+            let b = r.take(core::mem::size_of::<SearchLink>())?;
+            *pos = SearchLink::from_le_bytes(b.try_into().unwrap());
+            // the match search follows these links without bounds checks, so they must
+            // point strictly backwards or end the chain
+            if *pos != NO_LINK && *pos as usize >= i {
+                return Err(HeatshrinkError::InvalidState);
+            }
+        }
+        let buffer_len = encoder.buffer.len();
+        encoder.buffer.copy_from_slice(r.take(buffer_len)?);
+        r.finish()?;
+
+        let ibs = encoder.input_buffer_size;
+        if encoder.input_size > ibs
+            || encoder.match_scan_index > encoder.input_size
+            || encoder.match_length > encoder.lookahead_size
+            || encoder.match_pos as usize > ibs
+            || encoder.outgoing_bits_count > 16
+            || encoder.flags & !(FLAG_IS_FINISHING | FLAG_HAS_INPUT) != 0
+            || !encoder.bit_index.is_power_of_two()
+            || encoder.backlog_size > ibs
+        {
+            return Err(HeatshrinkError::InvalidState);
+        }
+        Ok(encoder)
+    }
+
+    ///
+    /// Call `f` with every literal and backreference as the encoder finishes emitting it,
+    /// e.g. to visualize the compression decisions. The observer survives `reset`.
+    pub fn set_token_observer(&mut self, f: impl FnMut(Token) + Send + 'static) {
+        self.token_observer = Some(Box::new(f));
+    }
+
+    ///
+    /// Remove the token observer set with `set_token_observer`.
+    pub fn clear_token_observer(&mut self) {
+        self.token_observer = None;
+    }
+
+    ///
+    /// Report the out-of-range accesses caught by the `debug-bounds` feature to `logger`
+    /// before the encoder panics on them. The logger survives `reset`.
+    #[cfg(feature = "debug-bounds")]
+    pub fn set_bounds_logger(&mut self, logger: impl BoundsLogger + 'static) {
+        self.bounds_logger = Some(Box::new(logger));
+    }
+
+    ///
+    /// Tell `logger` about every state change while polling. The logger survives `reset`.
+    #[cfg(feature = "trace")]
+    pub fn set_state_logger(&mut self, logger: impl StateLogger + 'static) {
+        self.state_logger = Some(Box::new(logger));
+    }
+
+    ///
+    /// Remove the logger set with `set_state_logger`.
+    #[cfg(feature = "trace")]
+    pub fn clear_state_logger(&mut self) {
+        self.state_logger = None;
+    }
+
+    ///
+    /// How many times `poll` entered each state since the encoder was created or reset,
+    /// indexed like `HSE_STATE_NAMES`, e.g. to check that tests reach the rare states.
+    #[cfg(feature = "test-introspection")]
+    pub fn state_visit_counts(&self) -> [u32; HSEState::ALL.len()] {
+        self.state_visits
+    }
+
+    ///
+    /// Give up on a stream that is not worth compressing: once more than `limit` bytes were
+    /// polled since the encoder was created or reset, polling returns `HSEPollRes::Aborted`,
+    /// without the bytes of that call, until the encoder is `reset`, and helpers such as
+    /// `poll_into` return `HeatshrinkError::ExpansionLimit`. With `limit` set to the input
+    /// length, the caller can store the input raw as soon as compression is a loss instead
+    /// of finishing it first.
+    ///
+    /// The limit survives `reset`. Like the token observer, it is not part of the saved
+    /// state, and neither is the count of polled bytes.
+    pub fn set_expansion_limit(&mut self, limit: usize) {
+        self.expansion_limit = Some(limit as u64);
+    }
+
+    ///
+    /// Remove the limit set with `set_expansion_limit`.
+    pub fn clear_expansion_limit(&mut self) {
+        self.expansion_limit = None;
+    }
+
+    ///
+    /// Emit runs of incompressible bytes as a run header followed by the raw bytes, which
+    /// saves the tag bit of every literal in the run. Off by default, so that enabling the
+    /// `literal-runs` feature anywhere in a build does not change the output: streams with
+    /// runs only decode with this crate's decoder built with the feature.
+    ///
+    /// The setting survives `reset` and is part of the saved state.
+    #[cfg(feature = "literal-runs")]
+    pub fn set_literal_runs(&mut self, enabled: bool) {
+        self.literal_runs = enabled;
+    }
+
+    ///
+    /// Trade ratio for speed on a large input: while the tokens emitted since the encoder
+    /// was created or reset compress their input by at least `target`, e.g. `2.0` for half
+    /// the size, the next byte is emitted as a literal without searching for a match. The
+    /// literals count toward the ratio too, so once it drops below `target` the search
+    /// resumes, and the ratio of the whole stream stays close to `target` where the input
+    /// allows it. The stream stays valid, as a literal always is.
+    ///
+    /// The target survives `reset`, and like the expansion limit, it is not part of the
+    /// saved state.
+    pub fn set_ratio_target(&mut self, target: f32) {
+        self.ratio_target = Some(target);
+    }
+
+    ///
+    /// Remove the target set with `set_ratio_target`, so that every byte is searched again.
+    pub fn clear_ratio_target(&mut self) {
+        self.ratio_target = None;
+        self.literals_only = false;
+    }
+
+    ///
+    /// Prime the encoder with a preset dictionary before any input is sunk.
+    ///
+    /// The last `1 << window_sz2` bytes of `dict` are loaded into the previous-input
+    /// window, so they are indexed with the first input and backreferences into the
+    /// dictionary are found. The decoder must be primed with the same `dict` using
+    /// `HeatshrinkDecoder::prime`.
+    ///
+    /// Returns the number of dictionary bytes loaded, or HSER_SINK_ERROR_MISUSE if
+    /// input has already been sunk.
+    pub fn prime(&mut self, dict: &[u8]) -> HSESinkRes {
+        if self.flags != 0 || self.state != HSEState::NotFull {
+            return HSESinkRes::ErrorMisuse;
+        }
+
+        let dict = &dict[dict.len().saturating_sub(self.input_buffer_size)..];
+        let backlog_end = self.get_input_offset();
+        self.buffer[backlog_end - dict.len()..backlog_end].copy_from_slice(dict);
+        self.backlog_size = dict.len();
+        HSESinkRes::Ok(dict.len())
+    }
+
     ///
     /// Sink all of the bytes in in_buf to the encoder, if bytes must be emitted
     /// they are emitted to out_buf. The number of bytes actually emitted is returned.
@@ -201,16 +846,58 @@ impl HeatshrinkEncoder {
     /// Sink up to `in_buf.len()` bytes from `in_buf` into the encoder.
     /// the number of bytes actually sunk is returned on success.
     ///
-    /// Do not provide an empty in_buf
+    /// An empty in_buf returns `ErrorNull`, like the decoder, so that a caller looping
+    /// until its input is sunk cannot spin on `Ok(0)`.
     #[inline]
     pub fn sink(&mut self, in_buf: &[u8]) -> HSESinkRes {
         // TODO: remove these checks and improve the docs instead
         // These checks are in the hot loop of pushing data through the encoder
         // this function gets called roughly O(n / 100) times for O(n) bytes of input
+        if in_buf.is_empty() {
+            return HSESinkRes::ErrorNull;
+        }
         if (self.is_finishing()) | (self.state != HSEState::NotFull) {
             return HSESinkRes::ErrorMisuse;
         }
 
+        let cp_sz = self.copy_input(in_buf);
+
+        // If the input buffer is full, then caller needs to poll to progress
+        if self.input_size == self.input_buffer_size {
+            self.state = HSEState::Filled;
+        }
+
+        HSESinkRes::Ok(cp_sz)
+    }
+
+    ///
+    /// Stage up to `in_buf.len()` bytes from `in_buf` like `sink`, but without starting the
+    /// search when the input buffer fills, so input can be buffered during idle time.
+    /// The number of bytes actually staged is returned, 0 once the buffer is full.
+    ///
+    /// Call `begin_search` to start the search, then `poll` as usual.
+    pub fn stage(&mut self, in_buf: &[u8]) -> HSESinkRes {
+        if self.is_finishing() || self.state != HSEState::NotFull {
+            return HSESinkRes::ErrorMisuse;
+        }
+        HSESinkRes::Ok(self.copy_input(in_buf))
+    }
+
+    ///
+    /// Start searching the staged input, so that the next `poll` emits output.
+    ///
+    /// Searching before the input buffer is full is valid, but the output differs from what
+    /// `sink` would produce. With less than a lookahead of staged input there is nothing to
+    /// search yet, and this does nothing; `finish` flushes such a tail.
+    pub fn begin_search(&mut self) {
+        if self.state == HSEState::NotFull && self.input_size >= self.lookahead_size {
+            self.state = HSEState::Filled;
+        }
+    }
+
+    /// Copy as much of `in_buf` as fits to the end of the input buffer, returning the count
+    #[inline(always)]
+    fn copy_input(&mut self, in_buf: &[u8]) -> usize {
         // Calculate the offset and remaining bytes at the end of the input buffer window
         let write_offset = self.get_input_offset() + self.input_size;
         let rem = self.input_buffer_size - self.input_size;
@@ -226,13 +913,42 @@ impl HeatshrinkEncoder {
             )
         };
         self.input_size += cp_sz;
+        self.total_sunk += cp_sz as u64;
+        self.flags |= FLAG_HAS_INPUT;
+        cp_sz
+    }
 
-        // If the input buffer is full, then caller needs to poll to progress
-        if cp_sz == rem {
-            self.state = HSEState::Filled;
+    ///
+    /// Sink directly from the internal buffer of `r`, consuming exactly the bytes that were
+    /// sunk, to avoid copying through a scratch buffer. The number of bytes sunk is returned,
+    /// 0 once `r` is at end of file.
+    ///
+    /// Like `sink`, poll between calls. Sinking while the encoder is not accepting input is
+    /// reported as an `InvalidInput` error.
+    #[cfg(feature = "std")]
+    pub fn sink_bufread(&mut self, r: &mut impl std::io::BufRead) -> std::io::Result<usize> {
+        use std::io::{Error, ErrorKind};
+
+        let buf = loop {
+            match r.fill_buf() {
+                Ok(buf) => break buf,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.sink(buf) {
+            HSESinkRes::Ok(sz) => {
+                r.consume(sz);
+                Ok(sz)
+            }
+            HSESinkRes::ErrorMisuse | HSESinkRes::ErrorNull => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the encoder is not accepting input, poll or finish first",
+            )),
         }
-
-        HSESinkRes::Ok(cp_sz)
     }
 
     /// Poll for output from the encoder, copying at most `out_buf.len()` bytes
@@ -242,96 +958,537 @@ impl HeatshrinkEncoder {
     ///
     #[inline]
     pub fn poll(&mut self, out_buf: &mut [u8]) -> HSEPollRes {
-        // Looping through states will fill the output buffer, accumulating the output size
-        let mut output_size = 0;
-        let mut oi = OutputInfo {
-            buf: out_buf,
-            output_size: &mut output_size,
-        };
+        self.poll_into_buffer(out_buf, usize::MAX)
+    }
+
+    /// Poll for output from the encoder like `poll`, but into memory that has not
+    /// been initialized, such as the spare capacity of a `Vec`.
+    ///
+    /// On success, the first `n` bytes of `out_buf` are initialized, where `n` is the
+    /// count returned in `Empty(n)` or `More(n)`.
+    #[inline]
+    pub fn poll_uninit(&mut self, out_buf: &mut [MaybeUninit<u8>]) -> HSEPollRes {
+        self.poll_into_buffer(out_buf, usize::MAX)
+    }
+
+    /// Poll for output from the encoder like `poll`, but into `out_buf[offset..]`, so a
+    /// caller can fill one buffer over several polls by adding each returned count to
+    /// `offset`.
+    ///
+    /// Returns HSER_POLL_ERROR_MISUSE if `offset` is past the end of `out_buf`.
+    #[inline]
+    pub fn poll_at(&mut self, out_buf: &mut [u8], offset: usize) -> HSEPollRes {
+        match out_buf.get_mut(offset..) {
+            Some(out_buf) => self.poll_into_buffer(out_buf, usize::MAX),
+            None => HSEPollRes::ErrorMisuse,
+        }
+    }
+
+    /// Poll all of the currently available output from the encoder into `dst`, through a
+    /// small stack buffer, until the encoder reports `Empty`.
+    /// The total number of bytes written to `dst` is returned on success.
+    ///
+    /// If `dst` runs out of room, the chunk that did not fit is dropped and the
+    /// stream cannot be completed.
+    pub fn poll_to(&mut self, dst: &mut impl Writable) -> Result<usize, HeatshrinkError> {
+        let mut scratch = [0; POLL_TO_CHUNK_SIZE];
+        let mut written = 0;
         loop {
-            let in_state = self.state;
-            self.state = match in_state {
-                HSEState::Done | HSEState::NotFull => return HSEPollRes::Empty(output_size),
-                HSEState::Filled => {
-                    self.do_indexing();
-                    HSEState::Search
+            match self.poll(&mut scratch) {
+                HSEPollRes::Empty(sz) => {
+                    dst.write_all(&scratch[..sz])?;
+                    return Ok(written + sz);
                 }
-                HSEState::Search => self.st_step_search(),
-                HSEState::YieldTagBit => self.st_yield_tag_bit(&mut oi),
-                HSEState::YieldLiteral => self.st_yield_literal(&mut oi),
-                HSEState::YieldBrIndex => self.st_yield_br_index(&mut oi),
-                HSEState::YieldBrLength => self.st_yield_br_length(&mut oi),
-                HSEState::SaveBacklog => self.st_save_backlog(),
-                HSEState::FlushBits => self.st_flush_bit_buffer(&mut oi),
-            };
-
-            if self.state == in_state {
-                if *oi.output_size == oi.buf.len() {
-                    return HSEPollRes::More(output_size);
+                HSEPollRes::More(sz) => {
+                    dst.write_all(&scratch[..sz])?;
+                    written += sz;
+                }
+                HSEPollRes::ErrorMisuse | HSEPollRes::ErrorNull => {
+                    return Err(HeatshrinkError::Misuse)
                 }
+                HSEPollRes::ErrorUnknown => return Err(HeatshrinkError::InvalidState),
+                HSEPollRes::Aborted => return Err(HeatshrinkError::ExpansionLimit),
             }
         }
     }
 
-    /// Notify the encoder that the input stream is finished.
-    /// If the return value is HSER_FINISH_MORE, there is more output to poll, so
-    /// call poll until it returns HSER_FINISH_DONE.
-    pub fn finish(&mut self) -> HSEFinishRes {
-        self.flags |= FLAG_IS_FINISHING;
-        if self.state == HSEState::NotFull {
-            // Mark the input filled to trigger indexing and emission of the remaining data
-            self.state = HSEState::Filled;
-        }
-        if self.state == HSEState::Done {
-            HSEFinishRes::Done
-        } else {
-            HSEFinishRes::More
+    /// Poll for output from the encoder like `poll`, but return after at most `max_states`
+    /// state machine transitions even if `out_buf` has room, to bound the time spent in a
+    /// single call. Searching for a match is the expensive transition, so this caps the
+    /// worst case latency independently of the size of `out_buf`.
+    ///
+    /// Returns `More(n)` if the budget ran out, then poll again to continue. Returns
+    /// HSER_POLL_ERROR_MISUSE if `max_states` is 0.
+    pub fn poll_budget(&mut self, out_buf: &mut [u8], max_states: usize) -> HSEPollRes {
+        if max_states == 0 {
+            return HSEPollRes::ErrorMisuse;
         }
+        self.poll_into_buffer(out_buf, max_states)
     }
 
-    #[inline]
-    fn st_step_search(&mut self) -> HSEState {
-        let window_length = self.input_buffer_size;
-        let lookahead_sz = self.lookahead_size;
-        let msi = self.match_scan_index;
+    /// Poll for output from the encoder like `poll`, but push each byte straight into
+    /// `ring` instead of copying through a slice.
+    ///
+    /// Returns `More(n)` once the ring reports it is full, or `Empty(n)` when the encoder
+    /// needs more input, where `n` is the number of bytes pushed.
+    pub fn poll_ring(&mut self, ring: &mut impl RingSink) -> HSEPollRes {
+        let mut out = RingOutput { ring, pushed: 0 };
+        self.poll_into_buffer(&mut out, usize::MAX)
+    }
 
-        if msi > self.input_size.saturating_sub(lookahead_sz) {
-            return HSEState::SaveBacklog;
-        } else if unlikely(self.is_finishing()) && msi >= self.input_size {
-            return HSEState::FlushBits;
-        }
+    /// Poll for output from the encoder like `poll`, but hand each byte to `sink`, e.g. to
+    /// push it into a device FIFO. The byte passed to `sink` is always taken, and `sink`
+    /// returns `false` once it has no room for another one.
+    ///
+    /// Returns `More(n)` when `sink` returned `false`, then poll again once it has room, or
+    /// `Empty(n)` when the encoder needs more input, where `n` is the number of bytes passed
+    /// to `sink`.
+    pub fn poll_with(&mut self, sink: impl FnMut(u8) -> bool) -> HSEPollRes {
+        let mut out = BackpressureOutput {
+            sink,
+            pushed: 0,
+            open: true,
+        };
+        self.poll_into_buffer(&mut out, usize::MAX)
+    }
 
-        let input_offset = self.get_input_offset();
-        let end = input_offset + msi;
-        let start = end - window_length;
+    ///
+    /// Sink all of `in_buf` and append the output it produces to `out`, alternating
+    /// `sink` and `poll` internally until every byte is taken, without finishing the stream.
+    ///
+    /// `out` is grown once, up front, by the worst case output for `in_buf`, so large
+    /// inputs are encoded with a single call and without per-chunk copies or reallocation.
+    /// Call it once per chunk as input arrives, then `finalize` after the last chunk to
+    /// flush the end of the stream.
+    pub fn compress_chunk(
+        &mut self,
+        mut in_buf: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), HeatshrinkError> {
+        out.reserve(crate::max_compressed_len(
+            in_buf.len(),
+            self.window_sz2,
+            self.lookahead_sz2,
+        ));
+        while !in_buf.is_empty() {
+            match self.sink(in_buf) {
+                HSESinkRes::Ok(sz) => in_buf = &in_buf[sz..],
+                HSESinkRes::ErrorMisuse | HSESinkRes::ErrorNull => {
+                    return Err(HeatshrinkError::Misuse)
+                }
+            }
+            self.poll_into(out)?;
+        }
+        Ok(())
+    }
 
-        let mut max_possible = lookahead_sz;
-        if self.input_size - msi < lookahead_sz {
-            max_possible = self.input_size - msi;
+    ///
+    /// Poll all of the currently available output from the encoder, appending it to `out`
+    /// by writing directly into its spare capacity.
+    pub fn poll_into(&mut self, out: &mut Vec<u8>) -> Result<(), HeatshrinkError> {
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(POLL_INTO_RESERVE);
+            }
+            match self.poll_uninit(out.spare_capacity_mut()) {
+                HSEPollRes::Empty(sz) => {
+                    // SAFETY: poll_uninit initialized the first `sz` bytes of spare capacity
+                    unsafe { out.set_len(out.len() + sz) };
+                    return Ok(());
+                }
+                HSEPollRes::More(sz) => {
+                    // SAFETY: poll_uninit initialized the first `sz` bytes of spare capacity
+                    unsafe { out.set_len(out.len() + sz) };
+                }
+                HSEPollRes::ErrorMisuse | HSEPollRes::ErrorNull => {
+                    return Err(HeatshrinkError::Misuse)
+                }
+                HSEPollRes::ErrorUnknown => return Err(HeatshrinkError::InvalidState),
+                HSEPollRes::Aborted => return Err(HeatshrinkError::ExpansionLimit),
+            }
         }
+    }
 
-        let mut match_length = 0;
-        let match_pos = self.find_longest_match(start, end, max_possible, &mut match_length);
+    ///
+    /// Notifies the encoder that the input stream is finished and appends all of the
+    /// remaining output to `out`, repeating `finish` and `poll` until `finish` is `Done`.
+    pub fn finalize(&mut self, out: &mut Vec<u8>) -> Result<(), HeatshrinkError> {
+        loop {
+            match self.finish() {
+                HSEFinishRes::Done => return Ok(()),
+                HSEFinishRes::More => self.poll_into(out)?,
+                HSEFinishRes::ErrorNull => return Err(HeatshrinkError::Misuse),
+                HSEFinishRes::ErrorUnknown => return Err(HeatshrinkError::InvalidState),
+            }
+        }
+    }
+
+    ///
+    /// Poll output until exactly `mtu` bytes are gathered and return them as one packet,
+    /// or `None` once the encoder needs more input. Output short of a full packet is kept
+    /// for the next call, so every packet is `mtu` bytes no matter how the tokens fall.
+    /// After the last input, use `drain_packets` to finish the stream and get the tail.
+    ///
+    /// Returns the errors of `poll_into` if polling fails, e.g.
+    /// `HeatshrinkError::ExpansionLimit`, after which the stream cannot be completed.
+    ///
+    /// Panics if `mtu` is 0.
+    pub fn poll_packet(&mut self, mtu: usize) -> Result<Option<Vec<u8>>, HeatshrinkError> {
+        assert!(mtu > 0, "mtu must be greater than 0");
+        let mut packet = core::mem::take(&mut self.packet);
+        while packet.len() < mtu {
+            let missing = mtu - packet.len();
+            packet.reserve(missing);
+            let (sz, drained) = match self.poll_uninit(&mut packet.spare_capacity_mut()[..missing])
+            {
+                HSEPollRes::Empty(sz) => (sz, true),
+                HSEPollRes::More(sz) => (sz, false),
+                res => {
+                    self.packet = packet;
+                    return Err(match res {
+                        HSEPollRes::ErrorUnknown => HeatshrinkError::InvalidState,
+                        HSEPollRes::Aborted => HeatshrinkError::ExpansionLimit,
+                        _ => HeatshrinkError::Misuse,
+                    });
+                }
+            };
+            // SAFETY: poll_uninit initialized the first `sz` bytes of spare capacity
+            unsafe { packet.set_len(packet.len() + sz) };
+            if drained && packet.len() < mtu {
+                self.packet = packet;
+                return Ok(None);
+            }
+        }
+        Ok(Some(packet))
+    }
+
+    ///
+    /// Finish the stream and return the rest of its output as packets for `poll_packet`
+    /// with the same `mtu`: all of them `mtu` bytes except the last, which may be shorter.
+    ///
+    /// Returns the errors of `finalize` if finishing or polling fails, e.g.
+    /// `HeatshrinkError::ExpansionLimit`, without the packets gathered before it.
+    ///
+    /// Panics if `mtu` is 0.
+    pub fn drain_packets(&mut self, mtu: usize) -> Result<Vec<Vec<u8>>, HeatshrinkError> {
+        let mut packets = Vec::new();
+        loop {
+            let finished = match self.finish() {
+                HSEFinishRes::Done => true,
+                HSEFinishRes::More => false,
+                HSEFinishRes::ErrorNull => return Err(HeatshrinkError::Misuse),
+                HSEFinishRes::ErrorUnknown => return Err(HeatshrinkError::InvalidState),
+            };
+            while let Some(packet) = self.poll_packet(mtu)? {
+                packets.push(packet);
+            }
+            if finished {
+                break;
+            }
+        }
+        if !self.packet.is_empty() {
+            packets.push(core::mem::take(&mut self.packet));
+        }
+        Ok(packets)
+    }
+
+    #[inline(always)]
+    fn poll_into_buffer<B: OutputBuffer + ?Sized>(
+        &mut self,
+        out_buf: &mut B,
+        max_states: usize,
+    ) -> HSEPollRes {
+        if self.over_expansion_limit() {
+            return HSEPollRes::Aborted;
+        }
+        let res = self.step_states(out_buf, max_states);
+        if let HSEPollRes::Empty(sz) | HSEPollRes::More(sz) = res {
+            self.total_polled += sz as u64;
+            if self.over_expansion_limit() {
+                return HSEPollRes::Aborted;
+            }
+        }
+        res
+    }
+
+    /// Whether more bytes were polled than the limit of `set_expansion_limit`
+    #[inline]
+    fn over_expansion_limit(&self) -> bool {
+        self.expansion_limit
+            .is_some_and(|limit| self.total_polled > limit)
+    }
+
+    /// Run the state machine until `out_buf` is full, it needs more input, or it made
+    /// `max_states` transitions
+    #[inline]
+    fn step_states<B: OutputBuffer + ?Sized>(
+        &mut self,
+        out_buf: &mut B,
+        max_states: usize,
+    ) -> HSEPollRes {
+        // Looping through states will fill the output buffer, accumulating the output size
+        let mut output_size = 0;
+        let mut oi = OutputInfo {
+            buf: out_buf,
+            output_size: &mut output_size,
+        };
+        let mut states = 0;
+        loop {
+            if states == max_states {
+                return HSEPollRes::More(output_size);
+            }
+            states += 1;
+
+            let in_state = self.state;
+            self.state = match in_state {
+                HSEState::Done | HSEState::NotFull => return HSEPollRes::Empty(output_size),
+                HSEState::Error => return HSEPollRes::ErrorUnknown,
+                HSEState::Filled => {
+                    self.do_indexing();
+                    HSEState::Search
+                }
+                HSEState::Search => self.st_step_search(),
+                HSEState::YieldTagBit => self.st_yield_tag_bit(&mut oi),
+                HSEState::YieldLiteral => self.st_yield_literal(&mut oi),
+                HSEState::YieldBrIndex => self.st_yield_br_index(&mut oi),
+                HSEState::YieldBrLength => self.st_yield_br_length(&mut oi),
+                HSEState::SaveBacklog => self.st_save_backlog(),
+                HSEState::FlushBits => self.st_flush_bit_buffer(&mut oi),
+            };
+
+            #[cfg(feature = "trace")]
+            if self.state != in_state {
+                if let Some(logger) = &mut self.state_logger {
+                    logger.on_transition(in_state.name(), self.state.name());
+                }
+            }
+            #[cfg(feature = "test-introspection")]
+            if self.state != in_state {
+                self.state_visits[self.state as usize] += 1;
+            }
+
+            if self.state == in_state && !oi.has_room() {
+                return HSEPollRes::More(output_size);
+            }
+        }
+    }
+
+    /// Notify the encoder that the input stream is finished.
+    /// If the return value is HSER_FINISH_MORE, there is more output to poll, so
+    /// call poll until it returns HSER_FINISH_DONE.
+    pub fn finish(&mut self) -> HSEFinishRes {
+        if self.state == HSEState::Error {
+            return HSEFinishRes::ErrorUnknown;
+        }
+        self.flags |= FLAG_IS_FINISHING;
+        if self.state == HSEState::NotFull {
+            // Mark the input filled to trigger indexing and emission of the remaining data
+            self.state = HSEState::Filled;
+        }
+        if self.state == HSEState::Done {
+            HSEFinishRes::Done
+        } else {
+            HSEFinishRes::More
+        }
+    }
+
+    /// Total number of input bytes taken by `sink` and `stage` since the encoder was created
+    /// or reset, e.g. for a progress bar over a reader. Dictionary bytes loaded by `prime`
+    /// are not counted.
+    pub fn total_sunk(&self) -> u64 {
+        self.total_sunk
+    }
+
+    /// Number of bits of the tokens emitted since the encoder was created or reset, those in
+    /// polled bytes and those held in the partial output byte. Once `finish` returns `Done`,
+    /// this is the exact bit length of the stream, without the zero bits that pad its last
+    /// byte.
+    pub fn total_bits(&self) -> u64 {
+        // `bit_index` marks the next free bit of the current byte, and keeps marking the
+        // padding after `FlushBits` wrote it out
+        let free = if self.bit_index == 0x80 {
+            8
+        } else {
+            self.bit_index.trailing_zeros() as u64 + 1
+        };
+        if self.state == HSEState::Done {
+            self.total_polled * 8 - free % 8
+        } else {
+            self.total_polled * 8 + (8 - free)
+        }
+    }
+
+    /// The bytes the encoder currently holds, for debugging: the window of previous input
+    /// that backreferences can reach, followed by the sunk input that was not encoded yet.
+    pub fn window_snapshot(&self) -> &[u8] {
+        let input_offset = self.get_input_offset();
+        &self.buffer[input_offset - self.backlog_size..input_offset + self.input_size]
+    }
+
+    /// Whether the encoder holds a partially filled output byte that has not been
+    /// polled out yet. Those bits are only emitted by `finish`, so this tells whether
+    /// finishing will produce a trailing byte.
+    #[inline]
+    pub fn has_buffered_bits(&self) -> bool {
+        self.bit_index != 0x80
+    }
+
+    /// Whether a `poll` would emit at least one byte, without running the state machine.
+    ///
+    /// This is true while a byte is held back by a full output buffer, once enough input is
+    /// sunk to fill the window, and after `finish` until everything is flushed. A partial
+    /// byte waiting for more input is reported by `has_buffered_bits` instead.
+    pub fn output_pending(&self) -> bool {
+        match self.state {
+            HSEState::Filled | HSEState::Search => {
+                self.match_scan_index < self.input_size
+                    || (self.is_finishing() && self.has_buffered_bits())
+            }
+            HSEState::YieldTagBit
+            | HSEState::YieldLiteral
+            | HSEState::YieldBrIndex
+            | HSEState::YieldBrLength => true,
+            HSEState::FlushBits => self.has_buffered_bits(),
+            HSEState::NotFull | HSEState::SaveBacklog | HSEState::Done | HSEState::Error => false,
+        }
+    }
+
+    #[inline]
+    fn st_step_search(&mut self) -> HSEState {
+        let lookahead_sz = self.lookahead_size;
+        let msi = self.match_scan_index;
+
+        // Search is only entered with a full input buffer, which always holds more than a
+        // lookahead, or while finishing, where `input_size` can be smaller than a lookahead.
+        // The subtraction saturates so that such a short tail is still searched from the
+        // start instead of wrapping and skipping the scan.
+        debug_assert!(self.is_finishing() || self.input_size >= lookahead_sz);
+        if msi > self.input_size.saturating_sub(lookahead_sz) {
+            return HSEState::SaveBacklog;
+        } else if unlikely(self.is_finishing()) && msi >= self.input_size {
+            return HSEState::FlushBits;
+        }
+
+        if self.literals_only {
+            #[cfg(feature = "literal-runs")]
+            {
+                self.unmatched = self.unmatched.saturating_sub(1);
+            }
+            self.match_scan_index += 1;
+            self.match_length = 0;
+            self.count_ratio(1, 9);
+            return HSEState::YieldTagBit;
+        }
+
+        #[cfg(feature = "literal-runs")]
+        if self.unmatched > 0 {
+            // searched while measuring a run too short for a header
+            self.unmatched -= 1;
+            self.match_scan_index += 1;
+            self.match_length = 0;
+            self.count_ratio(1, 9);
+            return HSEState::YieldTagBit;
+        }
+
+        let (match_pos, match_length) = self.search_at(msi);
 
         if match_pos == MATCH_NOT_FOUND {
+            #[cfg(feature = "literal-runs")]
+            if self.literal_runs {
+                let run = self.literal_run_len();
+                if run >= literal_run_min(self.window_sz2, self.lookahead_sz2) {
+                    // the header is a length 1 backref, which is never worth emitting,
+                    // with the run length in place of the distance
+                    self.literal_run = run;
+                    self.match_pos = run as WindowIndex;
+                    self.match_length = 1;
+                    self.count_ratio(run, self.backref_bits() + 8 * run);
+                    return HSEState::YieldTagBit;
+                }
+                self.unmatched = run - 1;
+            }
             self.match_scan_index += 1;
             self.match_length = 0;
+            self.count_ratio(1, 9);
             HSEState::YieldTagBit
         } else {
             self.match_pos = match_pos;
             self.match_length = match_length;
-            debug_assert!(match_pos <= 1 << self.window_sz2); // matching within window size
+            self.count_ratio(match_length, self.backref_bits());
             HSEState::YieldTagBit
         }
     }
 
+    /// Bits of a backref token: the tag bit, the index, and the count
+    #[inline]
+    fn backref_bits(&self) -> usize {
+        1 + self.window_sz2 as usize + self.lookahead_sz2 as usize
+    }
+
+    /// Count a token of `bits` bits for `bytes` input bytes toward the ratio target, and
+    /// decide whether the next byte is searched. A literal run is counted with its header.
+    #[inline]
+    fn count_ratio(&mut self, bytes: usize, bits: usize) {
+        if let Some(target) = self.ratio_target {
+            self.ratio_bytes += bytes as u64;
+            self.ratio_bits += bits as u64;
+            self.literals_only =
+                (self.ratio_bytes * 8) as f64 >= target as f64 * self.ratio_bits as f64;
+        }
+    }
+
+    /// Search for the longest match of the input at `msi`, returning its distance, or
+    /// `MATCH_NOT_FOUND`, and its length
+    #[inline(always)]
+    fn search_at(&self, msi: usize) -> (WindowIndex, usize) {
+        let window_length = self.input_buffer_size;
+        let lookahead_sz = self.lookahead_size;
+        let input_offset = self.get_input_offset();
+        let end = input_offset + msi;
+        // Never reach into the part of the window that was not written yet, so that
+        // the output does not depend on the decoder's window being zero-initialized
+        let start = (end - window_length).max(input_offset - self.backlog_size);
+
+        let mut max_possible = lookahead_sz;
+        if self.input_size - msi < lookahead_sz {
+            max_possible = self.input_size - msi;
+        }
+
+        let mut match_length = 0;
+        let match_pos = self.find_longest_match(start, end, max_possible, &mut match_length);
+        (match_pos, match_length)
+    }
+
+    /// Count the literals from `match_scan_index`, which has no match, up to the next match,
+    /// the end of the searchable input, or the longest run a header can describe
+    #[cfg(feature = "literal-runs")]
+    fn literal_run_len(&self) -> usize {
+        let max_run = 1 << self.window_sz2;
+        let last = self.input_size.saturating_sub(self.lookahead_size);
+        let mut msi = self.match_scan_index + 1;
+        while msi - self.match_scan_index < max_run
+            && msi <= last
+            && msi < self.input_size
+            && self.search_at(msi).0 == MATCH_NOT_FOUND
+        {
+            msi += 1;
+        }
+        msi - self.match_scan_index
+    }
+
     #[inline]
-    fn st_yield_tag_bit(&mut self, oi: &mut OutputInfo) -> HSEState {
+    fn st_yield_tag_bit<B: OutputBuffer + ?Sized>(&mut self, oi: &mut OutputInfo<B>) -> HSEState {
         if self.can_take_byte(oi) {
             if self.match_length == 0 {
                 self.add_tag_bit(oi, HEATSHRINK_LITERAL_MARKER);
                 HSEState::YieldLiteral
             } else {
+                // matching within window size, with a length that fits the lookahead bits
+                check_invariant!(
+                    (1..=1 << self.window_sz2).contains(&(self.match_pos as usize))
+                        && self.match_length <= self.lookahead_size,
+                    HSEState::Error
+                );
                 self.add_tag_bit(oi, HEATSHRINK_BACKREF_MARKER);
                 self.outgoing_bits = self.match_pos - 1;
                 self.outgoing_bits_count = self.get_window_bits();
@@ -343,9 +1500,23 @@ impl HeatshrinkEncoder {
     }
 
     #[inline]
-    fn st_yield_literal(&mut self, oi: &mut OutputInfo) -> HSEState {
+    fn st_yield_literal<B: OutputBuffer + ?Sized>(&mut self, oi: &mut OutputInfo<B>) -> HSEState {
         if self.can_take_byte(oi) {
-            self.push_literal_byte(oi);
+            // the literal is read from the buffer without a bounds check
+            check_invariant!(
+                (1..=self.buffer.len() - self.get_input_offset()).contains(&self.match_scan_index),
+                HSEState::Error
+            );
+            let c = self.push_literal_byte(oi);
+            self.observe(Token::Literal(c));
+            #[cfg(feature = "literal-runs")]
+            if self.literal_run > 1 {
+                self.literal_run -= 1;
+                self.match_scan_index += 1;
+                return HSEState::YieldLiteral;
+            } else {
+                self.literal_run = 0;
+            }
             HSEState::Search
         } else {
             HSEState::YieldLiteral
@@ -353,12 +1524,12 @@ impl HeatshrinkEncoder {
     }
 
     #[inline]
-    fn st_yield_br_index(&mut self, oi: &mut OutputInfo) -> HSEState {
+    fn st_yield_br_index<B: OutputBuffer + ?Sized>(&mut self, oi: &mut OutputInfo<B>) -> HSEState {
         if self.can_take_byte(oi) {
             if self.push_outgoing_bits(oi) > 0 {
                 HSEState::YieldBrIndex // continue
             } else {
-                self.outgoing_bits = (self.match_length - 1) as u16;
+                self.outgoing_bits = (self.match_length - 1) as WindowIndex;
                 self.outgoing_bits_count = self.get_lookahead_bits();
                 HSEState::YieldBrLength // done
             }
@@ -368,11 +1539,22 @@ impl HeatshrinkEncoder {
     }
 
     #[inline]
-    fn st_yield_br_length(&mut self, oi: &mut OutputInfo) -> HSEState {
+    fn st_yield_br_length<B: OutputBuffer + ?Sized>(&mut self, oi: &mut OutputInfo<B>) -> HSEState {
         if self.can_take_byte(oi) {
             if self.push_outgoing_bits(oi) > 0 {
                 HSEState::YieldBrLength
             } else {
+                #[cfg(feature = "literal-runs")]
+                if self.literal_run > 0 {
+                    // the raw bytes of the run follow its header
+                    self.match_scan_index += 1;
+                    self.match_length = 0;
+                    return HSEState::YieldLiteral;
+                }
+                self.observe(Token::Backref {
+                    distance: self.match_pos,
+                    length: self.match_length,
+                });
                 self.match_scan_index += self.match_length;
                 self.match_length = 0;
                 HSEState::Search
@@ -389,13 +1571,14 @@ impl HeatshrinkEncoder {
     }
 
     #[inline]
-    fn st_flush_bit_buffer(&mut self, oi: &mut OutputInfo) -> HSEState {
+    fn st_flush_bit_buffer<B: OutputBuffer + ?Sized>(
+        &mut self,
+        oi: &mut OutputInfo<B>,
+    ) -> HSEState {
         if self.bit_index == 0x80 {
             HSEState::Done
         } else if self.can_take_byte(oi) {
-            debug_assert!(*oi.output_size < oi.buf.len());
-            unsafe { *oi.buf.get_unchecked_mut(*oi.output_size) = self.current_byte };
-            *oi.output_size += 1;
+            oi.write_byte(self.current_byte);
             HSEState::Done
         } else {
             HSEState::FlushBits
@@ -403,7 +1586,7 @@ impl HeatshrinkEncoder {
     }
 
     #[inline]
-    fn add_tag_bit(&mut self, oi: &mut OutputInfo, tag: u8) {
+    fn add_tag_bit<B: OutputBuffer + ?Sized>(&mut self, oi: &mut OutputInfo<B>, tag: u8) {
         self.push_bits(1, tag, oi);
     }
 
@@ -412,22 +1595,86 @@ impl HeatshrinkEncoder {
         self.input_buffer_size
     }
 
+    /// Link every position up to the end of the input to the previous position holding the
+    /// same byte. Positions that are still indexed from before the last `save_backlog` are
+    /// kept, so only the newly sunk input is indexed.
     #[inline]
     fn do_indexing(&mut self) {
-        let data = &self.buffer;
-        let input_offset = self.get_input_offset();
-        let index = &mut self.search_index;
-        let end = input_offset + self.input_size;
-        let mut last: [i16; 256] = [-1; 256];
-        data.iter()
-            .take(end)
-            .zip(index.iter_mut())
+        let end = self.get_input_offset() + self.input_size;
+        if self.indexed == 0 {
+            self.last_seen = [NO_LINK; 256];
+        }
+        let from = self.indexed;
+        #[cfg(feature = "debug-bounds")]
+        {
+            self.check_bounds("do_indexing buffer", from..end, self.buffer.len());
+            self.check_bounds(
+                "do_indexing search_index",
+                from..end,
+                self.search_index.len(),
+            );
+        }
+        let last = &mut self.last_seen;
+        self.buffer[from..end]
+            .iter()
+            .zip(self.search_index[from..end].iter_mut())
             .enumerate()
             .for_each(|(i, (v, j))| {
                 let v = *v as usize;
                 *j = unsafe { *last.get_unchecked(v) };
-                last[v] = i as i16;
+                last[v] = (from + i) as SearchLink;
             });
+        self.indexed = end;
+
+        #[cfg(test)]
+        self.assert_index_rebuilds();
+    }
+
+    /// Move the index along with the buffer when `save_backlog` slides it `shift` bytes to
+    /// the front, dropping links to positions that fell off the front.
+    #[inline]
+    fn shift_index(&mut self, shift: usize) {
+        if self.indexed == 0 {
+            return;
+        }
+        let kept = self.indexed - shift;
+        self.search_index.copy_within(shift..self.indexed, 0);
+        let link_shift = shift as SearchLink;
+        self.search_index[..kept].iter_mut().for_each(|link| {
+            *link = if *link != NO_LINK && *link >= link_shift {
+                *link - link_shift
+            } else {
+                NO_LINK
+            };
+        });
+        self.last_seen.iter_mut().for_each(|pos| {
+            *pos = if *pos != NO_LINK && *pos >= link_shift {
+                *pos - link_shift
+            } else {
+                NO_LINK
+            };
+        });
+        // the last indexed position is the latest of its byte, and the one position that
+        // may have read as `NO_LINK` above
+        if kept > 0 {
+            self.last_seen[self.buffer[kept - 1] as usize] = (kept - 1) as SearchLink;
+        }
+        self.indexed = kept;
+    }
+
+    /// Check the incrementally maintained index against one rebuilt from scratch.
+    #[cfg(test)]
+    fn assert_index_rebuilds(&self) {
+        let mut last = [NO_LINK; 256];
+        for (i, &v) in self.buffer[..self.indexed].iter().enumerate() {
+            assert_eq!(
+                self.search_index[i], last[v as usize],
+                "index differs at {}",
+                i
+            );
+            last[v as usize] = i as SearchLink;
+        }
+        assert_eq!(self.last_seen, last);
     }
 
     #[inline]
@@ -436,8 +1683,8 @@ impl HeatshrinkEncoder {
     }
 
     #[inline]
-    fn can_take_byte(&self, oi: &OutputInfo) -> bool {
-        *oi.output_size < oi.buf.len()
+    fn can_take_byte<B: OutputBuffer + ?Sized>(&self, oi: &OutputInfo<B>) -> bool {
+        oi.has_room()
     }
 
     #[inline(always)]
@@ -447,65 +1694,33 @@ impl HeatshrinkEncoder {
         end: usize,
         maxlen: usize,
         match_length: &mut usize,
-    ) -> u16 {
-        let buf = &self.buffer;
-
-        let mut match_maxlen = 0;
-        let mut match_index = MATCH_NOT_FOUND;
-
-        let needlepoint = &buf[end..];
-        let hsi = &self.search_index;
-        let mut pos = hsi[end];
-        let break_even_point =
-            ((1 + self.get_window_bits() + self.get_lookahead_bits()) / 8) as usize;
-        let start = start as i16;
-        unsafe {
-            // fuzz with debug assertions
-            while pos >= start {
-                let posidx = pos as usize;
-                debug_assert!(posidx < buf.len());
-                let pospoint = buf.get_unchecked(posidx..);
-
-                debug_assert!(pospoint.len() >= match_maxlen);
-                debug_assert!(needlepoint.len() >= match_maxlen);
-                if pospoint.get_unchecked(match_maxlen) != needlepoint.get_unchecked(match_maxlen) {
-                    pos = *hsi.get_unchecked(posidx);
-                    continue;
-                }
-
-                let mut len = 1;
-                while len < maxlen {
-                    debug_assert!(pospoint.len() >= len);
-                    debug_assert!(needlepoint.len() >= len);
-                    if pospoint.get_unchecked(len) != needlepoint.get_unchecked(len) {
-                        break;
-                    }
-                    len += 1;
-                }
-
-                if len > match_maxlen {
-                    match_maxlen = len;
-                    match_index = pos as u16;
-                    if len == maxlen {
-                        break;
-                    }
-                }
-
-                debug_assert!(posidx < hsi.len());
-                pos = *hsi.get_unchecked(posidx);
-            }
+    ) -> WindowIndex {
+        #[cfg(feature = "debug-bounds")]
+        {
+            self.check_bounds("find_longest_match window", start..end, self.buffer.len());
+            self.check_bounds(
+                "find_longest_match needle",
+                end..end + maxlen,
+                self.buffer.len(),
+            );
+            self.check_bounds(
+                "find_longest_match search_index",
+                end..end + 1,
+                self.search_index.len(),
+            );
         }
-
-        if match_maxlen > break_even_point {
+        let (match_index, match_maxlen) =
+            longest_match(&self.buffer, &self.search_index, start, end, maxlen);
+        if match_maxlen > break_even_point(self.get_window_bits(), self.get_lookahead_bits()) {
             *match_length = match_maxlen;
-            end as u16 - match_index
+            (end - match_index) as WindowIndex
         } else {
             MATCH_NOT_FOUND
         }
     }
 
     #[inline]
-    fn push_outgoing_bits(&mut self, oi: &mut OutputInfo) -> u8 {
+    fn push_outgoing_bits<B: OutputBuffer + ?Sized>(&mut self, oi: &mut OutputInfo<B>) -> u8 {
         let count: u8;
         let bits: u8;
         if self.outgoing_bits_count > 8 {
@@ -525,21 +1740,18 @@ impl HeatshrinkEncoder {
 
     // Function to handle the writing logic when bit_index reaches zero
     #[inline]
-    fn write_current_byte(&mut self, oi: &mut OutputInfo) {
+    fn write_current_byte<B: OutputBuffer + ?Sized>(&mut self, oi: &mut OutputInfo<B>) {
         self.bit_index = 0x80;
-        debug_assert!(*oi.output_size < oi.buf.len());
-        unsafe { *oi.buf.get_unchecked_mut(*oi.output_size) = self.current_byte };
-        *oi.output_size += 1;
+        oi.write_byte(self.current_byte);
         self.current_byte = 0x00;
     }
 
     #[inline]
-    fn push_bits(&mut self, count: u8, bits: u8, oi: &mut OutputInfo) {
-        debug_assert!(count >= 1 && count <= 8);
+    fn push_bits<B: OutputBuffer + ?Sized>(&mut self, count: u8, bits: u8, oi: &mut OutputInfo<B>) {
+        debug_assert!((1..=8).contains(&count));
         // Directly emit the whole byte if possible
         if count == 8 && self.bit_index == 0x80 {
-            oi.buf[*oi.output_size] = bits;
-            *oi.output_size += 1;
+            oi.write_byte(bits);
         } else {
             let bits_to_write = bits;
             let mut bits_left = count;
@@ -580,12 +1792,46 @@ impl HeatshrinkEncoder {
     }
 
     #[inline]
-    fn push_literal_byte(&mut self, oi: &mut OutputInfo) {
+    fn push_literal_byte<B: OutputBuffer + ?Sized>(&mut self, oi: &mut OutputInfo<B>) -> u8 {
         let processed_offset = self.match_scan_index - 1;
         let input_offset = self.get_input_offset() + processed_offset;
+        #[cfg(feature = "debug-bounds")]
+        self.check_bounds(
+            "push_literal_byte buffer",
+            input_offset..input_offset + 1,
+            self.buffer.len(),
+        );
         debug_assert!(input_offset < self.buffer.len());
         let c = unsafe { *self.buffer.get_unchecked(input_offset) };
         self.push_bits(8, c, oi);
+        c
+    }
+
+    /// Report `range` to the bounds logger and panic unless it is a valid range of a buffer
+    /// of `len` bytes
+    #[cfg(feature = "debug-bounds")]
+    #[inline(always)]
+    fn check_bounds(&self, access: &'static str, range: core::ops::Range<usize>, len: usize) {
+        if range.start > range.end || range.end > len {
+            self.out_of_bounds(access, range, len);
+        }
+    }
+
+    #[cfg(feature = "debug-bounds")]
+    #[cold]
+    #[inline(never)]
+    fn out_of_bounds(&self, access: &'static str, range: core::ops::Range<usize>, len: usize) -> ! {
+        if let Some(logger) = &self.bounds_logger {
+            logger.out_of_bounds(access, range.clone(), len);
+        }
+        panic!("{access}: {range:?} is out of bounds for length {len}");
+    }
+
+    #[inline(always)]
+    fn observe(&mut self, token: Token) {
+        if let Some(observer) = &mut self.token_observer {
+            observer(token);
+        }
     }
 
     #[inline]
@@ -593,14 +1839,16 @@ impl HeatshrinkEncoder {
         let rem = self.input_buffer_size - self.match_scan_index;
         let shift_sz = self.input_buffer_size + rem;
 
-        unsafe {
-            ptr::copy(
-                self.buffer.as_ptr().add(self.input_buffer_size - rem),
-                self.buffer.as_mut_ptr(),
-                shift_sz,
-            );
-        }
+        // Slide the unprocessed input and the window behind it to the front of the buffer,
+        // the source range always ends at the end of the buffer
+        let src = self.input_buffer_size - rem;
+        self.buffer.copy_within(src..src + shift_sz, 0);
+        self.shift_index(src);
 
+        self.backlog_size = min(
+            self.backlog_size + self.match_scan_index,
+            self.input_buffer_size,
+        );
         self.match_scan_index = 0;
         self.input_size -= self.input_buffer_size - rem;
     }
@@ -625,27 +1873,134 @@ impl HeatshrinkEncoder {
 }
 
 const FLAG_IS_FINISHING: u8 = 0x01;
+const FLAG_HAS_INPUT: u8 = 0x02;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn sanity() {
-        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
-        let input_data: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
-        let sink_res = encoder.sink(&input_data);
-        println!("Sink result: {:?}", sink_res);
+/// An encoder that sinks a borrowed input by itself, from `HeatshrinkEncoder::from_input`
+pub struct SliceEncoder<'a> {
+    encoder: HeatshrinkEncoder,
+    /// input that was not sunk yet
+    input: &'a [u8],
+}
 
-        let mut output_buffer: Vec<u8> = vec![0; 32];
-        let mut written = 0;
-        let poll_res = encoder.poll(&mut output_buffer);
-        println!("Poll result: {:?}", poll_res);
-        match poll_res {
-            HSEPollRes::Empty(sz) | HSEPollRes::More(sz) => {
-                written += sz;
+impl SliceEncoder<'_> {
+    ///
+    /// Poll like `HeatshrinkEncoder::poll`, sinking more of the input whenever the input
+    /// buffer drains. `Empty` means that all of the input was encoded as far as it can be
+    /// before `finish`.
+    pub fn poll(&mut self, out_buf: &mut [u8]) -> HSEPollRes {
+        let mut polled = 0;
+        loop {
+            if !self.input.is_empty() && self.encoder.state == HSEState::NotFull {
+                if let HSESinkRes::Ok(sz) = self.encoder.sink(self.input) {
+                    self.input = &self.input[sz..];
+                }
+            }
+            match self.encoder.poll(&mut out_buf[polled..]) {
+                HSEPollRes::Empty(sz) => {
+                    polled += sz;
+                    if self.input.is_empty() {
+                        return HSEPollRes::Empty(polled);
+                    } else if polled == out_buf.len() {
+                        return HSEPollRes::More(polled);
+                    }
+                }
+                HSEPollRes::More(sz) => return HSEPollRes::More(polled + sz),
+                e => return e,
             }
-            _ => {}
+        }
+    }
+
+    ///
+    /// Finish like `HeatshrinkEncoder::finish`, once all of the input was sunk. Until
+    /// then, this returns `More` so that the caller keeps polling.
+    pub fn finish(&mut self) -> HSEFinishRes {
+        if self.input.is_empty() {
+            self.encoder.finish()
+        } else {
+            HSEFinishRes::More
+        }
+    }
+
+    /// Bytes of the input that were not sunk yet
+    pub fn remaining_input(&self) -> usize {
+        self.input.len()
+    }
+}
+
+/// An encoder for an endless stream, e.g. on a persistent connection where message
+/// boundaries should not reset the compression context. It is only finished by `close`, so
+/// its window persists across `feed` calls and later messages can backreference earlier
+/// ones. `StreamDecoder` decodes its output as it arrives.
+pub struct StreamEncoder {
+    encoder: HeatshrinkEncoder,
+}
+
+impl StreamEncoder {
+    /// Returns `None` if the sizes are invalid, like `HeatshrinkEncoder::new`.
+    pub fn new(window_sz2: u8, lookahead_sz2: u8) -> Option<Self> {
+        Some(Self {
+            encoder: HeatshrinkEncoder::new(window_sz2, lookahead_sz2)?,
+        })
+    }
+
+    /// Sink all of `input` and return the output that is ready. The encoder only searches
+    /// once a window of input is buffered, and holds a partial output byte until later
+    /// tokens complete it, so the output lags the input until `close`. Nothing is lost
+    /// between calls.
+    ///
+    /// Panics if the encoder fails, which only a failed runtime check does.
+    pub fn feed(&mut self, mut input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        while !input.is_empty() {
+            if let HSESinkRes::Ok(sz) = self.encoder.sink(input) {
+                input = &input[sz..];
+            }
+            self.encoder
+                .poll_into(&mut out)
+                .expect("Encoder invariant failed");
+        }
+        out
+    }
+
+    /// Finish the stream and return the rest of the output, including the buffered input
+    /// and the partial last byte.
+    ///
+    /// Panics like `feed`.
+    pub fn close(mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encoder
+            .finalize(&mut out)
+            .expect("Encoder invariant failed");
+        out
+    }
+
+    /// Total number of input bytes taken across all `feed` calls
+    pub fn total_sunk(&self) -> u64 {
+        self.encoder.total_sunk()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_all, encode_all};
+
+    #[test]
+    fn sanity() {
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let input_data: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let sink_res = encoder.sink(&input_data);
+        println!("Sink result: {:?}", sink_res);
+
+        let mut output_buffer: Vec<u8> = vec![0; 32];
+        let mut written = 0;
+        let poll_res = encoder.poll(&mut output_buffer);
+        println!("Poll result: {:?}", poll_res);
+        match poll_res {
+            HSEPollRes::Empty(sz) | HSEPollRes::More(sz) => {
+                written += sz;
+            }
+            _ => {}
         }
 
         let mut finish_res = encoder.finish();
@@ -670,4 +2025,1100 @@ mod tests {
             output_buffer[..written].to_vec()
         );
     }
+
+    #[test]
+    fn poll_to_matches_poll_loop() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let expected = encode_all(input, 8, 4, 256);
+
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut output = vec![0; expected.len()];
+        let mut dst = output.as_mut_slice();
+        let mut written = 0;
+        for chunk in input.chunks(256) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                match encoder.sink(chunk) {
+                    HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                    e => panic!("Failed to sink data: {:?}", e),
+                }
+                written += encoder.poll_to(&mut dst).expect("Failed to poll");
+            }
+        }
+        while encoder.finish() == HSEFinishRes::More {
+            written += encoder.poll_to(&mut dst).expect("Failed to poll");
+        }
+
+        assert!(dst.is_empty());
+        assert_eq!(written, expected.len());
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn poll_to_reports_full_destination() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut output = [0; 16];
+        let mut dst = &mut output[..];
+        assert_eq!(encoder.sink(input), HSESinkRes::Ok(256));
+        assert_eq!(
+            encoder.poll_to(&mut dst),
+            Err(HeatshrinkError::OutputTooSmall)
+        );
+    }
+
+    #[test]
+    fn window_snapshot_ends_with_latest_input() {
+        let input = &include_bytes!("heatshrink_encoder.rs")[..300];
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        encoder
+            .compress_chunk(&input[..10], &mut compressed)
+            .expect("Failed to compress");
+        assert_eq!(encoder.window_snapshot(), &input[..10]);
+
+        encoder
+            .compress_chunk(&input[10..], &mut compressed)
+            .expect("Failed to compress");
+        let window = encoder.window_snapshot();
+        assert!(window.len() > 256);
+        assert_eq!(window, &input[input.len() - window.len()..]);
+    }
+
+    #[test]
+    fn stage_then_begin_search_matches_sink() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        for chunk in input.chunks(100) {
+            let mut chunk = chunk;
+            loop {
+                match encoder.stage(chunk) {
+                    HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                    e => panic!("Failed to stage data: {:?}", e),
+                }
+                if chunk.is_empty() {
+                    break;
+                }
+                // Staging never searches, so a full buffer waits for begin_search
+                assert_eq!(encoder.stage(chunk), HSESinkRes::Ok(0));
+                assert_eq!(encoder.poll_to(&mut compressed), Ok(0));
+                encoder.begin_search();
+                encoder.poll_to(&mut compressed).expect("Failed to poll");
+            }
+        }
+        encoder
+            .finalize(&mut compressed)
+            .expect("Failed to finalize");
+        assert_eq!(compressed, encode_all(input, 8, 4, 100));
+
+        // Searching early emits a different stream that still decodes
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        for chunk in input.chunks(100) {
+            assert_eq!(encoder.stage(chunk), HSESinkRes::Ok(chunk.len()));
+            encoder.begin_search();
+            encoder.poll_to(&mut compressed).expect("Failed to poll");
+        }
+        encoder
+            .finalize(&mut compressed)
+            .expect("Failed to finalize");
+        let decompressed = decode_all(&compressed, 512, 8, 4, 512).expect("Failed to decode");
+        assert_eq!(&decompressed[..], &input[..]);
+    }
+
+    #[test]
+    fn has_buffered_bits_tracks_partial_byte() {
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut output = [0; 512];
+        assert!(!encoder.has_buffered_bits());
+
+        // 256 distinct bytes are all literals, and polling a full window emits
+        // 241 literals of 9 bits each, 2169 bits, leaving 1 bit in the current byte
+        let input: Vec<u8> = (0..=255).collect();
+        assert_eq!(encoder.sink(&input), HSESinkRes::Ok(256));
+        assert_eq!(encoder.poll(&mut output), HSEPollRes::Empty(271));
+        assert!(encoder.has_buffered_bits());
+
+        while encoder.finish() == HSEFinishRes::More {
+            encoder.poll(&mut output);
+        }
+        assert!(!encoder.has_buffered_bits());
+    }
+
+    #[test]
+    fn resize_reuses_encoder() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        assert_eq!(encoder.resize(4, 4), Err(HeatshrinkError::InvalidParams));
+
+        encoder.resize(12, 6).expect("Failed to resize encoder");
+        let mut compressed = vec![];
+        for chunk in input.chunks(1000) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                match encoder.sink(chunk) {
+                    HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                    e => panic!("Failed to sink data: {:?}", e),
+                }
+                encoder.poll_to(&mut compressed).expect("Failed to poll");
+            }
+        }
+        while encoder.finish() == HSEFinishRes::More {
+            encoder.poll_to(&mut compressed).expect("Failed to poll");
+        }
+
+        assert_eq!(compressed, encode_all(input, 12, 6, 1000));
+        let decompressed = decode_all(&compressed, 1000, 12, 6, 1000).expect("Failed to decode");
+        assert_eq!(&decompressed[..], &input[..]);
+    }
+
+    #[test]
+    fn from_input_only_polls() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let expected = encode_all(input, 8, 4, 512);
+        let drain = |encoder: &mut SliceEncoder, out: &mut [u8], compressed: &mut Vec<u8>| loop {
+            match encoder.poll(out) {
+                HSEPollRes::Empty(sz) => {
+                    compressed.extend_from_slice(&out[..sz]);
+                    break;
+                }
+                HSEPollRes::More(sz) => compressed.extend_from_slice(&out[..sz]),
+                e => panic!("Failed to poll: {:?}", e),
+            }
+        };
+
+        for out_len in [1, 7, 1024] {
+            let mut encoder =
+                HeatshrinkEncoder::from_input(input, 8, 4).expect("Failed to create encoder");
+            let mut compressed = vec![];
+            let mut out = vec![0; out_len];
+            drain(&mut encoder, &mut out, &mut compressed);
+            assert_eq!(encoder.remaining_input(), 0);
+            while encoder.finish() == HSEFinishRes::More {
+                drain(&mut encoder, &mut out, &mut compressed);
+            }
+            assert_eq!(compressed, expected);
+        }
+
+        // Finishing before polling still encodes all of the input
+        let mut encoder =
+            HeatshrinkEncoder::from_input(input, 8, 4).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        while encoder.finish() == HSEFinishRes::More {
+            drain(&mut encoder, &mut [0; 64], &mut compressed);
+        }
+        assert_eq!(compressed, expected);
+        assert!(HeatshrinkEncoder::from_input(input, 4, 4).is_none());
+    }
+
+    #[test]
+    fn with_default_roundtrip() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::with_default();
+        let mut compressed = vec![];
+        encoder
+            .compress_chunk(input, &mut compressed)
+            .expect("Failed to compress");
+        encoder
+            .finalize(&mut compressed)
+            .expect("Failed to finalize");
+
+        let (window_sz2, lookahead_sz2) = Config::default().into();
+        assert_eq!((window_sz2, lookahead_sz2), (9, 7));
+        let decompressed =
+            decode_all(&compressed, 512, window_sz2, lookahead_sz2, 512).expect("Failed to decode");
+        assert_eq!(&decompressed[..], &input[..]);
+    }
+
+    #[test]
+    fn compress_chunk_takes_whole_input() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        encoder
+            .compress_chunk(input, &mut compressed)
+            .expect("Failed to sink input");
+        assert!(compressed.capacity() >= crate::max_compressed_len(input.len(), 8, 4));
+        encoder.finalize(&mut compressed).expect("Failed to finish");
+
+        assert_eq!(compressed, encode_all(input, 8, 4, 512));
+        assert_eq!(
+            encoder.compress_chunk(b"more", &mut compressed),
+            Err(HeatshrinkError::Misuse)
+        );
+    }
+
+    #[test]
+    fn compress_chunks_then_finalize() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let (a, rest) = input.split_at(100);
+        let (b, c) = rest.split_at(5000);
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        for chunk in [a, b, c] {
+            encoder
+                .compress_chunk(chunk, &mut compressed)
+                .expect("Failed to compress chunk");
+        }
+        encoder
+            .finalize(&mut compressed)
+            .expect("Failed to finalize");
+
+        assert_eq!(compressed, encode_all(input, 8, 4, 512));
+    }
+
+    #[test]
+    fn total_sunk_counts_input() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        encoder.prime(b"dictionary bytes are not input");
+        let mut compressed = vec![];
+        let (head, tail) = input.split_at(5000);
+        encoder
+            .compress_chunk(head, &mut compressed)
+            .expect("Failed to compress chunk");
+        assert_eq!(encoder.total_sunk(), head.len() as u64);
+
+        // The count is part of the saved state
+        let mut encoder =
+            HeatshrinkEncoder::load_state(&encoder.save_state()).expect("Failed to load state");
+        encoder
+            .compress_chunk(tail, &mut compressed)
+            .expect("Failed to compress chunk");
+        encoder
+            .finalize(&mut compressed)
+            .expect("Failed to finalize");
+        assert_eq!(encoder.total_sunk(), input.len() as u64);
+
+        encoder.reset();
+        assert_eq!(encoder.total_sunk(), 0);
+    }
+
+    #[test]
+    fn expansion_limit_aborts_on_random_data() {
+        let random = include_bytes!("../random-data.bin");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        encoder.set_expansion_limit(random.len());
+        let mut compressed = vec![];
+        let res = encoder
+            .compress_chunk(random, &mut compressed)
+            .and_then(|_| encoder.finalize(&mut compressed));
+        assert_eq!(res, Err(HeatshrinkError::ExpansionLimit));
+        assert!(compressed.len() <= random.len());
+        assert_eq!(encoder.poll(&mut [0; 64]), HSEPollRes::Aborted);
+
+        // The limit survives reset, and compressible input stays well within it
+        encoder.reset();
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut compressed = vec![];
+        encoder
+            .compress_chunk(input, &mut compressed)
+            .expect("Failed to compress chunk");
+        encoder
+            .finalize(&mut compressed)
+            .expect("Failed to finalize");
+        assert_eq!(compressed, encode_all(input, 8, 4, 512));
+    }
+
+    #[test]
+    fn ratio_target_holds_ratio_and_roundtrips() {
+        use std::sync::{Arc, Mutex};
+
+        // A prefix far above the target, then compressible data that must still be searched
+        let mut input = vec![b'a'; 1024];
+        input.extend(include_bytes!("heatshrink_encoder.rs").repeat(4));
+        let baseline = encode_all(&input, 8, 4, 512);
+
+        let tokens = Arc::new(Mutex::new(vec![]));
+        let observed = tokens.clone();
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        encoder.set_ratio_target(1.5);
+        encoder.set_token_observer(move |token| observed.lock().unwrap().push(token));
+        let mut compressed = vec![];
+        encoder
+            .compress_chunk(&input, &mut compressed)
+            .expect("Failed to compress chunk");
+        encoder
+            .finalize(&mut compressed)
+            .expect("Failed to finalize");
+        assert_eq!(decode_all(&compressed, 512, 8, 4, 512).unwrap(), input);
+
+        // Literals while the ratio is met, and matches again whenever it drops below it, so
+        // the whole stream ends up near the target instead of the full search's ratio
+        let tokens = core::mem::take(&mut *tokens.lock().unwrap());
+        let tail = &tokens[tokens.len() * 3 / 4..];
+        assert!(tail
+            .iter()
+            .any(|token| matches!(token, Token::Backref { .. })));
+        assert!(tail.iter().any(|token| matches!(token, Token::Literal(_))));
+        let ratio = input.len() as f32 / compressed.len() as f32;
+        assert!((1.45..1.55).contains(&ratio), "ratio {ratio}");
+        assert!(compressed.len() > baseline.len());
+
+        // The target survives reset, and clearing it restores the full search
+        encoder.reset();
+        let mut again = vec![];
+        encoder
+            .compress_chunk(&input, &mut again)
+            .expect("Failed to compress chunk");
+        encoder.finalize(&mut again).expect("Failed to finalize");
+        assert_eq!(again, compressed);
+
+        encoder.reset();
+        encoder.clear_ratio_target();
+        let mut full = vec![];
+        encoder
+            .compress_chunk(&input, &mut full)
+            .expect("Failed to compress chunk");
+        encoder.finalize(&mut full).expect("Failed to finalize");
+        assert_eq!(full, baseline);
+    }
+
+    #[cfg(feature = "runtime-checks")]
+    #[test]
+    fn runtime_checks_catch_match_past_window() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        encoder
+            .compress_chunk(&input[..1000], &mut compressed)
+            .expect("Failed to compress chunk");
+
+        // A match one byte past the window, whose distance does not fit the index bits
+        encoder.state = HSEState::YieldTagBit;
+        encoder.match_pos = (1 << 8) + 1;
+        encoder.match_length = 4;
+        assert_eq!(encoder.poll(&mut [0; 64]), HSEPollRes::ErrorUnknown);
+        assert_eq!(encoder.finish(), HSEFinishRes::ErrorUnknown);
+        assert_eq!(
+            encoder.finalize(&mut compressed),
+            Err(HeatshrinkError::InvalidState)
+        );
+
+        encoder.reset();
+        let mut compressed = vec![];
+        encoder
+            .compress_chunk(input, &mut compressed)
+            .expect("Failed to compress chunk");
+        encoder
+            .finalize(&mut compressed)
+            .expect("Failed to finalize");
+        assert_eq!(compressed, encode_all(input, 8, 4, 512));
+    }
+
+    #[test]
+    fn packets_are_exactly_mtu() {
+        const MTU: usize = 23;
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut packets = vec![];
+        for chunk in input.chunks(100) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                match encoder.sink(chunk) {
+                    HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                    res => panic!("Failed to sink input: {:?}", res),
+                }
+                while let Some(packet) = encoder.poll_packet(MTU).expect("Failed to poll") {
+                    packets.push(packet);
+                }
+            }
+        }
+        packets.extend(encoder.drain_packets(MTU).expect("Failed to drain"));
+
+        let (last, full) = packets.split_last().expect("No packets");
+        assert!(full.iter().all(|packet| packet.len() == MTU));
+        assert!(!last.is_empty() && last.len() <= MTU);
+
+        let compressed = packets.concat();
+        assert_eq!(compressed, encode_all(input, 8, 4, 512));
+        let decompressed = crate::decode_all(&compressed, 64, 8, 4, 64).expect("Failed to decode");
+        assert_eq!(decompressed, input);
+    }
+
+    #[test]
+    fn drain_packets_stops_at_expansion_limit() {
+        let random = include_bytes!("../random-data.bin");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        encoder.set_expansion_limit(100);
+        let mut remaining = &random[..];
+        let mut result = Ok(None);
+        while !remaining.is_empty() && result.is_ok() {
+            match encoder.sink(remaining) {
+                HSESinkRes::Ok(sz) => remaining = &remaining[sz..],
+                res => panic!("Failed to sink input: {:?}", res),
+            }
+            result = encoder.poll_packet(32);
+            while let Ok(Some(_)) = result {
+                result = encoder.poll_packet(32);
+            }
+        }
+        assert_eq!(result, Err(HeatshrinkError::ExpansionLimit));
+        assert_eq!(
+            encoder.drain_packets(32),
+            Err(HeatshrinkError::ExpansionLimit)
+        );
+
+        // A stream that hits the limit while finishing fails the same way
+        encoder.reset();
+        assert_eq!(encoder.sink(&random[..200]), HSESinkRes::Ok(200));
+        assert_eq!(
+            encoder.drain_packets(32),
+            Err(HeatshrinkError::ExpansionLimit)
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn sink_bufread_small_capacity() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut reader = std::io::BufReader::with_capacity(7, &input[..]);
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        let mut sunk = 0;
+        loop {
+            let sz = encoder
+                .sink_bufread(&mut reader)
+                .expect("Failed to sink from reader");
+            if sz == 0 {
+                break;
+            }
+            sunk += sz;
+            encoder.poll_into(&mut compressed).expect("Failed to poll");
+        }
+        encoder
+            .finalize(&mut compressed)
+            .expect("Failed to finalize");
+
+        assert_eq!(sunk, input.len());
+        assert_eq!(compressed, encode_all(input, 8, 4, 512));
+    }
+
+    #[test]
+    fn sink_less_than_lookahead_then_poll() {
+        let mut encoder = HeatshrinkEncoder::new(8, 6).expect("Failed to create encoder");
+        let input = b"abcabcabc";
+        assert!(input.len() < 1 << 6);
+        assert_eq!(encoder.sink(input), HSESinkRes::Ok(input.len()));
+
+        // Not enough input to search yet, so polling emits nothing and leaves the encoder
+        // ready for more input
+        let mut compressed = vec![];
+        assert_eq!(encoder.poll_to(&mut compressed), Ok(0));
+        assert!(!encoder.output_pending());
+        assert_eq!(encoder.sink(input), HSESinkRes::Ok(input.len()));
+
+        encoder.finalize(&mut compressed).expect("Failed to finish");
+        let decompressed = decode_all(&compressed, 64, 8, 6, 64).expect("Failed to decode");
+        assert_eq!(decompressed, b"abcabcabcabcabcabc");
+    }
+
+    /// A UART style transmit ring, drained by the test instead of DMA
+    struct Ring {
+        bytes: [u8; 7],
+        head: usize,
+        len: usize,
+    }
+
+    impl Ring {
+        fn drain(&mut self, out: &mut Vec<u8>) {
+            while self.len > 0 {
+                out.push(self.bytes[self.head]);
+                self.head = (self.head + 1) % self.bytes.len();
+                self.len -= 1;
+            }
+        }
+    }
+
+    impl RingSink for Ring {
+        fn push(&mut self, byte: u8) -> bool {
+            if self.is_full() {
+                return false;
+            }
+            self.bytes[(self.head + self.len) % self.bytes.len()] = byte;
+            self.len += 1;
+            true
+        }
+
+        fn is_full(&self) -> bool {
+            self.len == self.bytes.len()
+        }
+    }
+
+    #[test]
+    fn poll_ring_fills_and_drains() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut ring = Ring {
+            bytes: [0; 7],
+            head: 0,
+            len: 0,
+        };
+        let mut compressed = vec![];
+        let mut saw_full = false;
+
+        let mut poll =
+            |encoder: &mut HeatshrinkEncoder, ring: &mut Ring, compressed: &mut Vec<u8>| loop {
+                match encoder.poll_ring(ring) {
+                    HSEPollRes::Empty(_) => {
+                        ring.drain(compressed);
+                        break;
+                    }
+                    HSEPollRes::More(sz) => {
+                        assert!(ring.is_full() && sz <= 7);
+                        saw_full = true;
+                        ring.drain(compressed);
+                    }
+                    e => panic!("Failed to poll: {:?}", e),
+                }
+            };
+        for chunk in input.chunks(100) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                match encoder.sink(chunk) {
+                    HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                    e => panic!("Failed to sink data: {:?}", e),
+                }
+                poll(&mut encoder, &mut ring, &mut compressed);
+            }
+        }
+        while encoder.finish() == HSEFinishRes::More {
+            poll(&mut encoder, &mut ring, &mut compressed);
+        }
+
+        assert!(saw_full);
+        assert_eq!(compressed, encode_all(input, 8, 4, 100));
+    }
+
+    #[test]
+    fn poll_with_backpressure() {
+        const FIFO_DEPTH: usize = 5;
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        let mut stalls = 0;
+
+        // The simulated FIFO fills after FIFO_DEPTH bytes and is drained between polls
+        let mut poll = |encoder: &mut HeatshrinkEncoder, compressed: &mut Vec<u8>| loop {
+            let mut fifo = 0;
+            let res = encoder.poll_with(|byte| {
+                compressed.push(byte);
+                fifo += 1;
+                fifo < FIFO_DEPTH
+            });
+            match res {
+                HSEPollRes::Empty(sz) => {
+                    assert_eq!(sz, fifo);
+                    break;
+                }
+                HSEPollRes::More(sz) => {
+                    assert_eq!(sz, FIFO_DEPTH);
+                    stalls += 1;
+                }
+                e => panic!("Failed to poll: {:?}", e),
+            }
+        };
+        for chunk in input.chunks(100) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                match encoder.sink(chunk) {
+                    HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                    e => panic!("Failed to sink data: {:?}", e),
+                }
+                poll(&mut encoder, &mut compressed);
+            }
+        }
+        while encoder.finish() == HSEFinishRes::More {
+            poll(&mut encoder, &mut compressed);
+        }
+
+        assert!(stalls > 0);
+        assert_eq!(compressed, encode_all(input, 8, 4, 100));
+    }
+
+    #[test]
+    fn poll_at_matches_sub_slices() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut by_offset = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut by_slice = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut at = vec![0; input.len() * 2];
+        let mut sliced = vec![0; input.len() * 2];
+        let mut offset = 0;
+
+        // Both end each poll at `offset + 7`, so polls stop on a full buffer too
+        let mut poll = |by_offset: &mut HeatshrinkEncoder, by_slice: &mut HeatshrinkEncoder| loop {
+            let limit = offset + 7;
+            let res = by_offset.poll_at(&mut at[..limit], offset);
+            assert_eq!(res, by_slice.poll(&mut sliced[offset..limit]));
+            match res {
+                HSEPollRes::More(sz) => offset += sz,
+                HSEPollRes::Empty(sz) => {
+                    offset += sz;
+                    break;
+                }
+                e => panic!("Failed to poll: {:?}", e),
+            }
+        };
+        let mut remaining = &input[..];
+        while !remaining.is_empty() {
+            let sz = match by_offset.sink(remaining) {
+                HSESinkRes::Ok(sz) => sz,
+                e => panic!("Failed to sink data: {:?}", e),
+            };
+            assert_eq!(by_slice.sink(remaining), HSESinkRes::Ok(sz));
+            remaining = &remaining[sz..];
+            poll(&mut by_offset, &mut by_slice);
+        }
+        while by_offset.finish() == HSEFinishRes::More {
+            assert_eq!(by_slice.finish(), HSEFinishRes::More);
+            poll(&mut by_offset, &mut by_slice);
+        }
+
+        at.truncate(offset);
+        sliced.truncate(offset);
+        assert_eq!(at, sliced);
+        assert_eq!(at, encode_all(input, 8, 4, 100));
+        assert_eq!(by_offset.poll_at(&mut [0; 4], 5), HSEPollRes::ErrorMisuse);
+    }
+
+    #[test]
+    fn poll_budget_matches_poll() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        assert_eq!(
+            encoder.poll_budget(&mut [0; 64], 0),
+            HSEPollRes::ErrorMisuse
+        );
+
+        let mut compressed = vec![];
+        let mut output = [0; 64];
+        let mut calls = 0;
+        let mut poll = |encoder: &mut HeatshrinkEncoder, compressed: &mut Vec<u8>| loop {
+            calls += 1;
+            match encoder.poll_budget(&mut output, 3) {
+                HSEPollRes::Empty(sz) => {
+                    compressed.extend_from_slice(&output[..sz]);
+                    break;
+                }
+                HSEPollRes::More(sz) => compressed.extend_from_slice(&output[..sz]),
+                e => panic!("Failed to poll: {:?}", e),
+            }
+        };
+        for chunk in input.chunks(1000) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                match encoder.sink(chunk) {
+                    HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                    e => panic!("Failed to sink data: {:?}", e),
+                }
+                poll(&mut encoder, &mut compressed);
+            }
+        }
+        while encoder.finish() == HSEFinishRes::More {
+            poll(&mut encoder, &mut compressed);
+        }
+
+        // A state emits at most two bytes, so the budget ends calls long before the buffer fills
+        assert!(calls >= compressed.len() / 6);
+        assert_eq!(compressed, encode_all(input, 8, 4, 1000));
+    }
+
+    #[test]
+    fn output_pending_transitions() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut output = [0; 512];
+        assert!(!encoder.output_pending());
+
+        // Not enough input to fill the window
+        assert_eq!(encoder.sink(&input[..10]), HSESinkRes::Ok(10));
+        assert!(!encoder.output_pending());
+
+        // Filling the window makes output available
+        assert_eq!(encoder.sink(&input[10..]), HSESinkRes::Ok(246));
+        assert!(encoder.output_pending());
+        assert_eq!(encoder.poll(&mut output[..1]), HSEPollRes::More(1));
+        assert!(encoder.output_pending());
+        assert!(matches!(encoder.poll(&mut output), HSEPollRes::Empty(_)));
+        assert!(!encoder.output_pending());
+
+        // Each round of finish has the tail of the input to emit
+        while encoder.finish() == HSEFinishRes::More {
+            assert!(encoder.output_pending());
+            assert!(matches!(encoder.poll(&mut output), HSEPollRes::Empty(_)));
+        }
+        assert!(!encoder.output_pending());
+
+        // Finishing an empty stream has nothing to emit
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        assert_eq!(encoder.finish(), HSEFinishRes::More);
+        assert!(!encoder.output_pending());
+    }
+
+    #[cfg(feature = "smallvec")]
+    #[test]
+    fn smallvec_backing_matches_vec() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let encode = |mut encoder: HeatshrinkEncoder| {
+            let mut compressed = vec![];
+            let mut chunk = &input[..];
+            while !chunk.is_empty() {
+                match encoder.sink(chunk) {
+                    HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                    e => panic!("Failed to sink data: {:?}", e),
+                }
+                encoder.poll_to(&mut compressed).expect("Failed to poll");
+            }
+            while encoder.finish() == HSEFinishRes::More {
+                encoder.poll_to(&mut compressed).expect("Failed to poll");
+            }
+            compressed
+        };
+
+        for window_sz2 in 4..=10 {
+            let lookahead_sz2 = window_sz2 / 2 + 1;
+            let inline = HeatshrinkEncoder::new(window_sz2, lookahead_sz2)
+                .expect("Failed to create encoder");
+            assert_eq!(inline.buffer.spilled(), window_sz2 > 8);
+
+            // Heap storage with the same contents, like a plain Vec
+            let mut heap = HeatshrinkEncoder::new(window_sz2, lookahead_sz2)
+                .expect("Failed to create encoder");
+            let mut buffer = Vec::with_capacity(2 * SMALL_STORAGE_SIZE);
+            buffer.resize(heap.buffer.len(), 0);
+            heap.buffer = Storage::from_vec(buffer);
+            let mut search_index = Vec::with_capacity(2 * SMALL_STORAGE_SIZE);
+            search_index.resize(heap.search_index.len(), NO_LINK);
+            heap.search_index = Storage::from_vec(search_index);
+            assert!(heap.buffer.spilled() && heap.search_index.spilled());
+
+            assert_eq!(encode(inline), encode(heap));
+        }
+    }
+
+    #[test]
+    fn token_observer_reports_tokens() {
+        use std::sync::{Arc, Mutex};
+
+        let tokens = Arc::new(Mutex::new(vec![]));
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let observed = tokens.clone();
+        encoder.set_token_observer(move |token| observed.lock().unwrap().push(token));
+
+        let mut output = [0; 64];
+        assert_eq!(encoder.sink(b"abcabcabcabcx"), HSESinkRes::Ok(13));
+        while encoder.finish() == HSEFinishRes::More {
+            encoder.poll(&mut output);
+        }
+
+        assert_eq!(
+            *tokens.lock().unwrap(),
+            [
+                Token::Literal(b'a'),
+                Token::Literal(b'b'),
+                Token::Literal(b'c'),
+                Token::Backref {
+                    distance: 3,
+                    length: 9
+                },
+                Token::Literal(b'x'),
+            ]
+        );
+    }
+
+    #[test]
+    fn incremental_index_matches_full_rebuild() {
+        let text = &include_bytes!("heatshrink_encoder.rs")[..8192];
+        let random = &include_bytes!("../random-data.bin")[..8192];
+        let encode = |input: &[u8], window_sz2, lookahead_sz2, rebuild: bool| {
+            let mut encoder = HeatshrinkEncoder::new(window_sz2, lookahead_sz2)
+                .expect("Failed to create encoder");
+            let mut compressed = vec![];
+            for chunk in input.chunks(100) {
+                let mut chunk = chunk;
+                while !chunk.is_empty() {
+                    match encoder.sink(chunk) {
+                        HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                        e => panic!("Failed to sink data: {:?}", e),
+                    }
+                    if rebuild {
+                        encoder.indexed = 0;
+                    }
+                    encoder.poll_to(&mut compressed).expect("Failed to poll");
+                }
+            }
+            while encoder.finish() == HSEFinishRes::More {
+                encoder.poll_to(&mut compressed).expect("Failed to poll");
+            }
+            compressed
+        };
+
+        for window_sz2 in HEATSHRINK_MIN_WINDOW_BITS..=HEATSHRINK_MAX_WINDOW_BITS {
+            for lookahead_sz2 in HEATSHRINK_MIN_LOOKAHEAD_BITS..window_sz2.min(9) {
+                for input in [text, random] {
+                    assert_eq!(
+                        encode(input, window_sz2, lookahead_sz2, false),
+                        encode(input, window_sz2, lookahead_sz2, true),
+                        "window = {}, lookahead = {}",
+                        window_sz2,
+                        lookahead_sz2
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn window_15_finds_matches_past_32k() {
+        // Every search at window 15 starts past buffer position 32767, which used to end
+        // each chain, so the repeats of this random block were emitted as literals
+        let block = &include_bytes!("../random-data.bin")[..20000];
+        let input = block.repeat(3);
+        let compressed = encode_all(&input, 15, 8, 4096);
+        assert!(
+            compressed.len() < block.len() + block.len() / 4,
+            "{} bytes for {} bytes of input",
+            compressed.len(),
+            input.len()
+        );
+        let decompressed = decode_all(&compressed, 4096, 15, 8, 4096).expect("Failed to decode");
+        assert_eq!(decompressed, input);
+    }
+
+    #[test]
+    fn save_backlog_many_cycles() {
+        // A 16 byte window slides thousands of times over this input
+        let mut input = vec![];
+        for i in 0..4096u32 {
+            input.extend_from_slice(&(i % 37).to_le_bytes());
+            input.extend_from_slice(b"xyz");
+        }
+
+        for read_sz in [1, 7, 64, 1000] {
+            let compressed = encode_all(&input, 4, 3, read_sz);
+            assert!(compressed.len() < input.len());
+            let decompressed = decode_all(&compressed, 64, 4, 3, 64).expect("Failed to decode");
+            assert_eq!(decompressed, input);
+        }
+    }
+
+    #[test]
+    fn save_and_load_state_resumes_stream() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let expected = encode_all(input, 9, 5, 100);
+
+        let sink_all = |encoder: &mut HeatshrinkEncoder, data: &[u8], out: &mut Vec<u8>| {
+            for chunk in data.chunks(100) {
+                let mut chunk = chunk;
+                while !chunk.is_empty() {
+                    match encoder.sink(chunk) {
+                        HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                        e => panic!("Failed to sink data: {:?}", e),
+                    }
+                    encoder.poll_to(out).expect("Failed to poll");
+                }
+            }
+        };
+
+        // Checkpoint at an odd offset, mid-window
+        let (first, second) = input.split_at(input.len() / 2 + 77);
+        let mut encoder = HeatshrinkEncoder::new(9, 5).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        sink_all(&mut encoder, first, &mut compressed);
+        let state = encoder.save_state();
+        drop(encoder);
+
+        let mut encoder = HeatshrinkEncoder::load_state(&state).expect("Failed to load state");
+        sink_all(&mut encoder, second, &mut compressed);
+        while encoder.finish() == HSEFinishRes::More {
+            encoder.poll_to(&mut compressed).expect("Failed to poll");
+        }
+        assert_eq!(compressed, expected);
+
+        assert_eq!(
+            HeatshrinkEncoder::load_state(&state[..state.len() - 1]).err(),
+            Some(HeatshrinkError::InvalidState)
+        );
+        let mut bad_params = state.clone();
+        bad_params[2] = bad_params[1];
+        assert_eq!(
+            HeatshrinkEncoder::load_state(&bad_params).err(),
+            Some(HeatshrinkError::InvalidParams)
+        );
+
+        // Before the window first fills, nothing is indexed yet
+        let mut encoder = HeatshrinkEncoder::new(9, 5).expect("Failed to create encoder");
+        assert_eq!(encoder.sink(b"abc"), HSESinkRes::Ok(3));
+        assert!(HeatshrinkEncoder::load_state(&encoder.save_state()).is_ok());
+    }
+
+    #[test]
+    fn stream_encoder_roundtrips_with_stream_decoder() {
+        let text = include_bytes!("heatshrink_encoder.rs");
+        let input: Vec<u8> = text.iter().chain(text.iter()).copied().collect();
+
+        let mut encoder = StreamEncoder::new(10, 5).expect("Failed to create encoder");
+        let mut decoder = crate::StreamDecoder::new(32, 10, 5).expect("Failed to create decoder");
+        let mut compressed = Vec::new();
+        let mut decoded = Vec::new();
+        let mut held_bits = false;
+        let mut offset = 0;
+        for len in [1, 2, 3, 500, 7, 2000].iter().cycle() {
+            let message = &input[offset..input.len().min(offset + len)];
+            offset += message.len();
+            let polled = encoder.feed(message);
+            held_bits |= encoder.encoder.has_buffered_bits();
+            decoded.extend(decoder.feed(&polled).unwrap());
+            compressed.extend(polled);
+            assert_eq!(decoded, &input[..decoded.len()]);
+            if offset == input.len() {
+                break;
+            }
+        }
+        assert!(held_bits);
+        assert_eq!(encoder.total_sunk(), input.len() as u64);
+
+        let tail = encoder.close();
+        decoded.extend(decoder.feed(&tail).unwrap());
+        compressed.extend(tail);
+        assert_eq!(decoded, input);
+        // One continuous session is the same stream as compressing everything at once
+        assert_eq!(compressed, encode_all(&input, 10, 5, 64));
+    }
+
+    #[cfg(feature = "debug-bounds")]
+    #[test]
+    fn debug_bounds_logs_out_of_range_literal() {
+        use std::ops::Range;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use std::sync::{Arc, Mutex};
+
+        type Access = (&'static str, Range<usize>, usize);
+        struct Log(Arc<Mutex<Vec<Access>>>);
+        impl BoundsLogger for Log {
+            fn out_of_bounds(&self, access: &'static str, range: Range<usize>, len: usize) {
+                self.0.lock().unwrap().push((access, range, len));
+            }
+        }
+
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        encoder.set_bounds_logger(Log(logged.clone()));
+
+        // A bug in the offset math would leave the scan index past the end of the buffer
+        encoder.match_scan_index = 1 << 9;
+        let mut out = [0u8; 4];
+        let mut output_size = 0;
+        let mut oi = OutputInfo {
+            buf: &mut out[..],
+            output_size: &mut output_size,
+        };
+        let caught = catch_unwind(AssertUnwindSafe(|| encoder.push_literal_byte(&mut oi)));
+        assert!(caught.is_err());
+        assert_eq!(
+            *logged.lock().unwrap(),
+            [("push_literal_byte buffer", 767..768, 512)]
+        );
+    }
+
+    #[test]
+    fn sink_empty_is_error_null() {
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        assert_eq!(encoder.sink(&[]), HSESinkRes::ErrorNull);
+        assert_eq!(encoder.total_sunk(), 0);
+
+        // The encoder is unaffected and still takes input
+        assert_eq!(encoder.sink(b"abc"), HSESinkRes::Ok(3));
+        let mut compressed = vec![];
+        encoder.finalize(&mut compressed).unwrap();
+        assert_eq!(compressed, encode_all(b"abc", 8, 4, 64));
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn state_logger_follows_state_path() {
+        use std::sync::{Arc, Mutex};
+
+        struct Path(Arc<Mutex<Vec<String>>>);
+        impl StateLogger for Path {
+            fn on_transition(&mut self, from: &str, to: &str) {
+                self.0.lock().unwrap().push(format!("{from}->{to}"));
+            }
+        }
+
+        let path = Arc::new(Mutex::new(Vec::new()));
+        let mut encoder = HeatshrinkEncoder::new(4, 3).expect("Failed to create encoder");
+        encoder.set_state_logger(Path(path.clone()));
+        assert_eq!(encoder.sink(b"aaaaab"), HSESinkRes::Ok(6));
+        let mut compressed = vec![];
+        encoder.finalize(&mut compressed).unwrap();
+        assert_eq!(compressed, encode_all(b"aaaaab", 4, 3, 64));
+
+        // A literal, a backref for the next 4 bytes, and a literal. Input shorter than the
+        // lookahead is only searched while finishing, which marks the buffer `Filled` again
+        // after each token is saved back.
+        assert_eq!(
+            *path.lock().unwrap(),
+            [
+                "Filled->Search",
+                "Search->YieldTagBit",
+                "YieldTagBit->YieldLiteral",
+                "YieldLiteral->Search",
+                "Search->SaveBacklog",
+                "SaveBacklog->NotFull",
+                "Filled->Search",
+                "Search->YieldTagBit",
+                "YieldTagBit->YieldBrIndex",
+                "YieldBrIndex->YieldBrLength",
+                "YieldBrLength->Search",
+                "Search->SaveBacklog",
+                "SaveBacklog->NotFull",
+                "Filled->Search",
+                "Search->YieldTagBit",
+                "YieldTagBit->YieldLiteral",
+                "YieldLiteral->Search",
+                "Search->SaveBacklog",
+                "SaveBacklog->NotFull",
+                "Filled->Search",
+                "Search->FlushBits",
+                "FlushBits->Done",
+            ]
+        );
+    }
+
+    #[cfg(feature = "test-introspection")]
+    #[test]
+    fn state_visit_counts_cover_save_backlog() {
+        let save_backlog = HSE_STATE_NAMES
+            .iter()
+            .position(|&name| name == "SaveBacklog")
+            .unwrap();
+
+        // Every full window is saved back before the next one is sunk
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        encoder
+            .compress_chunk(input, &mut compressed)
+            .expect("Failed to compress");
+        let visits = encoder.state_visit_counts();
+        assert!(visits[save_backlog] as usize >= input.len() / 256 - 1);
+
+        // Input that never fills the window is only searched, and saved back after each
+        // token, once the stream is finished
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        let mut compressed = vec![];
+        encoder
+            .compress_chunk(b"aaaaab", &mut compressed)
+            .expect("Failed to compress");
+        assert_eq!(encoder.state_visit_counts(), [0; HSE_STATE_NAMES.len()]);
+        encoder
+            .finalize(&mut compressed)
+            .expect("Failed to finalize");
+        assert_eq!(encoder.state_visit_counts()[save_backlog], 3);
+
+        encoder.reset();
+        assert_eq!(encoder.state_visit_counts(), [0; HSE_STATE_NAMES.len()]);
+    }
 }
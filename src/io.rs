@@ -0,0 +1,539 @@
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use crate::HeatshrinkError;
+
+/// The outcome of reading from a `Readable` source.
+#[derive(Debug, PartialEq)]
+pub enum ReadResult<'r> {
+    /// Bytes were copied to the front of the buffer, this is that filled prefix
+    Read(&'r [u8]),
+    /// The source has no more bytes
+    Eof,
+    /// The source has no bytes right now but may have more later, e.g. a non-blocking UART
+    /// with an empty receive FIFO, so read again later
+    Pending,
+}
+
+/// A source of bytes to sink into an encoder or decoder.
+///
+/// This is a minimal no_std stand-in for `std::io::Read`.
+pub trait Readable {
+    /// Copy up to `buf.len()` bytes to the front of `buf` and advance past them,
+    /// or report `Eof`, or `Pending` if a non-blocking source has nothing yet
+    fn read<'r>(&mut self, buf: &'r mut [u8]) -> ReadResult<'r>;
+}
+
+/// Reading from a slice copies from its front and advances it past the read bytes,
+/// like `std::io::Read` for `&[u8]`. It is never `Pending`.
+impl Readable for &[u8] {
+    #[inline]
+    fn read<'r>(&mut self, buf: &'r mut [u8]) -> ReadResult<'r> {
+        if self.is_empty() {
+            return ReadResult::Eof;
+        }
+        let (head, tail) = self.split_at(self.len().min(buf.len()));
+        buf[..head.len()].copy_from_slice(head);
+        *self = tail;
+        ReadResult::Read(&buf[..head.len()])
+    }
+}
+
+/// Reads several slices back to back, as if they were one, e.g. the parts of a ring
+/// buffer or a scatter list.
+#[derive(Debug)]
+pub struct ChainReadable<'a> {
+    slices: &'a [&'a [u8]],
+    /// index of the slice being read
+    slice: usize,
+    /// bytes of that slice already read
+    offset: usize,
+}
+
+impl<'a> ChainReadable<'a> {
+    pub fn new(slices: &'a [&'a [u8]]) -> Self {
+        Self {
+            slices,
+            slice: 0,
+            offset: 0,
+        }
+    }
+}
+
+impl Readable for ChainReadable<'_> {
+    fn read<'r>(&mut self, buf: &'r mut [u8]) -> ReadResult<'r> {
+        let mut n = 0;
+        while n < buf.len() {
+            let Some(slice) = self.slices.get(self.slice) else {
+                break;
+            };
+            let rest = &slice[self.offset..];
+            let take = rest.len().min(buf.len() - n);
+            buf[n..n + take].copy_from_slice(&rest[..take]);
+            n += take;
+            if take == rest.len() {
+                self.slice += 1;
+                self.offset = 0;
+            } else {
+                self.offset += take;
+            }
+        }
+
+        if n == 0 && !buf.is_empty() {
+            ReadResult::Eof
+        } else {
+            ReadResult::Read(&buf[..n])
+        }
+    }
+}
+
+/// A destination for bytes polled out of an encoder or decoder.
+///
+/// This is a minimal no_std stand-in for `std::io::Write`.
+pub trait Writable {
+    /// Write all of `data`, or return an error without writing if it does not fit
+    fn write_all(&mut self, data: &[u8]) -> Result<(), HeatshrinkError>;
+}
+
+impl Writable for Vec<u8> {
+    #[inline]
+    fn write_all(&mut self, data: &[u8]) -> Result<(), HeatshrinkError> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Writing to a slice fills it from the front and advances it past the written bytes,
+/// like `std::io::Write` for `&mut [u8]`.
+impl Writable for &mut [u8] {
+    #[inline]
+    fn write_all(&mut self, data: &[u8]) -> Result<(), HeatshrinkError> {
+        if data.len() > self.len() {
+            return Err(HeatshrinkError::OutputTooSmall);
+        }
+        let (head, tail) = core::mem::take(self).split_at_mut(data.len());
+        head.copy_from_slice(data);
+        *self = tail;
+        Ok(())
+    }
+}
+
+/// A fixed-capacity ring that output is pushed into one byte at a time, such as the
+/// transmit ring of a UART that is drained by DMA.
+pub trait RingSink {
+    /// Append `byte`, or return `false` without storing it if the ring is full
+    fn push(&mut self, byte: u8) -> bool;
+    /// Whether `push` would currently fail
+    fn is_full(&self) -> bool;
+}
+
+#[cfg(feature = "std")]
+pub use self::decode_reader::{decode_reader_verified, DecodeReader};
+
+#[cfg(feature = "std")]
+mod decode_reader {
+    use std::format;
+    use std::io::{self, BufRead, Read, Write};
+    use std::vec;
+    use std::vec::Vec;
+
+    use crate::{
+        common::Crc32, HSDFinishRes, HSDPollRes, HSDSinkRes, HeatshrinkDecoder, HeatshrinkError,
+    };
+
+    /// Size of the compressed input and decompressed staging buffers.
+    const WORK_SIZE_UNIT: usize = 1024;
+
+    /// Decompresses a heatshrink stream read from `R`.
+    ///
+    /// Decompressed bytes are staged in an internal buffer that is exposed through
+    /// `BufRead`, so line-oriented consumers can use `read_line` or `lines` without
+    /// another copy.
+    pub struct DecodeReader<R> {
+        inner: R,
+        decoder: HeatshrinkDecoder,
+        /// compressed bytes read from `inner`, not yet sunk
+        input: Vec<u8>,
+        input_pos: usize,
+        input_len: usize,
+        /// decompressed bytes polled from the decoder, not yet consumed
+        staging: Vec<u8>,
+        staging_pos: usize,
+        staging_len: usize,
+        /// `inner` has reported end of file
+        input_eof: bool,
+        /// the decoder has finished and the staging buffer will not refill
+        done: bool,
+        /// CRC-32 of the decompressed bytes polled so far, if enabled by `with_crc32`
+        digest: Option<Crc32>,
+    }
+
+    impl<R: Read> DecodeReader<R> {
+        /// Wraps `inner` with a decoder for the given window and lookahead sizes.
+        pub fn new(inner: R, window_sz2: u8, lookahead_sz2: u8) -> Result<Self, HeatshrinkError> {
+            let decoder = HeatshrinkDecoder::new(WORK_SIZE_UNIT as u16, window_sz2, lookahead_sz2)
+                .ok_or(HeatshrinkError::InvalidParams)?;
+            Ok(Self {
+                inner,
+                decoder,
+                input: vec![0; WORK_SIZE_UNIT],
+                input_pos: 0,
+                input_len: 0,
+                staging: vec![0; WORK_SIZE_UNIT],
+                staging_pos: 0,
+                staging_len: 0,
+                input_eof: false,
+                done: false,
+                digest: None,
+            })
+        }
+
+        /// Also compute the CRC-32 of the decompressed bytes as they are decoded, to check
+        /// them against an expected digest with `crc32` without a second pass.
+        pub fn with_crc32(mut self) -> Self {
+            self.digest = Some(Crc32::new());
+            self
+        }
+
+        /// Treat a backref to bytes that were never decoded as corruption, instead of decoding
+        /// it to zeros like streams from the C encoder may rely on, see
+        /// `HeatshrinkDecoder::set_strict_backrefs`.
+        pub fn with_strict_backrefs(mut self) -> Self {
+            self.decoder.set_strict_backrefs(true);
+            self
+        }
+
+        /// CRC-32 (IEEE, as in zlib) of all bytes decoded so far, including any that are
+        /// buffered but not read yet, or `None` without `with_crc32`.
+        pub fn crc32(&self) -> Option<u32> {
+            self.digest.map(Crc32::finish)
+        }
+
+        /// Number of bytes decoded so far, including any that are buffered but not read yet.
+        pub fn total_emitted(&self) -> u64 {
+            self.decoder.total_emitted()
+        }
+
+        /// Unwraps the reader, discarding any buffered input or output.
+        pub fn into_inner(self) -> R {
+            self.inner
+        }
+    }
+
+    fn corrupt() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, "corrupt heatshrink stream")
+    }
+
+    impl<R: Read> BufRead for DecodeReader<R> {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            while self.staging_pos == self.staging_len && !self.done {
+                self.staging_pos = 0;
+                self.staging_len = match self.decoder.poll(&mut self.staging) {
+                    HSDPollRes::Empty(sz) | HSDPollRes::More(sz) => sz,
+                    HSDPollRes::ErrorUnknown => return Err(corrupt()),
+                    HSDPollRes::ErrorNull => unreachable!(),
+                };
+                if self.staging_len > 0 {
+                    if let Some(digest) = &mut self.digest {
+                        digest.update(&self.staging[..self.staging_len]);
+                    }
+                    break;
+                }
+
+                // The decoder is drained, so feed it more input or finish the stream
+                if self.input_pos < self.input_len {
+                    match self
+                        .decoder
+                        .sink(&self.input[self.input_pos..self.input_len])
+                    {
+                        HSDSinkRes::Ok(sz) => self.input_pos += sz,
+                        HSDSinkRes::Full => {}
+                        HSDSinkRes::ErrorNull | HSDSinkRes::ErrorMisuse => unreachable!(),
+                    }
+                } else if !self.input_eof {
+                    self.input_pos = 0;
+                    self.input_len = match self.inner.read(&mut self.input) {
+                        Ok(sz) => sz,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    self.input_eof = self.input_len == 0;
+                } else {
+                    match self.decoder.finish() {
+                        HSDFinishRes::Done => self.done = true,
+                        HSDFinishRes::More => {}
+                        HSDFinishRes::ErrorUnknown => return Err(corrupt()),
+                        HSDFinishRes::ErrorNull => unreachable!(),
+                    }
+                }
+            }
+            Ok(&self.staging[self.staging_pos..self.staging_len])
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.staging_pos = (self.staging_pos + amt).min(self.staging_len);
+        }
+    }
+
+    impl<R: Read> Read for DecodeReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let available = self.fill_buf()?;
+            let sz = available.len().min(buf.len());
+            buf[..sz].copy_from_slice(&available[..sz]);
+            self.consume(sz);
+            Ok(sz)
+        }
+    }
+
+    /// Decompress the heatshrink stream read from `r` into `out` in one pass, checking the
+    /// CRC-32 (IEEE, as in zlib) of the decompressed bytes against `expected_crc`.
+    ///
+    /// Returns an `InvalidInput` error if the window and lookahead sizes are invalid, and an
+    /// `InvalidData` error if the stream is corrupt or the digest does not match. All of the
+    /// output has been written to `out` by the time a mismatch is detected.
+    pub fn decode_reader_verified(
+        r: impl Read,
+        out: &mut impl Write,
+        window_sz2: u8,
+        lookahead_sz2: u8,
+        expected_crc: u32,
+    ) -> io::Result<()> {
+        let mut reader = DecodeReader::new(r, window_sz2, lookahead_sz2)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .with_crc32();
+        io::copy(&mut reader, out)?;
+        let crc = reader.crc32().expect("CRC-32 is enabled");
+        if crc != expected_crc {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "CRC-32 {crc:08x} of {} decompressed bytes does not match {expected_crc:08x}",
+                    reader.total_emitted()
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mmap")]
+pub use self::mmap::encode_file;
+
+#[cfg(feature = "mmap")]
+mod mmap {
+    use std::fs::File;
+    use std::io;
+    use std::path::Path;
+    use std::vec::Vec;
+
+    use memmap2::Mmap;
+
+    use crate::{
+        encode_all, heatshrink_encoder::valid_params, recommended_read_sz, HeatshrinkError,
+    };
+
+    /// Compress the file at `path` like `encode_all`, mapping it into memory instead of
+    /// reading it, which saves the read syscalls and copies for large files.
+    ///
+    /// Returns the error of opening or mapping the file, e.g. `NotFound` or
+    /// `PermissionDenied`, and an `InvalidInput` error if the window and lookahead sizes are
+    /// invalid. An empty file compresses to an empty stream.
+    ///
+    /// The file must not be truncated by another process while it is compressed, as reading
+    /// a mapped page past the new end of the file raises `SIGBUS`.
+    pub fn encode_file(
+        path: impl AsRef<Path>,
+        window_sz2: u8,
+        lookahead_sz2: u8,
+    ) -> io::Result<Vec<u8>> {
+        if !valid_params(window_sz2, lookahead_sz2) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                HeatshrinkError::InvalidParams,
+            ));
+        }
+        let file = File::open(path)?;
+        // mapping an empty file fails on some platforms
+        if file.metadata()?.len() == 0 {
+            return Ok(Vec::new());
+        }
+        // SAFETY: the map is only read while it is alive, see the truncation caveat above
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(encode_all(
+            &map,
+            window_sz2,
+            lookahead_sz2,
+            recommended_read_sz(window_sz2),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_readable_crosses_slices() {
+        let slices: [&[u8]; 4] = [b"ab", b"", b"c", b"def"];
+        let mut chain = ChainReadable::new(&slices);
+
+        let mut read = vec![];
+        let mut buf = [0; 1];
+        while let ReadResult::Read(data) = chain.read(&mut buf) {
+            assert_eq!(data.len(), 1);
+            read.extend_from_slice(data);
+        }
+        assert_eq!(read, b"abcdef");
+        assert_eq!(chain.read(&mut buf), ReadResult::Eof);
+
+        let mut chain = ChainReadable::new(&slices);
+        let mut buf = [0; 4];
+        assert_eq!(chain.read(&mut buf), ReadResult::Read(b"abcd"));
+        assert_eq!(chain.read(&mut buf), ReadResult::Read(b"ef"));
+        assert_eq!(chain.read(&mut buf), ReadResult::Eof);
+    }
+
+    #[test]
+    fn slice_readable_advances() {
+        let mut source: &[u8] = b"heatshrink";
+        let mut buf = [0; 4];
+        assert_eq!(source.read(&mut buf), ReadResult::Read(b"heat"));
+        assert_eq!(source.read(&mut buf), ReadResult::Read(b"shri"));
+        assert_eq!(source.read(&mut buf), ReadResult::Read(b"nk"));
+        assert_eq!(source.read(&mut buf), ReadResult::Eof);
+        assert!(source.is_empty());
+    }
+
+    #[test]
+    fn stateful_source() {
+        /// Counts up from `next`, ending after `end`
+        struct Counter {
+            next: u8,
+            end: u8,
+        }
+
+        impl Readable for Counter {
+            fn read<'r>(&mut self, buf: &'r mut [u8]) -> ReadResult<'r> {
+                if self.next > self.end {
+                    return ReadResult::Eof;
+                }
+                let mut n = 0;
+                while n < buf.len() && self.next <= self.end {
+                    buf[n] = self.next;
+                    self.next += 1;
+                    n += 1;
+                }
+                ReadResult::Read(&buf[..n])
+            }
+        }
+
+        let mut counter = Counter { next: 0, end: 9 };
+        let mut read = vec![];
+        let mut buf = [0; 3];
+        while let ReadResult::Read(data) = counter.read(&mut buf) {
+            read.extend_from_slice(data);
+        }
+        assert_eq!(read, (0..=9).collect::<Vec<u8>>());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn decode_reader_lines() {
+        use crate::encode_all;
+        use std::io::{BufRead, Read};
+
+        let text: String = (0..500)
+            .map(|i| std::format!("log line {i}: sensor reading {}\n", i * 7 % 13))
+            .collect();
+        let compressed = encode_all(text.as_bytes(), 8, 4, 64);
+
+        let reader = DecodeReader::new(compressed.as_slice(), 8, 4).unwrap();
+        let lines: Vec<String> = reader.lines().map(|l| l.unwrap()).collect();
+        assert_eq!(lines.len(), 500);
+        assert_eq!(lines, text.lines().collect::<Vec<_>>());
+
+        let mut reader = DecodeReader::new(compressed.as_slice(), 8, 4).unwrap();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, text.as_bytes());
+        assert!(reader.fill_buf().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn decode_reader_verified_checks_crc() {
+        use crate::{common::crc32, encode_all};
+
+        let input = include_bytes!("io.rs");
+        let compressed = encode_all(input, 8, 4, 64);
+        let expected_crc = crc32(input);
+
+        let mut out = Vec::new();
+        decode_reader_verified(compressed.as_slice(), &mut out, 8, 4, expected_crc).unwrap();
+        assert_eq!(out, input);
+
+        let mut out = Vec::new();
+        let err = decode_reader_verified(compressed.as_slice(), &mut out, 8, 4, !expected_crc)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(out, input);
+
+        let reader = DecodeReader::new(compressed.as_slice(), 8, 4).unwrap();
+        assert_eq!(reader.crc32(), None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn decode_reader_strict_backrefs() {
+        use crate::encode_all;
+        use std::io::Read;
+
+        let input = include_bytes!("io.rs");
+        let compressed = encode_all(input, 8, 4, 64);
+        let mut reader = DecodeReader::new(compressed.as_slice(), 8, 4)
+            .unwrap()
+            .with_strict_backrefs();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, input);
+
+        // A length 4 backref before anything was decoded
+        let corrupted = [0x00, 0x18];
+        let mut out = Vec::new();
+        DecodeReader::new(&corrupted[..], 8, 4)
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, [0; 4]);
+        let err = DecodeReader::new(&corrupted[..], 8, 4)
+            .unwrap()
+            .with_strict_backrefs()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn encode_file_matches_encode_all() {
+        use crate::encode_all;
+
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("embedded-heatshrink-{}.bin", std::process::id()));
+        let input = include_bytes!("heatshrink_encoder.rs");
+
+        std::fs::write(&path, input).unwrap();
+        let compressed = encode_file(&path, 11, 6).unwrap();
+        assert_eq!(compressed, encode_all(input, 11, 6, 64));
+
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(encode_file(&path, 11, 6).unwrap(), b"");
+
+        let err = encode_file(&path, 4, 4).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        std::fs::remove_file(&path).unwrap();
+        let err = encode_file(&path, 11, 6).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+}
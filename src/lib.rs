@@ -17,24 +17,128 @@ extern crate std;
 #[cfg(not(feature = "std"))]
 extern crate alloc;
 
+#[cfg(not(feature = "std"))]
+use alloc::string::String;
+#[cfg(not(feature = "std"))]
+use alloc::vec;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
 #[cfg(feature = "std")]
 use std::io::{Read, Write};
 
+pub mod block;
+pub mod codec;
 pub(crate) mod common;
+pub mod config;
+pub mod error;
+pub mod header;
 pub mod heatshrink_decoder;
 pub mod heatshrink_encoder;
+pub mod io;
+#[cfg(feature = "resync")]
+pub mod resync;
+pub mod shared_index;
+pub mod token;
 
+use common::{read_varint, write_varint, BitWriter};
+
+pub use block::*;
+pub use codec::*;
+pub use config::*;
+pub use error::*;
+pub use header::*;
 pub use heatshrink_decoder::*;
 pub use heatshrink_encoder::*;
+pub use io::*;
+#[cfg(feature = "resync")]
+pub use resync::*;
+pub use shared_index::*;
+pub use token::*;
 
 /// Heatshrink constant limits
 pub const HEATSHRINK_MIN_WINDOW_BITS: u8 = 4;
-pub const HEATSHRINK_MAX_WINDOW_BITS: u8 = 15; // there may be some strangeness with 15 but it passes tests
+#[cfg(not(feature = "wide-window"))]
+pub const HEATSHRINK_MAX_WINDOW_BITS: u8 = 15;
+/// A 64 KiB window is an extension of the format, standard heatshrink cannot decode it
+#[cfg(feature = "wide-window")]
+pub const HEATSHRINK_MAX_WINDOW_BITS: u8 = 16;
 pub const HEATSHRINK_MIN_LOOKAHEAD_BITS: u8 = 3;
 
+/// Default window size, chosen from the bar chart in 'average-compression-tsz-data.png'
+/// as a good tradeoff between ratio, speed, and a 1 KiB encoder buffer
+pub const DEFAULT_WINDOW_BITS: u8 = 9;
+/// Default lookahead size, paired with `DEFAULT_WINDOW_BITS`
+pub const DEFAULT_LOOKAHEAD_BITS: u8 = 7;
+
 /// Create an encoder, Read from stdin, Sink and Poll through the encoder, and Write polled bytes to stdout.
 #[cfg(feature = "std")]
 pub fn encode(window_sz2: u8, lookahead_sz2: u8, stdin: &mut impl Read, stdout: &mut impl Write) {
+    encode_with_progress(window_sz2, lookahead_sz2, stdin, stdout, u64::MAX, |_| {});
+}
+
+/// Throughput of `encode_with_progress` or `decode_with_progress` so far
+#[cfg(feature = "std")]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Progress {
+    /// bytes read from the input
+    pub bytes_in: u64,
+    /// bytes produced for the output
+    pub bytes_out: u64,
+    /// time since the call started
+    pub elapsed: std::time::Duration,
+}
+
+/// Calls a `Progress` callback each time the input count crosses a multiple of `interval`
+#[cfg(feature = "std")]
+struct ProgressMeter<F: FnMut(Progress)> {
+    interval: u64,
+    next: u64,
+    start: std::time::Instant,
+    progress: F,
+}
+
+#[cfg(feature = "std")]
+impl<F: FnMut(Progress)> ProgressMeter<F> {
+    fn new(interval: u64, progress: F) -> Self {
+        assert!(interval > 0, "interval must be greater than 0");
+        Self {
+            interval,
+            next: interval,
+            start: std::time::Instant::now(),
+            progress,
+        }
+    }
+
+    #[inline]
+    fn update(&mut self, bytes_in: u64, bytes_out: u64) {
+        if bytes_in >= self.next {
+            self.next = (bytes_in / self.interval).saturating_add(1) * self.interval;
+            (self.progress)(Progress {
+                bytes_in,
+                bytes_out,
+                elapsed: self.start.elapsed(),
+            });
+        }
+    }
+}
+
+/// Like `encode`, calling `progress` after each read that crosses a multiple of `interval`
+/// input bytes, e.g. to print a live ratio and throughput. Input is read in 1 KiB chunks, so
+/// an `interval` below that is reported at most once per read.
+///
+/// Panics if `interval` is 0.
+#[cfg(feature = "std")]
+pub fn encode_with_progress(
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    stdin: &mut impl Read,
+    stdout: &mut impl Write,
+    interval: u64,
+    progress: impl FnMut(Progress),
+) {
+    let mut meter = ProgressMeter::new(interval, progress);
+    let mut bytes_out = 0;
     let mut encoder =
         HeatshrinkEncoder::new(window_sz2, lookahead_sz2).expect("Failed to create encoder");
 
@@ -64,15 +168,21 @@ pub fn encode(window_sz2: u8, lookahead_sz2: u8, stdin: &mut impl Read, stdout:
                 match encoder.poll(&mut scratch) {
                     HSEPollRes::Empty(sz) => {
                         write_out(stdout, &scratch[..sz]);
+                        bytes_out += sz as u64;
                         break;
                     }
                     HSEPollRes::More(sz) => {
                         write_out(stdout, &scratch[..sz]);
+                        bytes_out += sz as u64;
                     }
-                    HSEPollRes::ErrorMisuse | HSEPollRes::ErrorNull => unreachable!(),
+                    HSEPollRes::ErrorMisuse | HSEPollRes::ErrorNull | HSEPollRes::Aborted => {
+                        unreachable!()
+                    }
+                    HSEPollRes::ErrorUnknown => panic!("Encoder invariant failed"),
                 }
             }
         }
+        meter.update(encoder.total_sunk(), bytes_out);
     }
 
     if !not_empty {
@@ -87,6 +197,7 @@ pub fn encode(window_sz2: u8, lookahead_sz2: u8, stdin: &mut impl Read, stdout:
             }
             HSEFinishRes::More => {}
             HSEFinishRes::ErrorNull => unreachable!(),
+            HSEFinishRes::ErrorUnknown => panic!("Encoder invariant failed"),
         }
 
         loop {
@@ -98,7 +209,10 @@ pub fn encode(window_sz2: u8, lookahead_sz2: u8, stdin: &mut impl Read, stdout:
                 HSEPollRes::More(sz) => {
                     write_out(stdout, &scratch[..sz]);
                 }
-                HSEPollRes::ErrorMisuse | HSEPollRes::ErrorNull => unreachable!(),
+                HSEPollRes::ErrorMisuse | HSEPollRes::ErrorNull | HSEPollRes::Aborted => {
+                    unreachable!()
+                }
+                HSEPollRes::ErrorUnknown => panic!("Encoder invariant failed"),
             }
         }
     }
@@ -107,8 +221,27 @@ pub fn encode(window_sz2: u8, lookahead_sz2: u8, stdin: &mut impl Read, stdout:
 /// Create a decoder, Read from stdin, Sink and Poll through the decoder, and Write polled bytes to stdout.
 #[cfg(feature = "std")]
 pub fn decode(window_sz2: u8, lookahead_sz2: u8, stdin: &mut impl Read, stdout: &mut impl Write) {
+    decode_with_progress(window_sz2, lookahead_sz2, stdin, stdout, u64::MAX, |_| {});
+}
+
+/// Like `decode`, calling `progress` after each read that crosses a multiple of `interval`
+/// compressed input bytes, see `encode_with_progress`.
+///
+/// Panics if `interval` is 0.
+#[cfg(feature = "std")]
+pub fn decode_with_progress(
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    stdin: &mut impl Read,
+    stdout: &mut impl Write,
+    interval: u64,
+    progress: impl FnMut(Progress),
+) {
     const WORK_SIZE_UNIT: usize = 1024;
 
+    let mut meter = ProgressMeter::new(interval, progress);
+    let mut bytes_in = 0;
+
     let mut decoder = HeatshrinkDecoder::new(WORK_SIZE_UNIT as u16, window_sz2, lookahead_sz2)
         .expect("Failed to create decoder");
     let mut buf = [0; WORK_SIZE_UNIT];
@@ -122,6 +255,7 @@ pub fn decode(window_sz2: u8, lookahead_sz2: u8, stdin: &mut impl Read, stdout:
         if read_len == 0 {
             break;
         }
+        bytes_in += read_len as u64;
         let mut read_data = &buf[..read_len];
         while !read_data.is_empty() {
             let sink_res = decoder.sink(read_data);
@@ -148,6 +282,7 @@ pub fn decode(window_sz2: u8, lookahead_sz2: u8, stdin: &mut impl Read, stdout:
                 }
             }
         }
+        meter.update(bytes_in, decoder.total_emitted());
     }
 
     if !not_empty {
@@ -162,6 +297,9 @@ pub fn decode(window_sz2: u8, lookahead_sz2: u8, stdin: &mut impl Read, stdout:
             }
             HSDFinishRes::More => {}
             HSDFinishRes::ErrorNull => unreachable!(),
+            HSDFinishRes::ErrorUnknown => {
+                panic!("Error: Unknown");
+            }
         }
 
         loop {
@@ -194,371 +332,2313 @@ fn write_out(stdout: &mut impl Write, data: &[u8]) {
     stdout.write_all(data).expect("Failed to write to stdout");
 }
 
-#[cfg(test)]
-mod tests {
-    use rayon::prelude::*;
-    use std::time::Instant;
+/// Upper bound on the number of bytes `encode_all` can produce for `input_len` bytes of input.
+///
+/// Derivation: a literal costs 1 tag bit plus 8 bits. A backref costs
+/// `1 + window_sz2 + lookahead_sz2` bits and is only emitted when its length `len` is greater than
+/// `(1 + window_sz2 + lookahead_sz2) / 8`, so `8 * len > 1 + window_sz2 + lookahead_sz2` and a
+/// backref is always cheaper than the `9 * len` bits of literals it replaces. The worst case is
+/// therefore all literals, `9 * input_len` bits, and the trailing flush pads the last partial
+/// byte, giving `ceil(9 * input_len / 8)` bytes. That holds for every valid window and lookahead.
+pub const fn max_compressed_len(input_len: usize, window_sz2: u8, lookahead_sz2: u8) -> usize {
+    let _ = (window_sz2, lookahead_sz2);
+    // ceil(9 * n / 8) without overflowing for large n
+    input_len + input_len.div_ceil(8)
+}
 
-    use super::*;
+/// Smallest `read_sz` with which a single read fills the encoder's input buffer, so that every
+/// sink is followed by a poll that emits output. Any `read_sz` above 0 makes progress, smaller
+/// sizes just take several reads per window, and 1 is valid but slow.
+pub const fn min_encoder_read_sz(window_sz2: u8) -> usize {
+    1 << window_sz2
+}
 
-    fn encode_all(input: &[u8], window_sz2: u8, lookahead_sz2: u8, read_sz: usize) -> Vec<u8> {
-        assert!(read_sz > 0, "read_sz must be greater than 0");
-        let mut encoder =
-            HeatshrinkEncoder::new(window_sz2, lookahead_sz2).expect("Failed to create encoder");
-        let mut compressed = vec![];
-        let mut scratch: Vec<u8> = vec![0; read_sz * 2];
-        let mut read_offset = 0;
-
-        // Sink all bytes from the input buffer
-        while read_offset < input.len() {
-            let read_len = if input.len() - read_offset > read_sz {
-                read_sz
-            } else {
-                input.len() - read_offset
-            };
-            let mut read_data = &input[read_offset..read_offset + read_len];
-            while !read_data.is_empty() {
-                let sink_res = encoder.sink(read_data);
-                match sink_res {
-                    HSESinkRes::Ok(bytes_sunk) => {
-                        read_data = &read_data[bytes_sunk..];
-                    }
-                    _ => unreachable!(),
-                }
-
-                loop {
-                    match encoder.poll(&mut scratch) {
-                        HSEPollRes::Empty(sz) => {
-                            compressed.extend(&scratch[..sz]);
-                            break;
-                        }
-                        HSEPollRes::More(sz) => {
-                            compressed.extend(&scratch[..sz]);
-                        }
-                        HSEPollRes::ErrorMisuse | HSEPollRes::ErrorNull => unreachable!(),
-                    }
-                }
-            }
+/// A `read_sz` for `encode_all` and `decode_all` that keeps the per-call overhead low: at
+/// least `min_encoder_read_sz`, and no less than 512 bytes for small windows.
+pub const fn recommended_read_sz(window_sz2: u8) -> usize {
+    let min = min_encoder_read_sz(window_sz2);
+    if min > 512 {
+        min
+    } else {
+        512
+    }
+}
+
+/// Largest `window_sz2` whose encoder, as counted by `encoder_bytes`, fits in `bytes`, or
+/// `None` if not even the smallest window fits.
+pub fn largest_window_for_budget(bytes: usize) -> Option<u8> {
+    (HEATSHRINK_MIN_WINDOW_BITS..=HEATSHRINK_MAX_WINDOW_BITS)
+        .rev()
+        .find(|&window_sz2| encoder_bytes(window_sz2) <= bytes)
+}
+
+/// Lookahead `encode_within_budget` pairs with a window: `DEFAULT_LOOKAHEAD_BITS`, or
+/// `window_sz2 - 1` for windows too small to hold it.
+pub const fn budget_lookahead_sz2(window_sz2: u8) -> u8 {
+    if DEFAULT_LOOKAHEAD_BITS < window_sz2 {
+        DEFAULT_LOOKAHEAD_BITS
+    } else {
+        window_sz2 - 1
+    }
+}
+
+/// Encode `input` with the largest window whose encoder fits in `bytes`, returning the
+/// compressed data and the `window_sz2` used. The lookahead is `budget_lookahead_sz2` of that
+/// window, which the decoder needs as well. Fails with `InvalidParams` if the budget is too
+/// small for any window.
+pub fn encode_within_budget(input: &[u8], bytes: usize) -> Result<(Vec<u8>, u8), HeatshrinkError> {
+    let window_sz2 = largest_window_for_budget(bytes).ok_or(HeatshrinkError::InvalidParams)?;
+    let lookahead_sz2 = budget_lookahead_sz2(window_sz2);
+    let compressed = try_encode_all(
+        input,
+        window_sz2,
+        lookahead_sz2,
+        recommended_read_sz(window_sz2),
+    )?;
+    Ok((compressed, window_sz2))
+}
+
+/// Create an encoder, Sink and Poll all of `input` through the encoder in `read_sz` chunks,
+/// and return the compressed bytes.
+///
+/// An empty `input` produces an empty stream, which `decode_all` turns back into an
+/// empty `Vec`.
+///
+/// Panics where `try_encode_all` returns an error, e.g. if the window and lookahead sizes
+/// are not accepted by `HeatshrinkEncoder::new` or if `read_sz` is 0.
+pub fn encode_all(input: &[u8], window_sz2: u8, lookahead_sz2: u8, read_sz: usize) -> Vec<u8> {
+    try_encode_all(input, window_sz2, lookahead_sz2, read_sz)
+        .unwrap_or_else(|e| panic!("Failed to encode: {e}"))
+}
+
+/// Encode `input` like `encode_all`, returning an error instead of panicking.
+///
+/// Returns `HeatshrinkError::InvalidParams` if the encoder cannot be created with the given
+/// sizes, `HeatshrinkError::ZeroReadSize` if `read_sz` is 0, and
+/// `HeatshrinkError::InvalidState` if a `runtime-checks` invariant fails.
+pub fn try_encode_all(
+    input: &[u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> Result<Vec<u8>, HeatshrinkError> {
+    let mut encoder =
+        HeatshrinkEncoder::new(window_sz2, lookahead_sz2).ok_or(HeatshrinkError::InvalidParams)?;
+    // The output never exceeds the bound, so it is never reallocated while encoding
+    let mut compressed =
+        Vec::with_capacity(max_compressed_len(input.len(), window_sz2, lookahead_sz2));
+    encode_with(&mut encoder, input, read_sz, |polled| {
+        compressed.extend(polled)
+    })?;
+    compressed.shrink_to_fit();
+    Ok(compressed)
+}
+
+/// Bytes pulled from the iterator per batch in `encode_from_iter`
+const ENCODE_FROM_ITER_BATCH: usize = 4096;
+
+/// Encode the bytes of `iter` like `encode_all`, pulling them in 4 KiB batches so that
+/// lazily produced input is never collected as a whole.
+///
+/// Panics if the window and lookahead sizes are invalid.
+pub fn encode_from_iter(
+    iter: impl IntoIterator<Item = u8>,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+) -> Vec<u8> {
+    let mut encoder =
+        HeatshrinkEncoder::new(window_sz2, lookahead_sz2).expect("Failed to create encoder");
+    let mut iter = iter.into_iter();
+    let mut batch = Vec::with_capacity(ENCODE_FROM_ITER_BATCH);
+    let mut compressed = vec![];
+    loop {
+        batch.clear();
+        batch.extend(iter.by_ref().take(ENCODE_FROM_ITER_BATCH));
+        if batch.is_empty() {
+            break;
+        }
+        encoder
+            .compress_chunk(&batch, &mut compressed)
+            .expect("Encoder invariant failed");
+    }
+    encoder
+        .finalize(&mut compressed)
+        .expect("Encoder invariant failed");
+    compressed
+}
+
+/// Encode `input` like `encode_all`, also returning the exact number of significant bits in
+/// the stream, so that streams can be packed together at the bit level. Only the last byte
+/// holds padding, so the count is above `8 * (len - 1)` for a non-empty stream.
+///
+/// Panics like `encode_all`.
+pub fn encode_all_bits(
+    input: &[u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> (Vec<u8>, u64) {
+    let mut encoder =
+        HeatshrinkEncoder::new(window_sz2, lookahead_sz2).expect("Failed to create encoder");
+    let mut compressed = vec![];
+    encode_with(&mut encoder, input, read_sz, |polled| {
+        compressed.extend(polled)
+    })
+    .unwrap_or_else(|e| panic!("Failed to encode: {e}"));
+    let bits = encoder.total_bits();
+    (compressed, bits)
+}
+
+/// Count the bytes `encode_all` would return, without keeping them, e.g. to survey the
+/// compression ratio of many inputs.
+///
+/// Panics like `encode_all`.
+pub fn compressed_len(input: &[u8], window_sz2: u8, lookahead_sz2: u8, read_sz: usize) -> usize {
+    let mut encoder =
+        HeatshrinkEncoder::new(window_sz2, lookahead_sz2).expect("Failed to create encoder");
+    let mut len = 0;
+    encode_with(&mut encoder, input, read_sz, |polled| len += polled.len())
+        .unwrap_or_else(|e| panic!("Failed to encode: {e}"));
+    len
+}
+
+/// Number of entries in the histogram of `encode_all_with_histogram`, enough to index every
+/// match length up to a `lookahead_sz2` of 8
+pub const MATCH_HISTOGRAM_LEN: usize = (1 << 8) + 1;
+
+/// Encode `input` like `encode_all`, and count the backreferences of each length, e.g. to
+/// see whether a larger lookahead would find longer matches.
+///
+/// Entry `n` of the histogram is the number of matches of length `n`. Matches longer than
+/// `MATCH_HISTOGRAM_LEN - 1`, only possible with a `lookahead_sz2` above 8, are counted in
+/// the last entry.
+///
+/// Panics like `encode_all`.
+#[cfg(feature = "std")]
+pub fn encode_all_with_histogram(
+    input: &[u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+) -> (Vec<u8>, [u32; MATCH_HISTOGRAM_LEN]) {
+    use std::sync::{Arc, Mutex};
 
-            read_offset += read_len;
+    let histogram = Arc::new(Mutex::new([0; MATCH_HISTOGRAM_LEN]));
+    let mut encoder =
+        HeatshrinkEncoder::new(window_sz2, lookahead_sz2).expect("Failed to create encoder");
+    let observed = Arc::clone(&histogram);
+    encoder.set_token_observer(move |token| {
+        if let Token::Backref { length, .. } = token {
+            observed.lock().unwrap()[length.min(MATCH_HISTOGRAM_LEN - 1)] += 1;
         }
+    });
+    let mut compressed = vec![];
+    encode_with(
+        &mut encoder,
+        input,
+        recommended_read_sz(window_sz2),
+        |polled| compressed.extend(polled),
+    )
+    .unwrap_or_else(|e| panic!("Failed to encode: {e}"));
+    drop(encoder);
+    let histogram = *histogram.lock().unwrap();
+    (compressed, histogram)
+}
 
-        // Poll out the remaining bytes
-        loop {
-            match encoder.finish() {
-                HSEFinishRes::Done => {
-                    break;
+/// Run `input` through a fresh `encoder` like `encode_all`, passing each polled chunk of the
+/// compressed stream to `emit`
+fn encode_with(
+    encoder: &mut HeatshrinkEncoder,
+    input: &[u8],
+    read_sz: usize,
+    mut emit: impl FnMut(&[u8]),
+) -> Result<(), HeatshrinkError> {
+    if read_sz == 0 {
+        return Err(HeatshrinkError::ZeroReadSize);
+    }
+    let mut scratch: Vec<u8> = vec![0; read_sz * 2];
+    let mut read_offset = 0;
+
+    // Sink all bytes from the input buffer
+    while read_offset < input.len() {
+        let read_len = if input.len() - read_offset > read_sz {
+            read_sz
+        } else {
+            input.len() - read_offset
+        };
+        let mut read_data = &input[read_offset..read_offset + read_len];
+        while !read_data.is_empty() {
+            let sink_res = encoder.sink(read_data);
+            match sink_res {
+                HSESinkRes::Ok(bytes_sunk) => {
+                    read_data = &read_data[bytes_sunk..];
                 }
-                HSEFinishRes::More => {}
-                HSEFinishRes::ErrorNull => unreachable!(),
+                _ => unreachable!(),
             }
 
             loop {
                 match encoder.poll(&mut scratch) {
                     HSEPollRes::Empty(sz) => {
-                        compressed.extend(&scratch[..sz]);
+                        emit(&scratch[..sz]);
                         break;
                     }
                     HSEPollRes::More(sz) => {
-                        compressed.extend(&scratch[..sz]);
+                        emit(&scratch[..sz]);
+                    }
+                    HSEPollRes::ErrorMisuse | HSEPollRes::ErrorNull | HSEPollRes::Aborted => {
+                        unreachable!()
                     }
-                    HSEPollRes::ErrorMisuse | HSEPollRes::ErrorNull => unreachable!(),
+                    HSEPollRes::ErrorUnknown => return Err(HeatshrinkError::InvalidState),
                 }
             }
         }
 
-        compressed
+        read_offset += read_len;
     }
 
-    fn decode_all(
-        input: &[u8],
-        input_buffer_size: usize,
-        window_sz2: u8,
-        lookahead_sz2: u8,
-        read_sz: usize,
-    ) -> Vec<u8> {
-        assert!(read_sz > 0, "read_sz must be greater than 0");
-        let mut decoder =
-            HeatshrinkDecoder::new(input_buffer_size as u16, window_sz2, lookahead_sz2)
-                .expect("Failed to create decoder");
-        let mut decompressed = vec![];
-        let mut scratch: Vec<u8> = vec![0; read_sz * 2];
-        let mut read_offset = 0;
-
-        // Sink all bytes from the input buffer
-        while read_offset < input.len() {
-            let read_len = if input.len() - read_offset > read_sz {
-                read_sz
-            } else {
-                input.len() - read_offset
-            };
-            let mut read_data = &input[read_offset..read_offset + read_len];
-            while !read_data.is_empty() {
-                let sink_res = decoder.sink(read_data);
-                match sink_res {
-                    HSDSinkRes::Ok(bytes_sunk) => {
-                        read_data = &read_data[bytes_sunk..];
-                    }
-                    _ => unreachable!(),
-                }
-
-                loop {
-                    match decoder.poll(&mut scratch) {
-                        HSDPollRes::Empty(sz) => {
-                            decompressed.extend(&scratch[..sz]);
-                            break;
-                        }
-                        HSDPollRes::More(sz) => {
-                            decompressed.extend(&scratch[..sz]);
-                        }
-                        HSDPollRes::ErrorNull => unreachable!(),
-                        e => panic!("Failed to poll data: {:?}", e),
-                    }
-                }
+    // Poll out the remaining bytes
+    loop {
+        match encoder.finish() {
+            HSEFinishRes::Done => {
+                return Ok(());
             }
-
-            read_offset += read_len;
+            HSEFinishRes::More => {}
+            HSEFinishRes::ErrorNull => unreachable!(),
+            HSEFinishRes::ErrorUnknown => return Err(HeatshrinkError::InvalidState),
         }
 
-        // Poll out the remaining bytes
         loop {
-            match decoder.finish() {
-                HSDFinishRes::Done => {
+            match encoder.poll(&mut scratch) {
+                HSEPollRes::Empty(sz) => {
+                    emit(&scratch[..sz]);
                     break;
                 }
-                HSDFinishRes::More => {}
-                HSDFinishRes::ErrorNull => unreachable!(),
+                HSEPollRes::More(sz) => {
+                    emit(&scratch[..sz]);
+                }
+                HSEPollRes::ErrorMisuse | HSEPollRes::ErrorNull | HSEPollRes::Aborted => {
+                    unreachable!()
+                }
+                HSEPollRes::ErrorUnknown => return Err(HeatshrinkError::InvalidState),
+            }
+        }
+    }
+}
+
+/// Create a decoder, Sink and Poll all of `input` through the decoder in `read_sz` chunks,
+/// and return the decompressed bytes.
+///
+/// An empty `input` decodes to an empty `Vec`. An `input_buffer_size` above `u16::MAX` is
+/// capped to it, as in every other entry point that takes one.
+///
+/// Returns `HeatshrinkError::ZeroReadSize` if `read_sz` is 0,
+/// `HeatshrinkError::InvalidParams` if the decoder cannot be created with the given sizes,
+/// and `HeatshrinkError::Corrupt` if the decoder fails while polling.
+///
+/// A backref to bytes that were never decoded copies from the zeroed window, as streams
+/// from the C encoder may rely on. Use `decode_all_strict` to reject it instead.
+pub fn decode_all(
+    input: &[u8],
+    input_buffer_size: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> Result<Vec<u8>, HeatshrinkError> {
+    decode_all_checked(
+        input,
+        input_buffer_size,
+        window_sz2,
+        lookahead_sz2,
+        read_sz,
+        false,
+    )
+}
+
+/// Decode `input` like `decode_all`, with strict backrefs: a backref to bytes that were
+/// never decoded is `HeatshrinkError::Corrupt` instead of decoding to zeros. Streams from
+/// this crate's encoder always pass, see `HeatshrinkDecoder::set_strict_backrefs`.
+pub fn decode_all_strict(
+    input: &[u8],
+    input_buffer_size: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> Result<Vec<u8>, HeatshrinkError> {
+    decode_all_checked(
+        input,
+        input_buffer_size,
+        window_sz2,
+        lookahead_sz2,
+        read_sz,
+        true,
+    )
+}
+
+/// `decode_all`, with or without strict backrefs
+fn decode_all_checked(
+    input: &[u8],
+    input_buffer_size: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+    strict_backrefs: bool,
+) -> Result<Vec<u8>, HeatshrinkError> {
+    if read_sz == 0 {
+        return Err(HeatshrinkError::ZeroReadSize);
+    }
+    let mut decoder = HeatshrinkDecoder::new(
+        input_buffer_size.min(u16::MAX as usize) as u16,
+        window_sz2,
+        lookahead_sz2,
+    )
+    .ok_or(HeatshrinkError::InvalidParams)?;
+    decoder.set_strict_backrefs(strict_backrefs);
+    let mut decompressed = vec![];
+    decode_with(&mut decoder, input, read_sz, |polled| {
+        decompressed.extend(polled)
+    })?;
+    Ok(decompressed)
+}
+
+/// Decode `blob` with strict backrefs, keeping only the decompressed length, to check that
+/// it is intact and that the claimed window and lookahead sizes are right.
+///
+/// Returns `HeatshrinkError::InvalidParams` if the decoder cannot be created with the given
+/// sizes, and `HeatshrinkError::Corrupt` if the decoder fails, including on a backref to
+/// bytes that were never decoded. Streams from the reference heatshrink encoder that start
+/// with `0x00` bytes may reference the zeroed window and fail, see
+/// `HeatshrinkDecoder::set_strict_backrefs`.
+///
+/// With the `literal-runs` feature, wrong sizes are not reliably caught: a misread backref
+/// whose count reads as 1 is a run header, which is valid with any index, and the stream
+/// may then decode to the end without an error. Compare the returned length against the
+/// expected one to detect wrong sizes in that mode.
+pub fn validate(
+    blob: &[u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    input_buffer_size: usize,
+) -> Result<usize, HeatshrinkError> {
+    let input_buffer_size = input_buffer_size.min(u16::MAX as usize);
+    let mut decoder = HeatshrinkDecoder::new(input_buffer_size as u16, window_sz2, lookahead_sz2)
+        .ok_or(HeatshrinkError::InvalidParams)?;
+    decoder.set_strict_backrefs(true);
+    let mut len = 0;
+    decode_with(&mut decoder, blob, input_buffer_size, |polled| {
+        len += polled.len()
+    })?;
+    Ok(len)
+}
+
+/// Input buffer and read size of `decode_verify`
+const DECODE_VERIFY_READ_SIZE: usize = 1024;
+
+/// Decode `input` like `decode_all`, passing each decoded chunk to `sink` instead of keeping
+/// it, and return the decompressed length, e.g. to check the digest of a large archive
+/// without holding its contents.
+///
+/// Returns `HeatshrinkError::InvalidParams` if the decoder cannot be created with the given
+/// sizes, and `HeatshrinkError::Corrupt` if the decoder fails. `sink` has seen the output up
+/// to the failure by then.
+pub fn decode_verify(
+    input: &[u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    mut sink: impl FnMut(&[u8]),
+) -> Result<u64, HeatshrinkError> {
+    let mut decoder =
+        HeatshrinkDecoder::new(DECODE_VERIFY_READ_SIZE as u16, window_sz2, lookahead_sz2)
+            .ok_or(HeatshrinkError::InvalidParams)?;
+    let mut len = 0;
+    decode_with(&mut decoder, input, DECODE_VERIFY_READ_SIZE, |polled| {
+        len += polled.len() as u64;
+        sink(polled);
+    })?;
+    Ok(len)
+}
+
+/// Bytes at the start of the data that `is_probably_heatshrink` decodes
+const SNIFF_LEN: usize = 256;
+
+/// Guess whether `data` is a heatshrink stream with the given window and lookahead sizes,
+/// e.g. to detect the format in a multi-codec pipeline, by checking that at most its first
+/// `SNIFF_LEN` bytes decode like `validate`, with strict backrefs.
+///
+/// Heatshrink has no magic number and any bytes decode to something, so this only rejects
+/// data that refers back to bytes before the start of the stream, which random bytes do
+/// within a few tokens. False positives are possible, mostly on short or mostly `0xFF`
+/// data, which reads as a run of literals. An empty `data` is an empty stream. Returns
+/// `false` if the sizes are invalid.
+pub fn is_probably_heatshrink(data: &[u8], window_sz2: u8, lookahead_sz2: u8) -> bool {
+    let probe = &data[..data.len().min(SNIFF_LEN)];
+    validate(probe, window_sz2, lookahead_sz2, SNIFF_LEN).is_ok()
+}
+
+/// Sink and Poll all of `input` through `decoder` like `decode_all`, passing each polled
+/// chunk of the decompressed bytes to `emit`
+fn decode_with(
+    decoder: &mut HeatshrinkDecoder,
+    input: &[u8],
+    read_sz: usize,
+    mut emit: impl FnMut(&[u8]),
+) -> Result<(), HeatshrinkError> {
+    let mut scratch: Vec<u8> = vec![0; read_sz * 2];
+    let mut read_offset = 0;
+
+    // Sink all bytes from the input buffer
+    while read_offset < input.len() {
+        let read_len = if input.len() - read_offset > read_sz {
+            read_sz
+        } else {
+            input.len() - read_offset
+        };
+        let mut read_data = &input[read_offset..read_offset + read_len];
+        while !read_data.is_empty() {
+            let sink_res = decoder.sink(read_data);
+            match sink_res {
+                HSDSinkRes::Ok(bytes_sunk) => {
+                    read_data = &read_data[bytes_sunk..];
+                }
+                _ => unreachable!(),
             }
 
             loop {
                 match decoder.poll(&mut scratch) {
                     HSDPollRes::Empty(sz) => {
-                        decompressed.extend(&scratch[..sz]);
+                        emit(&scratch[..sz]);
                         break;
                     }
                     HSDPollRes::More(sz) => {
-                        decompressed.extend(&scratch[..sz]);
+                        emit(&scratch[..sz]);
                     }
                     HSDPollRes::ErrorNull => unreachable!(),
-                    e => panic!("Failed to poll data: {:?}", e),
+                    HSDPollRes::ErrorUnknown => return Err(HeatshrinkError::Corrupt),
                 }
             }
         }
 
-        decompressed
+        read_offset += read_len;
     }
 
-    fn roundtrip(
-        input: &[u8],
-        window_sz2: u8,
-        lookahead_sz2: u8,
-        in_read_sz: usize,
-        out_read_sz: usize,
-        out_buffer_sz: usize,
-    ) -> (Vec<u8>, Vec<u8>) {
-        let compressed = encode_all(input, window_sz2, lookahead_sz2, in_read_sz);
-        let decompressed = decode_all(
-            &compressed,
-            out_buffer_sz,
-            window_sz2,
-            lookahead_sz2,
-            out_read_sz,
-        );
-        (compressed, decompressed)
+    // Poll out the remaining bytes
+    loop {
+        match decoder.finish() {
+            HSDFinishRes::Done => {
+                break;
+            }
+            HSDFinishRes::More => {}
+            HSDFinishRes::ErrorNull => unreachable!(),
+            HSDFinishRes::ErrorUnknown => return Err(HeatshrinkError::Corrupt),
+        }
+
+        loop {
+            match decoder.poll(&mut scratch) {
+                HSDPollRes::Empty(sz) => {
+                    emit(&scratch[..sz]);
+                    break;
+                }
+                HSDPollRes::More(sz) => {
+                    emit(&scratch[..sz]);
+                }
+                HSDPollRes::ErrorNull => unreachable!(),
+                HSDPollRes::ErrorUnknown => return Err(HeatshrinkError::Corrupt),
+            }
+        }
     }
 
-    #[test]
-    fn end2end_sanity_mock() {
-        let input_data: Vec<u8> = (0..100).flat_map(|x| vec![x; 10]).collect();
-        println!(
-            "Input {} bytes: {:02X?}",
-            input_data.len(),
-            input_data.as_slice()
-        );
+    Ok(())
+}
 
-        // Encode
-        let compressed = encode_all(&input_data, 8, 4, 16);
+/// Decode all of `input` into `out` without allocating, using `arena` as the decoder's
+/// working memory, and return the number of bytes written to `out`.
+///
+/// `arena` must hold the `1 << window_sz2` byte window plus an input buffer, so
+/// `(1 << window_sz2) + input_buffer_size` bytes, see `HeatshrinkDecoder::new_in`.
+///
+/// Returns `HeatshrinkError::InvalidParams` if the decoder cannot be created in `arena`,
+/// `HeatshrinkError::OutputTooSmall` if the decompressed data does not fit in `out`, and
+/// `HeatshrinkError::Corrupt` if the decoder fails while polling.
+pub fn decode_into(
+    input: &[u8],
+    out: &mut [u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    arena: &mut [u8],
+) -> Result<usize, HeatshrinkError> {
+    let mut decoder = HeatshrinkDecoder::new_in(arena, window_sz2, lookahead_sz2)
+        .ok_or(HeatshrinkError::InvalidParams)?;
+    decode_slice_into(&mut decoder, input, out)
+}
 
-        println!(
-            "Wrote {} bytes: {:02X?}",
-            compressed.len(),
-            compressed.as_slice()
-        );
+/// Input buffer size of the decoder that `decode_all_into` allocates
+const DECODE_ALL_INTO_INPUT_SIZE: u16 = 64;
 
-        // Decode
-        let decompressed = decode_all(&compressed, 100, 8, 4, 16);
+/// Decode all of `input` into `out`, returning the number of bytes written, like
+/// `decode_into` but without an arena. Only the decoder's working memory is allocated, the
+/// `1 << window_sz2` byte window and a small input buffer, and never the output.
+///
+/// Returns `HeatshrinkError::InvalidParams` if the decoder cannot be created with the given
+/// sizes, `HeatshrinkError::OutputTooSmall` if `out` fills before the stream ends, and
+/// `HeatshrinkError::Corrupt` if the decoder fails while polling.
+pub fn decode_all_into(
+    input: &[u8],
+    out: &mut [u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+) -> Result<usize, HeatshrinkError> {
+    let mut decoder = HeatshrinkDecoder::new(DECODE_ALL_INTO_INPUT_SIZE, window_sz2, lookahead_sz2)
+        .ok_or(HeatshrinkError::InvalidParams)?;
+    decode_slice_into(&mut decoder, input, out)
+}
 
-        println!(
-            "Read {} bytes: {:02X?}",
-            decompressed.len(),
-            decompressed.as_slice()
-        );
+/// Sink all of `input` into a fresh `decoder` and finish it, polling into `out`, and return
+/// the number of bytes written.
+fn decode_slice_into<S: AsRef<[u8]> + AsMut<[u8]>>(
+    decoder: &mut HeatshrinkDecoder<S>,
+    input: &[u8],
+    out: &mut [u8],
+) -> Result<usize, HeatshrinkError> {
+    let mut written = 0;
 
-        // Check
-        for i in 0..input_data.len() {
-            if i >= decompressed.len() {
-                assert_eq!(input_data[i], 0, "{}: {} == {}", i, input_data[i], "EOF");
-                continue;
+    // Sink all bytes from the input buffer
+    let mut read_data = input;
+    while !read_data.is_empty() {
+        match decoder.sink(read_data) {
+            HSDSinkRes::Ok(bytes_sunk) => {
+                read_data = &read_data[bytes_sunk..];
             }
-            assert_eq!(
-                input_data[i], decompressed[i],
-                "{}: {} == {}",
-                i, input_data[i], decompressed[i]
-            );
+            _ => unreachable!(),
         }
+        poll_into_slice(decoder, out, &mut written)?;
     }
 
-    /// Configuration used to track the compression configurations
-    #[derive(Debug, Clone, Copy)]
-    #[allow(dead_code)] // used by Debug
-    struct RoundtripConfig {
-        window_sz2: u8,
-        lookahead_sz2: u8,
-        in_read_sz: usize,
-        out_read_sz: usize,
-        out_buffer_sz: usize,
-        file_name: &'static str,
-        compressed_size: usize,
-        compression_ratio: f32,
-        compression_time_us: usize,
+    // Poll out the remaining bytes
+    loop {
+        match decoder.finish() {
+            HSDFinishRes::Done => {
+                break;
+            }
+            HSDFinishRes::More => {}
+            HSDFinishRes::ErrorNull => unreachable!(),
+            HSDFinishRes::ErrorUnknown => return Err(HeatshrinkError::Corrupt),
+        }
+        poll_into_slice(decoder, out, &mut written)?;
     }
 
-    #[test]
-    fn end2end_sanity_param_sweep() {
-        // Compress several different types of files from B to KB to MB
-        let text_data = include_bytes!("heatshrink_encoder.rs");
-        let real_medium_size_data = include_bytes!("../tsz-compressed-data.bin");
-        let data: Vec<(&'static str, &[u8])> = vec![
-            ("heatshrink_encoder.rs", text_data),
-            ("tsz-compressed-data.bin", real_medium_size_data),
-        ];
-
-        // Use all possible window and lookahead sizes
-        let window_lookahead_pairs = (HEATSHRINK_MIN_WINDOW_BITS..=HEATSHRINK_MAX_WINDOW_BITS)
-            .flat_map(|window_sz2| {
-                (HEATSHRINK_MIN_LOOKAHEAD_BITS..window_sz2)
-                    .map(move |lookahead_sz2| (window_sz2, lookahead_sz2))
-            });
-
-        // Use several different read and buffer sizes
-        let read_buffer_sizes = [1, 2, 512];
-        let read_size_pairs = read_buffer_sizes
-            .iter()
-            .flat_map(|&read_sz| {
-                read_buffer_sizes
-                    .iter()
-                    .map(move |&buf_sz| (read_sz, buf_sz))
-            })
-            .collect::<Vec<_>>();
+    Ok(written)
+}
 
-        // Use several different input buffer sizes to stress different code paths
-        let input_buffer_sizes = [1, 64, 8192];
+/// Decode `input` into exactly `expected_len` bytes, allocated once, e.g. when a manifest
+/// records the decompressed length. Decoding stops at `expected_len`, so whatever trailing
+/// padding would decode to is ignored.
+///
+/// Returns `HeatshrinkError::InvalidParams` if the decoder cannot be created with the given
+/// sizes, `HeatshrinkError::Corrupt` if the decoder fails, and
+/// `HeatshrinkError::UnexpectedEof` if the stream ends before `expected_len` bytes.
+pub fn decode_exact(
+    input: &[u8],
+    expected_len: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+) -> Result<Vec<u8>, HeatshrinkError> {
+    let input_buffer_size = recommended_read_sz(window_sz2).min(u16::MAX as usize);
+    let mut decoder = HeatshrinkDecoder::new(input_buffer_size as u16, window_sz2, lookahead_sz2)
+        .ok_or(HeatshrinkError::InvalidParams)?;
+    let mut decompressed = vec![0; expected_len];
+    let mut written = 0;
+    let mut read_data = input;
 
-        // Use rayon to run all the permutations in parallel
-        let mut configurations = vec![];
-        for (window_sz2, lookahead_sz2) in window_lookahead_pairs {
-            for (in_read_sz, out_read_sz) in read_size_pairs.iter() {
-                for out_buffer_sz in input_buffer_sizes.iter() {
-                    for data in data.iter() {
-                        configurations.push((
-                            window_sz2,
-                            lookahead_sz2,
-                            *in_read_sz,
-                            *out_read_sz,
-                            *out_buffer_sz,
-                            data,
-                        ));
-                    }
+    while written < expected_len {
+        if !read_data.is_empty() {
+            match decoder.sink(read_data) {
+                HSDSinkRes::Ok(bytes_sunk) => {
+                    read_data = &read_data[bytes_sunk..];
+                }
+                _ => unreachable!(),
+            }
+        } else {
+            match decoder.finish() {
+                HSDFinishRes::Done => return Err(HeatshrinkError::UnexpectedEof),
+                HSDFinishRes::More => {}
+                HSDFinishRes::ErrorNull => unreachable!(),
+                HSDFinishRes::ErrorUnknown => return Err(HeatshrinkError::Corrupt),
+            }
+        }
+
+        match decoder.poll(&mut decompressed[written..]) {
+            HSDPollRes::Empty(sz) | HSDPollRes::More(sz) => {
+                written += sz;
+            }
+            HSDPollRes::ErrorNull => unreachable!(),
+            HSDPollRes::ErrorUnknown => return Err(HeatshrinkError::Corrupt),
+        }
+    }
+    Ok(decompressed)
+}
+
+/// Poll the decoder into `out[*written..]` until it needs more input.
+fn poll_into_slice<S: AsRef<[u8]> + AsMut<[u8]>>(
+    decoder: &mut HeatshrinkDecoder<S>,
+    out: &mut [u8],
+    written: &mut usize,
+) -> Result<(), HeatshrinkError> {
+    loop {
+        if *written == out.len() {
+            // `out` is full, so any further output means it was too small
+            let mut probe = [0; 1];
+            return match decoder.poll(&mut probe) {
+                HSDPollRes::Empty(0) => Ok(()),
+                HSDPollRes::Empty(_) | HSDPollRes::More(_) => Err(HeatshrinkError::OutputTooSmall),
+                HSDPollRes::ErrorNull => unreachable!(),
+                HSDPollRes::ErrorUnknown => Err(HeatshrinkError::Corrupt),
+            };
+        }
+
+        match decoder.poll(&mut out[*written..]) {
+            HSDPollRes::Empty(sz) => {
+                *written += sz;
+                return Ok(());
+            }
+            HSDPollRes::More(sz) => {
+                *written += sz;
+            }
+            HSDPollRes::ErrorNull => unreachable!(),
+            HSDPollRes::ErrorUnknown => return Err(HeatshrinkError::Corrupt),
+        }
+    }
+}
+
+/// Encode `input` like `encode_all`, prefixed with the length of `input` as a LEB128 varint.
+///
+/// `decode_all_framed` uses the length to allocate the output once and to know exactly
+/// where the stream ends. Inputs shorter than 128 bytes add a single byte.
+pub fn encode_all_framed(
+    input: &[u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> Vec<u8> {
+    let mut framed = vec![];
+    write_varint(&mut framed, input.len());
+    framed.extend(encode_all(input, window_sz2, lookahead_sz2, read_sz));
+    framed
+}
+
+/// Decode a stream produced by `encode_all_framed` into a `Vec` allocated once with the
+/// framed length.
+///
+/// Returns `HeatshrinkError::InvalidParams` if the decoder cannot be created with the given
+/// sizes, and `HeatshrinkError::Corrupt` if the length prefix is malformed, is larger than
+/// the stream could possibly decode to, or does not match the decoded length.
+pub fn decode_all_framed(
+    input: &[u8],
+    input_buffer_size: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+) -> Result<Vec<u8>, HeatshrinkError> {
+    let mut decoder = HeatshrinkDecoder::new(
+        input_buffer_size.min(u16::MAX as usize) as u16,
+        window_sz2,
+        lookahead_sz2,
+    )
+    .ok_or(HeatshrinkError::InvalidParams)?;
+    let (len, prefix_len) = read_varint(input).ok_or(HeatshrinkError::Corrupt)?;
+    let input = &input[prefix_len..];
+
+    // Refuse to allocate for a length the stream cannot produce: a literal takes 9 bits,
+    // a backref takes 1 + window_sz2 + lookahead_sz2 bits, and neither emits more than
+    // 1 << lookahead_sz2 bytes
+    let min_token_bits = (1 + window_sz2 as usize + lookahead_sz2 as usize).min(9);
+    let max_len = (input.len() * 8 / min_token_bits).saturating_mul(1 << lookahead_sz2);
+    if len > max_len {
+        return Err(HeatshrinkError::Corrupt);
+    }
+
+    let mut decompressed = vec![0; len];
+    let mut written = 0;
+    let too_long = |e| match e {
+        HeatshrinkError::OutputTooSmall => HeatshrinkError::Corrupt,
+        e => e,
+    };
+
+    // Sink all bytes from the input buffer
+    let mut read_data = input;
+    while !read_data.is_empty() {
+        match decoder.sink(read_data) {
+            HSDSinkRes::Ok(bytes_sunk) => {
+                read_data = &read_data[bytes_sunk..];
+            }
+            _ => unreachable!(),
+        }
+        poll_into_slice(&mut decoder, &mut decompressed, &mut written).map_err(too_long)?;
+    }
+
+    // Poll out the remaining bytes
+    loop {
+        match decoder.finish() {
+            HSDFinishRes::Done => {
+                break;
+            }
+            HSDFinishRes::More => {}
+            HSDFinishRes::ErrorNull => unreachable!(),
+            HSDFinishRes::ErrorUnknown => return Err(HeatshrinkError::Corrupt),
+        }
+        poll_into_slice(&mut decoder, &mut decompressed, &mut written).map_err(too_long)?;
+    }
+
+    if written != len {
+        return Err(HeatshrinkError::Corrupt);
+    }
+    Ok(decompressed)
+}
+
+/// Encode each of `messages` as its own stream like `encode_all`, each prefixed with its
+/// compressed length as a LEB128 varint, so `decode_all_multi` can split them back apart.
+pub fn encode_all_multi<'a>(
+    messages: impl IntoIterator<Item = &'a [u8]>,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> Vec<u8> {
+    let mut framed = vec![];
+    for message in messages {
+        let compressed = encode_all(message, window_sz2, lookahead_sz2, read_sz);
+        write_varint(&mut framed, compressed.len());
+        framed.extend(compressed);
+    }
+    framed
+}
+
+/// Decode the messages of a stream produced by `encode_all_multi`, each like `decode_all`.
+///
+/// Returns the errors of `decode_all`, and `HeatshrinkError::Corrupt` if a length prefix is
+/// malformed or runs past the end of `input`.
+pub fn decode_all_multi(
+    input: &[u8],
+    input_buffer_size: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> Result<Vec<Vec<u8>>, HeatshrinkError> {
+    let mut messages = vec![];
+    let mut rest = input;
+    while !rest.is_empty() {
+        let (len, prefix_len) = read_varint(rest).ok_or(HeatshrinkError::Corrupt)?;
+        let compressed = rest[prefix_len..]
+            .get(..len)
+            .ok_or(HeatshrinkError::Corrupt)?;
+        messages.push(decode_all(
+            compressed,
+            input_buffer_size,
+            window_sz2,
+            lookahead_sz2,
+            read_sz,
+        )?);
+        rest = &rest[prefix_len + len..];
+    }
+    Ok(messages)
+}
+
+/// Concatenate two compressed streams without decompressing them, so that decoding the
+/// result gives the decompressed `a` followed by the decompressed `b`.
+///
+/// A stream ends with up to 7 zero bits that pad its last token to a byte, which a decoder
+/// would read as the start of a backref if `b` simply followed. Instead, the tokens of `b`
+/// are shifted in right after the last token of `a`, found by walking the tags of `a`, so
+/// neither stream has to end on a byte or flush boundary. The cost is a pass over the bits
+/// of both streams.
+///
+/// Backrefs count back from the current position, so those of `b` stay correct as long as
+/// none reaches in front of the start of `b`. This holds for streams from this crate's
+/// encoder, which never matches bytes it has not seen, unless it was primed with a
+/// dictionary. Both streams must be complete and use the same window and lookahead sizes.
+pub fn concat_compressed(a: &[u8], b: &[u8], window_sz2: u8, lookahead_sz2: u8) -> Vec<u8> {
+    let mut out = BitWriter::with_capacity(a.len() + b.len());
+    out.push_slice(a, token_bits(a, window_sz2, lookahead_sz2));
+    out.push_slice(b, token_bits(b, window_sz2, lookahead_sz2));
+    out.finish()
+}
+
+/// Number of bits in the complete tokens of `stream`, leaving out the zero bits that pad
+/// the last one to a byte. A backref takes at least 8 bits and a literal 9, so the padding
+/// is never mistaken for a token.
+fn token_bits(stream: &[u8], window_sz2: u8, lookahead_sz2: u8) -> usize {
+    let total = stream.len() * 8;
+    let bits = |pos: usize, count: u8| {
+        (pos..pos + count as usize).fold(0, |v, i| {
+            (v << 1) | ((stream[i / 8] >> (7 - i % 8)) & 1) as usize
+        })
+    };
+    let backref_len = 1 + window_sz2 as usize + lookahead_sz2 as usize;
+    let mut pos = 0;
+    while pos < total {
+        let len = if bits(pos, 1) == 1 {
+            9
+        } else if cfg!(feature = "literal-runs")
+            && pos + backref_len <= total
+            && bits(pos + 1 + window_sz2 as usize, lookahead_sz2) == 0
+        {
+            // a length 1 backref is the header of a run of raw literals
+            backref_len + 8 * (bits(pos + 1, window_sz2) + 1)
+        } else {
+            backref_len
+        };
+        if pos + len > total {
+            break;
+        }
+        pos += len;
+    }
+    pos
+}
+
+/// Encode `input` like `encode_all`, but if that is not smaller than `input`, return a copy
+/// of `input` instead, so the output never exceeds the input. The flag tells whether the
+/// bytes were compressed and must be stored alongside them for
+/// `decode_all_stored_fallback`.
+pub fn encode_all_stored_fallback(
+    input: &[u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> (Vec<u8>, bool) {
+    let compressed = encode_all(input, window_sz2, lookahead_sz2, read_sz);
+    if compressed.len() < input.len() {
+        (compressed, true)
+    } else {
+        (input.to_vec(), false)
+    }
+}
+
+/// Decode the output of `encode_all_stored_fallback` like `decode_all` if `was_compressed`,
+/// otherwise return a copy of the stored bytes.
+pub fn decode_all_stored_fallback(
+    data: &[u8],
+    was_compressed: bool,
+    input_buffer_size: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> Result<Vec<u8>, HeatshrinkError> {
+    if was_compressed {
+        decode_all(data, input_buffer_size, window_sz2, lookahead_sz2, read_sz)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+/// Encode the UTF-8 bytes of `s` like `encode_all`, e.g. for log lines.
+pub fn encode_str(s: &str, window_sz2: u8, lookahead_sz2: u8, read_sz: usize) -> Vec<u8> {
+    encode_all(s.as_bytes(), window_sz2, lookahead_sz2, read_sz)
+}
+
+/// Decode `input` like `decode_all` and check that the result is UTF-8 text.
+///
+/// Returns the errors of `decode_all`, and `HeatshrinkError::InvalidUtf8` if the decoded
+/// bytes are not valid UTF-8, which catches corrupt input and input that was not text.
+pub fn decode_to_string(
+    input: &[u8],
+    input_buffer_size: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> Result<String, HeatshrinkError> {
+    let decompressed = decode_all(input, input_buffer_size, window_sz2, lookahead_sz2, read_sz)?;
+    String::from_utf8(decompressed).map_err(|_| HeatshrinkError::InvalidUtf8)
+}
+
+/// Encode `input` like `encode_all`, then decode the result with a fresh decoder and
+/// compare it against `input` before returning it.
+///
+/// This roughly doubles the cost of encoding, so it is intended for one-time packaging
+/// (e.g. firmware images) where a latent encoder bug must not escape.
+///
+/// Returns the errors of `try_encode_all`, and `HeatshrinkError::RoundtripMismatch` if the
+/// decoded stream differs from `input`.
+pub fn encode_all_verified(
+    input: &[u8],
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> Result<Vec<u8>, HeatshrinkError> {
+    let compressed = try_encode_all(input, window_sz2, lookahead_sz2, read_sz)?;
+    verify_roundtrip(input, compressed, window_sz2, lookahead_sz2, read_sz)
+}
+
+/// Decode `compressed` and hand it back only if it reproduces `input` exactly.
+fn verify_roundtrip(
+    input: &[u8],
+    compressed: Vec<u8>,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    read_sz: usize,
+) -> Result<Vec<u8>, HeatshrinkError> {
+    let input_buffer_size = read_sz.min(u16::MAX as usize);
+    match decode_all(
+        &compressed,
+        input_buffer_size,
+        window_sz2,
+        lookahead_sz2,
+        read_sz,
+    ) {
+        Ok(decompressed) if decompressed == input => Ok(compressed),
+        _ => Err(HeatshrinkError::RoundtripMismatch),
+    }
+}
+
+/// Compress `input` with each `(window_sz2, lookahead_sz2)` of `candidates` and return the
+/// smallest output along with the window and lookahead that produced it, which the decoder
+/// needs. Ties go to the earliest candidate.
+///
+/// With the `parallel` feature, candidates are compressed in parallel with rayon.
+///
+/// Panics if `candidates` is empty or any of them is not accepted by `HeatshrinkEncoder::new`.
+pub fn encode_best(input: &[u8], candidates: &[(u8, u8)]) -> (Vec<u8>, u8, u8) {
+    #[cfg(feature = "parallel")]
+    use rayon::prelude::*;
+
+    let encode = |&(window_sz2, lookahead_sz2): &(u8, u8)| {
+        let compressed = encode_all(input, window_sz2, lookahead_sz2, ENCODE_BEST_READ_SZ);
+        (compressed, window_sz2, lookahead_sz2)
+    };
+
+    #[cfg(feature = "parallel")]
+    let best = candidates
+        .par_iter()
+        .map(encode)
+        .min_by_key(|(compressed, _, _)| compressed.len());
+    #[cfg(not(feature = "parallel"))]
+    let best = candidates
+        .iter()
+        .map(encode)
+        .min_by_key(|(compressed, _, _)| compressed.len());
+
+    best.expect("candidates must not be empty")
+}
+
+/// Read size `encode_best` sinks with, which does not change the output
+const ENCODE_BEST_READ_SZ: usize = 4096;
+
+#[cfg(test)]
+mod tests {
+    use rayon::prelude::*;
+
+    use super::*;
+
+    fn roundtrip(
+        input: &[u8],
+        window_sz2: u8,
+        lookahead_sz2: u8,
+        in_read_sz: usize,
+        out_read_sz: usize,
+        out_buffer_sz: usize,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let compressed = encode_all(input, window_sz2, lookahead_sz2, in_read_sz);
+        let decompressed = decode_all(
+            &compressed,
+            out_buffer_sz,
+            window_sz2,
+            lookahead_sz2,
+            out_read_sz,
+        )
+        .expect("Failed to decode");
+        (compressed, decompressed)
+    }
+
+    #[test]
+    fn end2end_sanity_mock() {
+        let input_data: Vec<u8> = (0..100).flat_map(|x| vec![x; 10]).collect();
+        println!(
+            "Input {} bytes: {:02X?}",
+            input_data.len(),
+            input_data.as_slice()
+        );
+
+        // Encode
+        let compressed = encode_all(&input_data, 8, 4, 16);
+
+        println!(
+            "Wrote {} bytes: {:02X?}",
+            compressed.len(),
+            compressed.as_slice()
+        );
+
+        // Decode
+        let decompressed = decode_all(&compressed, 100, 8, 4, 16).expect("Failed to decode");
+
+        println!(
+            "Read {} bytes: {:02X?}",
+            decompressed.len(),
+            decompressed.as_slice()
+        );
+
+        // Check
+        for i in 0..input_data.len() {
+            if i >= decompressed.len() {
+                assert_eq!(input_data[i], 0, "{}: {} == {}", i, input_data[i], "EOF");
+                continue;
+            }
+            assert_eq!(
+                input_data[i], decompressed[i],
+                "{}: {} == {}",
+                i, input_data[i], decompressed[i]
+            );
+        }
+    }
+
+    /// Numbered records that differ only slightly from their neighbors, long enough that
+    /// window 15 searches from buffer positions past `i16::MAX`
+    fn self_similar_records(len: usize) -> Vec<u8> {
+        let mut records = String::new();
+        let mut i = 0;
+        while records.len() < len {
+            records += &format!(
+                "record {:05} sensor {} value {:04}\n",
+                i,
+                i % 3,
+                i * 37 % 1000
+            );
+            i += 1;
+        }
+        records.truncate(len);
+        records.into_bytes()
+    }
+
+    #[test]
+    fn single_byte_roundtrip_all_params() {
+        // One literal and the padding of its last byte, which must not decode as more output
+        for window_sz2 in HEATSHRINK_MIN_WINDOW_BITS..=HEATSHRINK_MAX_WINDOW_BITS {
+            for lookahead_sz2 in HEATSHRINK_MIN_LOOKAHEAD_BITS..window_sz2 {
+                let mut encoder = HeatshrinkEncoder::new(window_sz2, lookahead_sz2)
+                    .expect("Failed to create encoder");
+                for byte in 0..=u8::MAX {
+                    encoder.reset();
+                    let mut compressed = vec![];
+                    encoder
+                        .compress_chunk(&[byte], &mut compressed)
+                        .expect("Failed to compress");
+                    encoder
+                        .finalize(&mut compressed)
+                        .expect("Failed to finalize");
+                    assert_eq!(compressed.len(), 2);
+                    for input_buffer_size in [1, 64] {
+                        let decompressed = decode_all(
+                            &compressed,
+                            input_buffer_size,
+                            window_sz2,
+                            lookahead_sz2,
+                            1,
+                        )
+                        .expect("Failed to decode");
+                        assert_eq!(
+                            decompressed,
+                            [byte],
+                            "w={window_sz2} l={lookahead_sz2} ibs={input_buffer_size}"
+                        );
+                    }
                 }
             }
         }
+    }
+
+    #[test]
+    fn window_15_self_similar_roundtrip() {
+        // With i16 search index links, positions past 32767 wrapped negative, so every
+        // window 15 search ended early and this input was stored as literals
+        let input = self_similar_records(48 * 1024);
+        for (in_read_sz, out_read_sz) in [(1, 512), (512, 1), (4096, 4096)] {
+            let (compressed, decompressed) =
+                roundtrip(&input, 15, 8, in_read_sz, out_read_sz, 4096);
+            assert_eq!(
+                decompressed, input,
+                "in_read_sz={in_read_sz} out_read_sz={out_read_sz}"
+            );
+            assert!(
+                compressed.len() < input.len() / 2,
+                "{} bytes for {} bytes of input",
+                compressed.len(),
+                input.len()
+            );
+        }
+    }
+
+    #[test]
+    fn end2end_sanity_param_sweep() {
+        // Compress text, incompressible, and real data, see benches/roundtrip.rs for timing.
+        // The real data is long enough to slide even the largest window.
+        let text_data = &include_bytes!("heatshrink_encoder.rs")[..16 * 1024];
+        let random_data = &include_bytes!("../random-data.bin")[..16 * 1024];
+        let real_data = &include_bytes!("../tsz-compressed-data.bin")[..40 * 1024];
+        let data: [&[u8]; 3] = [text_data, random_data, real_data];
 
-        println!("Running {} configurations", configurations.len());
-        let t0 = Instant::now();
+        // Use all possible window sizes with lookaheads up to 1 KiB. Larger lookaheads shift
+        // and re-index the whole buffer for nearly every byte at the end of the input, which
+        // takes minutes in a debug build, so they only run on a short slice below.
+        let window_lookahead_pairs = (HEATSHRINK_MIN_WINDOW_BITS..=HEATSHRINK_MAX_WINDOW_BITS)
+            .flat_map(|window_sz2| {
+                (HEATSHRINK_MIN_LOOKAHEAD_BITS..window_sz2.min(11))
+                    .map(move |lookahead_sz2| (window_sz2, lookahead_sz2))
+            });
+
+        // Use tiny and large reads on each side to stress different code paths
+        let read_size_pairs = [(1, 512), (512, 1), (512, 512)];
+
+        // Use several different input buffer sizes to stress different code paths
+        let input_buffer_sizes = [1, 64, 8192];
 
-        let results: Vec<RoundtripConfig> = configurations
-            .into_par_iter()
-            .map(
-                |(window_sz2, lookahead_sz2, in_read_sz, out_read_sz, out_buffer_sz, data)| {
-                    // Run the roundtrip configuration several times to get an average
-                    let mut compression_ratio = 0.0;
-                    let mut elapsed_us = 0;
-                    let mut compressed_len = 0;
-                    const ITERS: usize = 5;
-                    for i in 0..ITERS {
-                        let t0 = Instant::now();
-                        let (compressed, decompressed) = roundtrip(
-                            data.1,
+        let mut configurations = vec![];
+        for (window_sz2, lookahead_sz2) in window_lookahead_pairs {
+            // Reads of one and two bytes on both sides, with a single input buffer size
+            for (in_read_sz, out_read_sz) in [(1, 1), (2, 2)] {
+                for data in data {
+                    configurations.push((
+                        window_sz2,
+                        lookahead_sz2,
+                        in_read_sz,
+                        out_read_sz,
+                        64,
+                        data,
+                    ));
+                }
+            }
+            for (in_read_sz, out_read_sz) in read_size_pairs {
+                for out_buffer_sz in input_buffer_sizes {
+                    for data in data {
+                        configurations.push((
                             window_sz2,
                             lookahead_sz2,
                             in_read_sz,
                             out_read_sz,
                             out_buffer_sz,
-                        );
-                        let t1 = Instant::now();
-                        let elapsed = t1 - t0;
-                        elapsed_us += elapsed.as_micros();
-                        compression_ratio = data.1.len() as f32 / compressed.len() as f32;
-                        if i == 0 {
-                            compressed_len = compressed.len();
-                        }
-                        assert_eq!(compressed_len, compressed.len());
-                        assert_eq!(data.1, decompressed.as_slice());
+                            data,
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Still cover every larger lookahead once per window, on a slice short enough that
+        // the shifting at the end of the input stays cheap
+        let large_lookahead_pairs = (12..=HEATSHRINK_MAX_WINDOW_BITS).flat_map(|window_sz2| {
+            (11..window_sz2).map(move |lookahead_sz2| (window_sz2, lookahead_sz2))
+        });
+        for (window_sz2, lookahead_sz2) in large_lookahead_pairs {
+            for data in [&text_data[..2048], &real_data[..2048]] {
+                configurations.push((window_sz2, lookahead_sz2, 512, 512, 64, data));
+            }
+        }
+
+        // Use rayon to run all the permutations in parallel
+        configurations.into_par_iter().for_each(
+            |(window_sz2, lookahead_sz2, in_read_sz, out_read_sz, out_buffer_sz, data)| {
+                let (compressed, decompressed) = roundtrip(
+                    data,
+                    window_sz2,
+                    lookahead_sz2,
+                    in_read_sz,
+                    out_read_sz,
+                    out_buffer_sz,
+                );
+                assert!(compressed.len() <= max_compressed_len(data.len(), window_sz2, lookahead_sz2));
+                assert_eq!(
+                    data,
+                    decompressed.as_slice(),
+                    "w={window_sz2} l={lookahead_sz2} in_read_sz={in_read_sz} out_read_sz={out_read_sz} out_buffer_sz={out_buffer_sz}"
+                );
+                // The largest windows search from buffer positions past i16::MAX, where a
+                // broken search index silently finds no matches and text stops compressing
+                if window_sz2 >= 15 && core::ptr::eq(data, text_data) {
+                    assert!(
+                        compressed.len() < data.len() / 2,
+                        "w={window_sz2} l={lookahead_sz2} compressed text to {} of {} bytes",
+                        compressed.len(),
+                        data.len()
+                    );
+                }
+            },
+        );
+    }
+
+    /// Seed of `roundtrip_property`, override with `HEATSHRINK_PROPERTY_SEED` to explore
+    /// other inputs
+    const PROPERTY_SEED: u64 = 0x6865_6174_7368_726b;
+
+    /// Deterministic xorshift64* generator for property tests
+    struct Rng(u64);
+
+    impl Rng {
+        fn new(seed: u64) -> Self {
+            // xorshift gets stuck at 0
+            Rng(seed | 1)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        /// uniform in `range`, up to a negligible bias
+        fn range(&mut self, range: core::ops::RangeInclusive<usize>) -> usize {
+            range.start() + (self.next_u64() % (range.end() - range.start() + 1) as u64) as usize
+        }
+    }
+
+    /// Random input of random length, from one of several shapes that stress literals,
+    /// short and long backrefs, and runs of a single byte
+    fn random_input(rng: &mut Rng) -> Vec<u8> {
+        let len = match rng.range(0..=3) {
+            0 => rng.range(0..=16),
+            1 => rng.range(0..=512),
+            _ => rng.range(0..=4096),
+        };
+        let mut input = Vec::with_capacity(len);
+        let alphabet = [1, 2, 4, 16, 256][rng.range(0..=4)];
+        while input.len() < len {
+            match rng.range(0..=3) {
+                // copy from earlier in the input, near or far
+                0 if !input.is_empty() => {
+                    let distance = rng.range(1..=input.len());
+                    let copy_len = rng.range(1..=300).min(len - input.len());
+                    for _ in 0..copy_len {
+                        input.push(input[input.len() - distance]);
                     }
-                    let config = RoundtripConfig {
+                }
+                1 => {
+                    let byte = rng.range(0..=alphabet - 1) as u8;
+                    let run_len = rng.range(1..=100).min(len - input.len());
+                    input.resize(input.len() + run_len, byte);
+                }
+                _ => input.push(rng.range(0..=alphabet - 1) as u8),
+            }
+        }
+        input
+    }
+
+    /// Remove chunks of `input`, then zero its bytes, for as long as `fails` still holds,
+    /// down to an input where removing any single byte makes it pass
+    fn shrink(mut input: Vec<u8>, fails: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+        let mut chunk = input.len().div_ceil(2);
+        while chunk > 0 {
+            let mut shrunk = false;
+            let mut i = 0;
+            while i + chunk <= input.len() {
+                let candidate = [&input[..i], &input[i + chunk..]].concat();
+                if fails(&candidate) {
+                    input = candidate;
+                    shrunk = true;
+                } else {
+                    i += chunk;
+                }
+            }
+            if !shrunk {
+                chunk /= 2;
+            }
+        }
+        for i in 0..input.len() {
+            if input[i] != 0 {
+                let mut candidate = input.clone();
+                candidate[i] = 0;
+                if fails(&candidate) {
+                    input = candidate;
+                }
+            }
+        }
+        input
+    }
+
+    #[test]
+    fn shrink_finds_minimal_input() {
+        let fails = |input: &[u8]| input.windows(2).any(|w| w == [7, 9]);
+        let input: Vec<u8> = (0..200).chain([7, 9]).chain(0..50).collect();
+        assert_eq!(shrink(input, fails), [7, 9]);
+        assert_eq!(shrink(vec![3, 1, 4], |input| input.len() >= 2), [0, 0]);
+    }
+
+    #[test]
+    fn roundtrip_property() {
+        // Encode and decode random inputs with random sizes across the whole parameter
+        // range, the inputs are short enough that even the largest lookaheads are quick
+        let seed = std::env::var("HEATSHRINK_PROPERTY_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or(PROPERTY_SEED);
+        let mut rng = Rng::new(seed);
+        for case in 0..1000 {
+            let window_sz2 = rng
+                .range(HEATSHRINK_MIN_WINDOW_BITS as usize..=HEATSHRINK_MAX_WINDOW_BITS as usize)
+                as u8;
+            let lookahead_sz2 =
+                rng.range(HEATSHRINK_MIN_LOOKAHEAD_BITS as usize..=window_sz2 as usize - 1) as u8;
+            let in_read_sz = rng.range(1..=600);
+            let out_read_sz = rng.range(1..=600);
+            let input_buffer_size = rng.range(1..=1024);
+            let input = random_input(&mut rng);
+
+            let roundtrips = |input: &[u8]| {
+                std::panic::catch_unwind(|| {
+                    let compressed = encode_all(input, window_sz2, lookahead_sz2, in_read_sz);
+                    decode_all(
+                        &compressed,
+                        input_buffer_size,
                         window_sz2,
                         lookahead_sz2,
-                        in_read_sz,
                         out_read_sz,
-                        out_buffer_sz,
-                        file_name: data.0,
-                        compressed_size: compressed_len,
-                        compression_ratio,
-                        compression_time_us: elapsed_us as usize / ITERS,
-                    };
-                    println!("{:?}", config);
-                    config
-                },
-            )
+                    ) == Ok(input.to_vec())
+                })
+                .unwrap_or(false)
+            };
+            if !roundtrips(&input) {
+                let minimal = shrink(input, |input| !roundtrips(input));
+                panic!(
+                    "seed {} case {}: w={} l={} in_read_sz={} out_read_sz={} \
+                     input_buffer_size={} failed for {} bytes: {:02X?}",
+                    seed,
+                    case,
+                    window_sz2,
+                    lookahead_sz2,
+                    in_read_sz,
+                    out_read_sz,
+                    input_buffer_size,
+                    minimal.len(),
+                    minimal
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn progress_fires_every_interval() {
+        let input: Vec<u8> = include_bytes!("lib.rs")
+            .iter()
+            .copied()
+            .cycle()
+            .take(100_000)
             .collect();
 
-        // Only print out results for real data
-        let mut results = results
-            .into_iter()
-            .filter(|r| r.file_name == "tsz-compressed-data.bin")
-            .collect::<Vec<_>>();
-        // Print top 3 and bottom 3 compression ratios
-        results.sort_by(|a, b| {
-            a.compression_ratio
-                .partial_cmp(&b.compression_ratio)
-                .unwrap()
+        let mut reports = vec![];
+        let mut compressed = vec![];
+        encode_with_progress(8, 4, &mut &input[..], &mut compressed, 10_000, |p| {
+            reports.push(p)
         });
-        println!("Bottom compression ratios:");
-        for i in 0..50 {
-            println!("WORST RATIO: {:?}", results[i]);
-        }
-        println!("Top compression ratios:");
-        for i in (results.len() - 50)..results.len() {
-            println!("BEST RATIO: {:?}", results[i]);
+        assert_eq!(reports.len(), 10);
+        for (i, p) in reports.iter().enumerate() {
+            assert!(p.bytes_in >= (i as u64 + 1) * 10_000);
+            assert!(p.bytes_in < (i as u64 + 1) * 10_000 + 1024);
+            assert!(p.bytes_out > 0 && p.bytes_out < p.bytes_in);
         }
+        assert!(reports.windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+        assert_eq!(reports[9].bytes_in, 100_000);
 
-        // Print top 3 and bottom 3 compression times
-        results.sort_by(|a, b| {
-            a.compression_time_us
-                .partial_cmp(&b.compression_time_us)
-                .unwrap()
+        let mut reports = vec![];
+        let mut decompressed = vec![];
+        decode_with_progress(8, 4, &mut &compressed[..], &mut decompressed, 2048, |p| {
+            reports.push(p)
         });
-        println!("Bottom 3 compression times:");
-        for i in (results.len() - 50)..results.len() {
-            println!("WORST TIME: {:?}", results[i]);
-        }
-        println!("Top compression times:");
-        for i in 0..50 {
-            println!("BEST TIME: {:?}", results[i]);
-        }
+        assert_eq!(decompressed, input);
+        assert_eq!(reports.len(), compressed.len() / 2048);
+        assert!(reports.iter().all(|p| p.bytes_out > p.bytes_in));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn histogram_counts_match_lengths() {
+        let input = b"abcd".repeat(100);
+        let (compressed, histogram) = encode_all_with_histogram(&input, 8, 4);
+        assert_eq!(compressed, encode_all(&input, 8, 4, 512));
+
+        // After the first period is emitted as literals, the input is copied in matches of
+        // the full 16 byte lookahead, and one shorter match for the 12 bytes left over
+        let matched: usize = histogram
+            .iter()
+            .enumerate()
+            .map(|(n, &c)| n * c as usize)
+            .sum();
+        assert_eq!(matched, input.len() - 4);
+        assert_eq!(histogram[16], 24);
+        assert_eq!(histogram[12], 1);
+        assert_eq!(histogram.iter().sum::<u32>(), 25);
+    }
+
+    #[test]
+    fn compressed_len_matches_encode_all() {
+        let text = include_bytes!("heatshrink_encoder.rs");
+        let random = &include_bytes!("../random-data.bin")[..4096];
+        let zeros = [0; 3000];
+        for input in [&text[..], random, &zeros, b"a", b""] {
+            for (window_sz2, lookahead_sz2, read_sz) in [(4, 3, 1), (8, 4, 64), (11, 6, 4096)] {
+                assert_eq!(
+                    compressed_len(input, window_sz2, lookahead_sz2, read_sz),
+                    encode_all(input, window_sz2, lookahead_sz2, read_sz).len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decode_exact_stops_at_expected_len() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 8, 4, 64);
+        assert_eq!(decode_exact(&compressed, input.len(), 8, 4).unwrap(), input);
+        assert_eq!(decode_exact(&compressed, 100, 8, 4).unwrap(), &input[..100]);
+        assert!(decode_exact(&compressed, 0, 8, 4).unwrap().is_empty());
+
+        // Zero bits decode to backrefs, which are ignored past the expected length
+        let padded = [&compressed[..], &[0; 8]].concat();
+        assert_ne!(
+            decode_all(&padded, 64, 8, 4, 64).ok().as_deref(),
+            Some(&input[..])
+        );
+        assert_eq!(decode_exact(&padded, input.len(), 8, 4).unwrap(), input);
+
+        assert_eq!(
+            decode_exact(&compressed[..compressed.len() / 2], input.len(), 8, 4),
+            Err(HeatshrinkError::UnexpectedEof)
+        );
+        assert_eq!(
+            decode_exact(&compressed, input.len() + 1, 8, 4),
+            Err(HeatshrinkError::UnexpectedEof)
+        );
+        assert_eq!(
+            decode_exact(&compressed, input.len(), 4, 4),
+            Err(HeatshrinkError::InvalidParams)
+        );
+    }
+
+    #[test]
+    fn validate_counts_and_rejects_corruption() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 8, 4, 64);
+        assert_eq!(validate(&compressed, 8, 4, 64), Ok(input.len()));
+        assert_eq!(validate(&[], 8, 4, 64), Ok(0));
+        assert_eq!(
+            validate(&compressed, 8, 4, 0),
+            Err(HeatshrinkError::InvalidParams)
+        );
+
+        // A backref at the start reaches before anything was decoded: tag 0, index 0 and
+        // count 3, a length of 4 that no mode reads as a literal run header
+        let corrupted = [0x00, 0x18];
+        assert_eq!(
+            validate(&corrupted, 8, 4, 64),
+            Err(HeatshrinkError::Corrupt)
+        );
+
+        // The wrong window size misreads the first backref. With `literal-runs` it may read
+        // as a run header instead, see `validate`, and only the length tells
+        #[cfg(not(feature = "literal-runs"))]
+        assert_eq!(
+            validate(&compressed, 12, 4, 64),
+            Err(HeatshrinkError::Corrupt)
+        );
+        assert_ne!(validate(&compressed, 12, 4, 64), Ok(input.len()));
+    }
+
+    #[test]
+    fn decode_all_strict_rejects_unwritten_window() {
+        let input = include_bytes!("lib.rs");
+        let compressed = encode_all(input, 8, 4, 64);
+        assert_eq!(decode_all_strict(&compressed, 64, 8, 4, 64).unwrap(), input);
+
+        // A length 4 backref before anything was decoded
+        let corrupted = [0x00, 0x18];
+        assert_eq!(decode_all(&corrupted, 64, 8, 4, 64), Ok(vec![0; 4]));
+        assert_eq!(
+            decode_all_strict(&corrupted, 64, 8, 4, 64),
+            Err(HeatshrinkError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn window_fits_budget() {
+        let min = HEATSHRINK_MIN_WINDOW_BITS;
+        let max = HEATSHRINK_MAX_WINDOW_BITS;
+
+        // The budget covers the encoder itself, not only its buffers
+        assert!(encoder_bytes(min) > encoder_buffer_bytes(min));
+
+        // A tiny budget that only holds the smallest encoder picks a window of that size,
+        // the smallest one unless `smallvec` keeps several windows inline
+        let smallest = largest_window_for_budget(encoder_bytes(min)).unwrap();
+        assert_eq!(encoder_bytes(smallest), encoder_bytes(min));
+        assert_eq!(largest_window_for_budget(encoder_bytes(min) - 1), None);
+        assert_eq!(largest_window_for_budget(0), None);
+
+        // A large budget picks the largest window, one just short of it the next one down
+        assert_eq!(largest_window_for_budget(usize::MAX), Some(max));
+        assert_eq!(
+            largest_window_for_budget(encoder_bytes(max) - 1),
+            Some(max - 1)
+        );
+
+        // Budgets between two encoder sizes pick the smaller window
+        for window_sz2 in min..max {
+            if encoder_bytes(window_sz2 + 1) > encoder_bytes(window_sz2) {
+                let budget = encoder_bytes(window_sz2 + 1) - 1;
+                assert_eq!(largest_window_for_budget(budget), Some(window_sz2));
+            }
+        }
+    }
+
+    #[test]
+    fn encode_within_budget_roundtrips() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        for budget in [
+            encoder_bytes(HEATSHRINK_MIN_WINDOW_BITS),
+            16 * 1024,
+            usize::MAX,
+        ] {
+            let (compressed, window_sz2) = encode_within_budget(input, budget).unwrap();
+            assert!(encoder_bytes(window_sz2) <= budget);
+            let lookahead_sz2 = budget_lookahead_sz2(window_sz2);
+            let decompressed = decode_all(&compressed, 64, window_sz2, lookahead_sz2, 64).unwrap();
+            assert_eq!(decompressed, input);
+        }
+        assert_eq!(
+            encode_within_budget(input, 1),
+            Err(HeatshrinkError::InvalidParams)
+        );
+    }
+
+    #[test]
+    fn decode_into_caller_buffers() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 8, 4, 64);
+
+        let mut arena = [0xAA; (1 << 8) + 64];
+        let mut out = vec![0; input.len()];
+        let written =
+            decode_into(&compressed, &mut out, 8, 4, &mut arena).expect("Failed to decode");
+        assert_eq!(written, input.len());
+        assert_eq!(&out[..], &input[..]);
+
+        // A window-sized arena leaves no room for the input buffer
+        let mut arena = [0; 1 << 8];
+        assert_eq!(
+            decode_into(&compressed, &mut out, 8, 4, &mut arena),
+            Err(HeatshrinkError::InvalidParams)
+        );
+    }
+
+    #[test]
+    fn decode_into_output_too_small() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 8, 4, 64);
+
+        let mut arena = [0; (1 << 8) + 64];
+        let mut out = vec![0; input.len() - 1];
+        assert_eq!(
+            decode_into(&compressed, &mut out, 8, 4, &mut arena),
+            Err(HeatshrinkError::OutputTooSmall)
+        );
+    }
+
+    #[test]
+    fn result_enums_key_sets() {
+        use std::collections::{HashMap, HashSet};
+
+        let input = &include_bytes!("lib.rs")[..4096];
+        let compressed = encode_all(input, 8, 4, 64);
+        let mut encoder = HeatshrinkEncoder::new(8, 4).unwrap();
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 4).unwrap();
+
+        // Count each pair of encoder and decoder poll results over lockstep chunks
+        let mut pairs: HashMap<(HSEPollRes, HSDPollRes), usize> = HashMap::new();
+        let mut sinks = HashSet::new();
+        let mut buf = [0; 512];
+        let mut steps = 0;
+        for (plain, packed) in input.chunks(64).zip(compressed.chunks(32)) {
+            sinks.insert((encoder.sink(plain), decoder.sink(packed)));
+            let polled = (encoder.poll(&mut buf), decoder.poll(&mut buf));
+            *pairs.entry(polled).or_default() += 1;
+            steps += 1;
+        }
+        assert!(sinks.contains(&(HSESinkRes::Ok(64), HSDSinkRes::Ok(32))));
+        assert!(pairs.len() > 1);
+        assert_eq!(pairs.values().sum::<usize>(), steps);
+
+        let finished: HashSet<_> = [(encoder.finish(), decoder.finish()); 2].into();
+        assert_eq!(
+            finished,
+            HashSet::from([(HSEFinishRes::More, HSDFinishRes::Done)])
+        );
+    }
+
+    #[test]
+    fn concat_compressed_decodes_both() {
+        let text = include_bytes!("lib.rs");
+        for (window_sz2, lookahead_sz2) in [(4, 3), (8, 4), (11, 6), (13, 12)] {
+            let enc = |x: &[u8]| encode_all(x, window_sz2, lookahead_sz2, 64);
+            let dec = |x: &[u8]| decode_all(x, 64, window_sz2, lookahead_sz2, 64).unwrap();
+            // cut at several lengths so that `a` ends at different bit offsets
+            for (a_len, b_len) in [
+                (0, 100),
+                (100, 0),
+                (1, 1),
+                (333, 4000),
+                (2048, 77),
+                (5001, 5000),
+            ] {
+                let (x, y) = (&text[..a_len], &text[1000..1000 + b_len]);
+                let joined = concat_compressed(&enc(x), &enc(y), window_sz2, lookahead_sz2);
+                assert_eq!(
+                    dec(&joined),
+                    [x, y].concat(),
+                    "w{window_sz2} l{lookahead_sz2} {a_len}+{b_len}"
+                );
+            }
+
+            // The result is a complete stream, so it can be extended again
+            let parts = [&text[..300], &text[5000..5300], &text[300..1000]];
+            let joined = parts.iter().fold(vec![], |acc, part| {
+                concat_compressed(&acc, &enc(part), window_sz2, lookahead_sz2)
+            });
+            assert_eq!(dec(&joined), parts.concat());
+        }
+    }
+
+    #[test]
+    fn max_compressed_len_presizes_without_reallocation() {
+        let text = include_bytes!("lib.rs");
+        let random = include_bytes!("../random-data.bin");
+        for input in [&text[..], random] {
+            let mut encoder = HeatshrinkEncoder::new(11, 6).expect("Failed to create encoder");
+            let mut compressed = Vec::with_capacity(max_compressed_len(input.len(), 11, 6));
+            let (ptr, capacity) = (compressed.as_ptr(), compressed.capacity());
+            encode_with(&mut encoder, input, 512, |polled| {
+                compressed.extend(polled);
+                assert_eq!(compressed.capacity(), capacity);
+            })
+            .unwrap();
+            assert_eq!(compressed.as_ptr(), ptr);
+
+            let encoded = encode_all(input, 11, 6, 512);
+            assert_eq!(encoded, compressed);
+            assert_eq!(encoded.capacity(), encoded.len());
+        }
+    }
+
+    #[test]
+    fn decode_verify_streams_to_crc() {
+        let input = include_bytes!("lib.rs");
+        let compressed = encode_all(input, 11, 6, 512);
+        let decoded = decode_all(&compressed, 512, 11, 6, 512).unwrap();
+
+        let mut digest = common::Crc32::new();
+        let len = decode_verify(&compressed, 11, 6, |chunk| digest.update(chunk)).unwrap();
+        assert_eq!(len, decoded.len() as u64);
+        assert_eq!(digest.finish(), common::crc32(&decoded));
+
+        assert_eq!(
+            decode_verify(&compressed, 4, 4, |_| {}),
+            Err(HeatshrinkError::InvalidParams)
+        );
+    }
+
+    #[test]
+    fn encode_from_iter_matches_encode_all() {
+        let input: Vec<u8> = (0..10000u32).map(|x| x as u8).collect();
+        assert_eq!(
+            encode_from_iter((0..10000u32).map(|x| x as u8), 8, 4),
+            encode_all(&input, 8, 4, 64)
+        );
+        let text = include_bytes!("lib.rs");
+        assert_eq!(
+            encode_from_iter(text.iter().copied(), 11, 6),
+            encode_all(text, 11, 6, 64)
+        );
+        assert_eq!(encode_from_iter(core::iter::empty(), 8, 4), b"");
+    }
+
+    #[test]
+    fn encode_all_bits_counts_significant_bits() {
+        let text = include_bytes!("lib.rs");
+        for (window_sz2, lookahead_sz2) in [(4, 3), (8, 4), (11, 6)] {
+            for len in [1, 2, 7, 100, 333, 4096] {
+                let input = &text[..len];
+                let (bytes, bits) = encode_all_bits(input, window_sz2, lookahead_sz2, 64);
+                assert_eq!(bytes, encode_all(input, window_sz2, lookahead_sz2, 64));
+                assert!(bits <= 8 * bytes.len() as u64);
+                assert!(bits > 8 * (bytes.len() as u64 - 1));
+                assert_eq!(
+                    bits as usize,
+                    token_bits(&bytes, window_sz2, lookahead_sz2),
+                    "w{window_sz2} l{lookahead_sz2} {len}"
+                );
+            }
+        }
+        assert_eq!(encode_all_bits(b"", 8, 4, 64), (vec![], 0));
+    }
+
+    #[test]
+    fn sniff_heatshrink() {
+        let text = include_bytes!("lib.rs");
+        for (window_sz2, lookahead_sz2) in [(4, 3), (8, 4), (11, 6), (15, 8)] {
+            let compressed = encode_all(text, window_sz2, lookahead_sz2, 64);
+            assert!(is_probably_heatshrink(
+                &compressed,
+                window_sz2,
+                lookahead_sz2
+            ));
+            assert!(is_probably_heatshrink(
+                &compressed[..10],
+                window_sz2,
+                lookahead_sz2
+            ));
+        }
+        assert!(is_probably_heatshrink(&[], 8, 4));
+        assert!(!is_probably_heatshrink(&[], 8, 8));
+
+        // Random bytes are rejected almost always, there is no magic number to be sure
+        let random = include_bytes!("../random-data.bin");
+        let accepted = random
+            .chunks_exact(SNIFF_LEN)
+            .take(200)
+            .filter(|chunk| is_probably_heatshrink(chunk, 8, 4))
+            .count();
+        assert!(accepted < 5, "{accepted} of 200 random chunks accepted");
+    }
+
+    #[test]
+    fn decode_all_into_slice_sizes() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 8, 4, 64);
+
+        let mut exact = vec![0; input.len()];
+        assert_eq!(
+            decode_all_into(&compressed, &mut exact, 8, 4),
+            Ok(input.len())
+        );
+        assert_eq!(&exact[..], &input[..]);
+
+        let mut short = vec![0; input.len() - 1];
+        assert_eq!(
+            decode_all_into(&compressed, &mut short, 8, 4),
+            Err(HeatshrinkError::OutputTooSmall)
+        );
+
+        let mut oversized = vec![0xAA; input.len() + 100];
+        assert_eq!(
+            decode_all_into(&compressed, &mut oversized, 8, 4),
+            Ok(input.len())
+        );
+        assert_eq!(&oversized[..input.len()], &input[..]);
+        assert!(oversized[input.len()..].iter().all(|&b| b == 0xAA));
+
+        assert_eq!(
+            decode_all_into(&compressed, &mut oversized, 8, 8),
+            Err(HeatshrinkError::InvalidParams)
+        );
+    }
+
+    #[test]
+    fn empty_input_roundtrip() {
+        for (window_sz2, lookahead_sz2) in [(4, 3), (8, 4), (11, 10), (15, 14)] {
+            let compressed = encode_all(&[], window_sz2, lookahead_sz2, 64);
+            assert!(compressed.is_empty());
+            let decompressed = decode_all(&compressed, 64, window_sz2, lookahead_sz2, 64)
+                .expect("Failed to decode");
+            assert!(decompressed.is_empty());
+
+            let mut arena = vec![0; (1 << window_sz2) + 64];
+            assert_eq!(
+                decode_into(&[], &mut [], window_sz2, lookahead_sz2, &mut arena),
+                Ok(0)
+            );
+
+            // A lone literal leaves 7 padding bits that must not decode to anything
+            let compressed = encode_all(b"a", window_sz2, lookahead_sz2, 64);
+            assert_eq!(compressed.len(), 2);
+            let decompressed = decode_all(&compressed, 64, window_sz2, lookahead_sz2, 64)
+                .expect("Failed to decode");
+            assert_eq!(decompressed, b"a");
+        }
+    }
+
+    #[test]
+    fn framed_roundtrip_presizes_output() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let framed = encode_all_framed(input, 9, 5, 128);
+        let (len, prefix_len) = read_varint(&framed).expect("Failed to read length");
+        assert_eq!(len, input.len());
+        assert_eq!(framed[prefix_len..], encode_all(input, 9, 5, 128));
+
+        let decompressed = decode_all_framed(&framed, 128, 9, 5).expect("Failed to decode");
+        assert_eq!(decompressed.len(), input.len());
+        assert_eq!(decompressed.capacity(), input.len());
+        assert_eq!(&decompressed[..], &input[..]);
+
+        // Small payloads add a single byte
+        let framed = encode_all_framed(b"abc", 9, 5, 128);
+        assert_eq!(framed[0], 3);
+        assert_eq!(framed.len(), 1 + encode_all(b"abc", 9, 5, 128).len());
+        assert_eq!(decode_all_framed(&framed, 128, 9, 5).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn framed_length_mismatch_is_corrupt() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let framed = encode_all_framed(input, 9, 5, 128);
+        let (_, prefix_len) = read_varint(&framed).expect("Failed to read length");
+
+        // Claim one byte less and one byte more than the stream holds
+        for len in [input.len() - 1, input.len() + 1] {
+            let mut reframed = vec![];
+            write_varint(&mut reframed, len);
+            assert_eq!(reframed.len(), prefix_len);
+            reframed.extend_from_slice(&framed[prefix_len..]);
+            assert_eq!(
+                decode_all_framed(&reframed, 128, 9, 5),
+                Err(HeatshrinkError::Corrupt)
+            );
+        }
+
+        // A huge length is rejected before allocating
+        let mut bomb = vec![];
+        write_varint(&mut bomb, usize::MAX);
+        bomb.extend_from_slice(&framed[prefix_len..]);
+        assert_eq!(
+            decode_all_framed(&bomb, 128, 9, 5),
+            Err(HeatshrinkError::Corrupt)
+        );
+        assert_eq!(
+            decode_all_framed(&[0x80], 128, 9, 5),
+            Err(HeatshrinkError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn multi_splits_messages() {
+        let messages: [&[u8]; 4] = [b"first", b"", b"second second second", b"third"];
+        let framed = encode_all_multi(messages, 8, 4, 64);
+        let decoded = decode_all_multi(&framed, 64, 8, 4, 64).unwrap();
+        assert_eq!(decoded, messages);
+
+        assert_eq!(decode_all_multi(&[], 64, 8, 4, 64).unwrap().len(), 0);
+        assert_eq!(
+            decode_all_multi(&framed[..framed.len() - 1], 64, 8, 4, 64),
+            Err(HeatshrinkError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn all_zeros_1mib_roundtrip() {
+        // Every position chains to the same byte value, the worst case for the search index,
+        // but the nearest candidate is already a full-length match and ends the search.
+        // Window 15 links positions past i16::MAX, which the search index once could not.
+        let zeros = vec![0u8; 1 << 20];
+        for (window_sz2, lookahead_sz2) in [(4, 3), (8, 4), (9, 7), (12, 6), (15, 8)] {
+            let compressed = encode_all(&zeros, window_sz2, lookahead_sz2, 4096);
+            assert!(compressed.len() < zeros.len() / 4);
+            let decompressed =
+                decode_all(&compressed, 4096, window_sz2, lookahead_sz2, 4096).unwrap();
+            assert_eq!(
+                decompressed, zeros,
+                "Failed for w{} l{}",
+                window_sz2, lookahead_sz2
+            );
+        }
+    }
+
+    #[test]
+    fn encode_all_verified_accepts_roundtrip() {
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let verified = encode_all_verified(input, 8, 4, 64).expect("Failed to verify");
+        assert_eq!(verified, encode_all(input, 8, 4, 64));
+    }
+
+    #[test]
+    fn encode_all_verified_rejects_corrupted_output() {
+        // Inject a fault in the encoded bytes to simulate a latent encoder bug
+        let input = include_bytes!("heatshrink_encoder.rs");
+        let mut compressed = encode_all(input, 8, 4, 64);
+        let mid = compressed.len() / 2;
+        compressed[mid] ^= 0xFF;
+        assert_eq!(
+            verify_roundtrip(input, compressed, 8, 4, 64),
+            Err(HeatshrinkError::RoundtripMismatch)
+        );
+    }
+
+    #[test]
+    fn poll_uninit_matches_poll() {
+        let input = include_bytes!("../tsz-compressed-data.bin");
+        const READ_SZ: usize = 512;
+
+        let expected = encode_all(input, 9, 7, READ_SZ);
+
+        // Encode straight into the spare capacity of the output Vec
+        let mut encoder = HeatshrinkEncoder::new(9, 7).expect("Failed to create encoder");
+        let mut compressed: Vec<u8> = Vec::new();
+        let poll_uninit = |encoder: &mut HeatshrinkEncoder, out: &mut Vec<u8>| loop {
+            out.reserve(READ_SZ * 2);
+            match encoder.poll_uninit(out.spare_capacity_mut()) {
+                HSEPollRes::Empty(sz) => {
+                    unsafe { out.set_len(out.len() + sz) };
+                    break;
+                }
+                HSEPollRes::More(sz) => unsafe { out.set_len(out.len() + sz) },
+                e => panic!("Failed to poll data: {:?}", e),
+            }
+        };
+        for chunk in input.chunks(READ_SZ) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                match encoder.sink(chunk) {
+                    HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                    e => panic!("Failed to sink data: {:?}", e),
+                }
+                poll_uninit(&mut encoder, &mut compressed);
+            }
+        }
+        while encoder.finish() == HSEFinishRes::More {
+            poll_uninit(&mut encoder, &mut compressed);
+        }
+        assert_eq!(expected, compressed);
+
+        // Decode straight into the spare capacity of the output Vec
+        let mut decoder =
+            HeatshrinkDecoder::new(READ_SZ as u16, 9, 7).expect("Failed to create decoder");
+        let mut decompressed: Vec<u8> = Vec::new();
+        let poll_uninit = |decoder: &mut HeatshrinkDecoder, out: &mut Vec<u8>| loop {
+            out.reserve(READ_SZ * 2);
+            match decoder.poll_uninit(out.spare_capacity_mut()) {
+                HSDPollRes::Empty(sz) => {
+                    unsafe { out.set_len(out.len() + sz) };
+                    break;
+                }
+                HSDPollRes::More(sz) => unsafe { out.set_len(out.len() + sz) },
+                e => panic!("Failed to poll data: {:?}", e),
+            }
+        };
+        for chunk in compressed.chunks(READ_SZ) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                match decoder.sink(chunk) {
+                    HSDSinkRes::Ok(sz) => chunk = &chunk[sz..],
+                    e => panic!("Failed to sink data: {:?}", e),
+                }
+                poll_uninit(&mut decoder, &mut decompressed);
+            }
+        }
+        while decoder.finish() == HSDFinishRes::More {
+            poll_uninit(&mut decoder, &mut decompressed);
+        }
+        assert_eq!(input, decompressed.as_slice());
+    }
+
+    #[test]
+    fn encode_best_is_smallest_candidate() {
+        let input = include_bytes!("../tsz-compressed-data.bin");
+        let candidates = [(8, 4), (10, 5), (12, 4), (5, 3)];
+
+        let (best, window_sz2, lookahead_sz2) = encode_best(input, &candidates);
+        for (w, l) in candidates {
+            assert!(best.len() <= encode_all(input, w, l, 512).len());
+        }
+        assert_eq!(best, encode_all(input, window_sz2, lookahead_sz2, 512));
+        let decompressed =
+            decode_all(&best, 512, window_sz2, lookahead_sz2, 512).expect("Failed to decode");
+        assert_eq!(decompressed, input);
+    }
+
+    #[cfg(feature = "wide-window")]
+    #[test]
+    fn wide_window_roundtrip() {
+        use std::sync::{Arc, Mutex};
+
+        // The repeat is 64 KiB back, only a 16 bit window can reach it
+        let random_data = include_bytes!("../random-data.bin");
+        let input = [&random_data[..], &random_data[..]].concat();
+
+        let max_distance = Arc::new(Mutex::new(0));
+        let observed = max_distance.clone();
+        let mut encoder = HeatshrinkEncoder::new(16, 8).expect("Failed to create encoder");
+        encoder.set_token_observer(move |token| {
+            if let Token::Backref { distance, .. } = token {
+                let mut max = observed.lock().unwrap();
+                *max = distance.max(*max);
+            }
+        });
+        let mut compressed = vec![];
+        encoder
+            .compress_chunk(&input, &mut compressed)
+            .expect("Failed to sink input");
+        encoder.finalize(&mut compressed).expect("Failed to finish");
+
+        assert_eq!(*max_distance.lock().unwrap(), 1 << 16);
+        assert_eq!(compressed, encode_all(&input, 16, 8, 512));
+        assert!(compressed.len() < encode_all(&input, 15, 8, 512).len() * 2 / 3);
+        for input_buffer_size in [1, 512, u16::MAX as usize] {
+            let decompressed =
+                decode_all(&compressed, input_buffer_size, 16, 8, 512).expect("Failed to decode");
+            assert_eq!(decompressed, input);
+        }
+        let decompressed = decode_all(&encode_all(&input, 16, 15, 512), 512, 16, 15, 512)
+            .expect("Failed to decode");
+        assert_eq!(decompressed, input);
+    }
+
+    #[cfg(feature = "literal-runs")]
+    #[test]
+    fn literal_runs_roundtrip() {
+        // Incompressible spans between repeats, so runs end at matches as well as at the
+        // end of the searchable input and at the longest run a header can describe
+        let random_data = include_bytes!("../random-data.bin");
+        let text = include_bytes!("heatshrink_decoder.rs");
+        let mixed: Vec<u8> = random_data
+            .chunks(700)
+            .zip(text.chunks(300))
+            .flat_map(|(random, text)| [random, text].concat())
+            .collect();
+        let encode_runs = |input: &[u8], window_sz2, lookahead_sz2, read_sz| {
+            let mut encoder = HeatshrinkEncoder::new(window_sz2, lookahead_sz2)
+                .expect("Failed to create encoder");
+            encoder.set_literal_runs(true);
+            let mut compressed = vec![];
+            encode_with(&mut encoder, input, read_sz, |polled| {
+                compressed.extend(polled)
+            })
+            .expect("Failed to encode");
+            compressed
+        };
+
+        for (window_sz2, lookahead_sz2) in [(4, 3), (8, 4), (9, 7), (12, 6), (15, 8)] {
+            // Runs are opt-in, without them each incompressible byte costs 9 bits
+            let standard = encode_all(random_data, window_sz2, lookahead_sz2, 512);
+            let compressed = encode_runs(random_data, window_sz2, lookahead_sz2, 512);
+            assert!(compressed.len() < random_data.len() * 9 / 8);
+            if window_sz2 >= 8 {
+                assert!(compressed.len() < random_data.len() * 102 / 100);
+                assert!(standard.len() > random_data.len() * 11 / 10);
+            }
+
+            for input in [&random_data[..], &mixed] {
+                for read_sz in [1, 7, 512] {
+                    let compressed = encode_runs(input, window_sz2, lookahead_sz2, read_sz);
+                    let decompressed =
+                        decode_all(&compressed, read_sz, window_sz2, lookahead_sz2, read_sz)
+                            .expect("Failed to decode");
+                    assert_eq!(decompressed, input);
+                }
+            }
+        }
+
+        // Resume an encoder and a decoder from their saved states in the middle of runs
+        let input = &mixed[..2000];
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        encoder.set_literal_runs(true);
+        let mut compressed = vec![];
+        let mut out = [0; 3];
+        let mut poll = |encoder: &mut HeatshrinkEncoder| loop {
+            *encoder =
+                HeatshrinkEncoder::load_state(&encoder.save_state()).expect("Failed to load state");
+            match encoder.poll(&mut out) {
+                HSEPollRes::Empty(sz) => {
+                    compressed.extend_from_slice(&out[..sz]);
+                    break;
+                }
+                HSEPollRes::More(sz) => compressed.extend_from_slice(&out[..sz]),
+                e => panic!("Failed to poll: {:?}", e),
+            }
+        };
+        for mut chunk in input.chunks(100) {
+            while !chunk.is_empty() {
+                match encoder.sink(chunk) {
+                    HSESinkRes::Ok(sz) => chunk = &chunk[sz..],
+                    e => panic!("Failed to sink data: {:?}", e),
+                }
+                poll(&mut encoder);
+            }
+        }
+        while encoder.finish() == HSEFinishRes::More {
+            poll(&mut encoder);
+        }
+        assert_eq!(compressed, encode_runs(input, 8, 4, 100));
+
+        let mut decoder = HeatshrinkDecoder::new(16, 8, 4).expect("Failed to create decoder");
+        decoder.set_strict_backrefs(true);
+        let mut decompressed = vec![];
+        let mut out = [0; 3];
+        for chunk in compressed.chunks(5) {
+            assert_eq!(decoder.sink(chunk), HSDSinkRes::Ok(chunk.len()));
+            loop {
+                decoder = HeatshrinkDecoder::load_state(&decoder.save_state())
+                    .expect("Failed to load state");
+                match decoder.poll(&mut out) {
+                    HSDPollRes::Empty(sz) => {
+                        decompressed.extend_from_slice(&out[..sz]);
+                        break;
+                    }
+                    HSDPollRes::More(sz) => decompressed.extend_from_slice(&out[..sz]),
+                    e => panic!("Failed to poll: {:?}", e),
+                }
+            }
+        }
+        assert_eq!(decoder.finish(), HSDFinishRes::Done);
+        assert_eq!(decompressed, input);
+    }
+
+    #[test]
+    fn large_input_buffer_sizes_are_capped() {
+        let input = include_bytes!("heatshrink_decoder.rs");
+        let compressed = encode_all(input, 8, 4, 512);
+        let framed = encode_all_framed(input, 8, 4, 512);
+        // Sizes past u16::MAX decode like u16::MAX instead of wrapping to a tiny buffer
+        for input_buffer_size in [u16::MAX as usize, 1 << 16, (1 << 16) + 1, usize::MAX] {
+            assert_eq!(
+                decode_all(&compressed, input_buffer_size, 8, 4, 512).as_deref(),
+                Ok(&input[..])
+            );
+            assert_eq!(
+                decode_all_strict(&compressed, input_buffer_size, 8, 4, 512).as_deref(),
+                Ok(&input[..])
+            );
+            assert_eq!(
+                validate(&compressed, 8, 4, input_buffer_size),
+                Ok(input.len())
+            );
+            assert_eq!(
+                decode_all_framed(&framed, input_buffer_size, 8, 4).as_deref(),
+                Ok(&input[..])
+            );
+        }
+    }
+
+    #[test]
+    fn zero_read_size_is_an_error() {
+        let compressed = encode_all(b"abcabcabc", 8, 4, recommended_read_sz(8));
+        assert_eq!(
+            decode_all(&compressed, 64, 8, 4, 0),
+            Err(HeatshrinkError::ZeroReadSize)
+        );
+        assert_eq!(
+            encode_all_verified(b"abcabcabc", 8, 4, 0),
+            Err(HeatshrinkError::ZeroReadSize)
+        );
+        assert_eq!(
+            try_encode_all(b"abcabcabc", 8, 4, 0),
+            Err(HeatshrinkError::ZeroReadSize)
+        );
+        assert_eq!(
+            try_encode_all(b"abcabcabc", 8, 8, 64),
+            Err(HeatshrinkError::InvalidParams)
+        );
+        assert_eq!(try_encode_all(b"abcabcabc", 8, 4, 1), Ok(compressed));
+        assert!(std::panic::catch_unwind(|| encode_all(b"abcabcabc", 8, 4, 0)).is_err());
+
+        assert_eq!(min_encoder_read_sz(8), 256);
+        assert_eq!(recommended_read_sz(8), 512);
+        assert_eq!(recommended_read_sz(12), 4096);
+    }
+
+    #[test]
+    fn stored_fallback_only_when_not_smaller() {
+        let text = &include_bytes!("heatshrink_encoder.rs")[..4096];
+        let random = &include_bytes!("../random-data.bin")[..4096];
+        for input in [text, random, b"a", b""] {
+            let compressed_len = encode_all(input, 8, 4, 512).len();
+            let (data, was_compressed) = encode_all_stored_fallback(input, 8, 4, 512);
+            assert_eq!(was_compressed, compressed_len < input.len());
+            assert!(data.len() <= input.len());
+            let decoded = decode_all_stored_fallback(&data, was_compressed, 512, 8, 4, 512)
+                .expect("Failed to decode");
+            assert_eq!(decoded, input);
+        }
+        assert!(encode_all_stored_fallback(text, 8, 4, 512).1);
+        assert!(!encode_all_stored_fallback(random, 8, 4, 512).1);
+    }
+
+    #[test]
+    fn str_roundtrip() {
+        let text = "boot ok\nsensor: 21.5 °C\nsensor: 21.5 °C\nsensor: 21.6 °C\n";
+        let compressed = encode_str(text, 8, 4, 16);
+        assert_eq!(
+            decode_to_string(&compressed, 16, 8, 4, 16),
+            Ok(text.to_string())
+        );
+    }
+
+    #[test]
+    fn decode_to_string_rejects_invalid_utf8() {
+        let compressed = encode_all(&[b'o', b'k', 0xff, 0xfe], 8, 4, 16);
+        assert_eq!(
+            decode_to_string(&compressed, 16, 8, 4, 16),
+            Err(HeatshrinkError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn max_compressed_len_bounds_random_data() {
+        let random_data = include_bytes!("../random-data.bin");
+        for len in [0, 1, 2, 7, 8, 9, 100, 1000, 4096] {
+            let input = &random_data[..len];
+            for (window_sz2, lookahead_sz2) in [(4, 3), (8, 4), (9, 7), (12, 11), (15, 14)] {
+                let compressed = encode_all(input, window_sz2, lookahead_sz2, 512);
+                let bound = max_compressed_len(len, window_sz2, lookahead_sz2);
+                assert!(
+                    compressed.len() <= bound,
+                    "{} > {} for len = {}, window = {}, lookahead = {}",
+                    compressed.len(),
+                    bound,
+                    len,
+                    window_sz2,
+                    lookahead_sz2
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dictionary_priming_shrinks_small_messages() {
+        let dict = br#"{"device_id":"","temperature":,"humidity":,"battery":}"#;
+        let message = br#"{"device_id":"sensor-7","temperature":21.5,"humidity":40,"battery":87}"#;
+
+        let encode_primed = |dict: &[u8]| {
+            let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+            assert_eq!(encoder.prime(dict), HSESinkRes::Ok(dict.len()));
+            let mut compressed = vec![];
+            assert_eq!(encoder.sink(message), HSESinkRes::Ok(message.len()));
+            encoder.poll_to(&mut compressed).expect("Failed to poll");
+            while encoder.finish() == HSEFinishRes::More {
+                encoder.poll_to(&mut compressed).expect("Failed to poll");
+            }
+            compressed
+        };
+        let unprimed = encode_primed(&[]);
+        let primed = encode_primed(dict);
+        assert_eq!(unprimed, encode_all(message, 8, 4, 512));
+        assert!(
+            primed.len() < unprimed.len(),
+            "{} >= {}",
+            primed.len(),
+            unprimed.len()
+        );
+
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+        assert_eq!(decoder.prime(dict), HSDSinkRes::Ok(dict.len()));
+        let mut decompressed = vec![];
+        for chunk in primed.chunks(64) {
+            assert_eq!(decoder.sink(chunk), HSDSinkRes::Ok(chunk.len()));
+            decoder
+                .poll_into(&mut decompressed)
+                .expect("Failed to poll");
+        }
+        decoder
+            .finalize(&mut decompressed)
+            .expect("Failed to finish");
+        assert_eq!(message, decompressed.as_slice());
+    }
+
+    #[test]
+    fn prime_after_sink_is_misuse() {
+        let mut encoder = HeatshrinkEncoder::new(8, 4).expect("Failed to create encoder");
+        assert_eq!(encoder.sink(b"abc"), HSESinkRes::Ok(3));
+        assert_eq!(encoder.prime(b"abc"), HSESinkRes::ErrorMisuse);
 
-        let t1 = Instant::now();
-        println!("Completed permutations in {:?}", t1 - t0);
+        let mut decoder = HeatshrinkDecoder::new(64, 8, 4).expect("Failed to create decoder");
+        assert_eq!(decoder.sink(b"abc"), HSDSinkRes::Ok(3));
+        assert_eq!(decoder.prime(b"abc"), HSDSinkRes::ErrorMisuse);
     }
 
     #[test]
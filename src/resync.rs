@@ -0,0 +1,155 @@
+//!
+//! Resync format for long-lived streams that may suffer bit errors.
+//!
+//! The input is split into `interval` byte segments that are compressed independently, like
+//! `encode_blocks`, so every segment starts with a fresh window. Each segment is stored as a
+//! record that starts with the byte-aligned `SYNC_MARKER`, followed by a CRC-32 and the
+//! segment's offset and length. When a record is damaged, the decoder scans forward to the
+//! next marker and resumes there, so only the damaged segment is lost.
+//!
+//! This is not part of the heatshrink format, standard decoders cannot read it.
+//!
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use crate::{
+    common::{crc32, read_varint, write_varint},
+    decode_all, encode_blocks,
+};
+
+/// Bytes that start every record of a resync stream
+pub const SYNC_MARKER: [u8; 4] = [0xA7, 0x5E, 0x9C, 0x1D];
+
+/// Bytes of the little-endian CRC-32 that follows the marker
+const CRC_LEN: usize = 4;
+
+/// Read size used to stream a segment through the decoder
+const SEGMENT_READ_SIZE: usize = 1024;
+
+/// A segment of the original input recovered by `decode_resync`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResyncSegment {
+    /// offset of the segment's first byte in the original input
+    pub offset: usize,
+    /// the decompressed bytes of the segment
+    pub data: Vec<u8>,
+}
+
+/// Compress `input` as independent `interval` byte segments, each stored as a record of
+/// `SYNC_MARKER`, the little-endian CRC-32 of the rest of the record, the segment's offset
+/// in `input` and its compressed length as LEB128 varints, and the compressed bytes.
+///
+/// Panics if `interval` is 0 or the window and lookahead sizes are invalid.
+pub fn encode_resync(input: &[u8], interval: usize, window_sz2: u8, lookahead_sz2: u8) -> Vec<u8> {
+    let (data, index) = encode_blocks(input, interval, window_sz2, lookahead_sz2);
+    let mut stream = Vec::with_capacity(data.len() + index.len() * 16);
+    let mut record = Vec::new();
+    for block in index {
+        record.clear();
+        write_varint(&mut record, block.uncompressed_offset);
+        write_varint(&mut record, block.compressed_len);
+        record.extend_from_slice(&data[block.compressed_offset..][..block.compressed_len]);
+        stream.extend_from_slice(&SYNC_MARKER);
+        stream.extend_from_slice(&crc32(&record).to_le_bytes());
+        stream.extend_from_slice(&record);
+    }
+    stream
+}
+
+/// Decompress the segments of a stream produced by `encode_resync`, skipping the damaged
+/// ones.
+///
+/// A record whose CRC does not match, or that does not decode, is dropped and decoding
+/// resumes at the next `SYNC_MARKER`. The segments that were lost show as gaps between the
+/// `offset` and `data` of consecutive segments, and a lost last segment as a shortfall
+/// against the input length if the caller knows it.
+pub fn decode_resync(data: &[u8], window_sz2: u8, lookahead_sz2: u8) -> Vec<ResyncSegment> {
+    let mut segments = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = find_marker(&data[pos..]) {
+        let start = pos + found + SYNC_MARKER.len();
+        let segment = split_record(&data[start..]).and_then(|(offset, compressed, len)| {
+            let data = decode_all(
+                compressed,
+                SEGMENT_READ_SIZE,
+                window_sz2,
+                lookahead_sz2,
+                SEGMENT_READ_SIZE,
+            )
+            .ok()?;
+            Some((ResyncSegment { offset, data }, len))
+        });
+        match segment {
+            Some((segment, len)) => {
+                segments.push(segment);
+                pos = start + len;
+            }
+            // the marker may be damaged data, so look for the next one right after it
+            None => pos += found + 1,
+        }
+    }
+    segments
+}
+
+/// Position of the first `SYNC_MARKER` in `data`
+fn find_marker(data: &[u8]) -> Option<usize> {
+    data.windows(SYNC_MARKER.len())
+        .position(|window| window == SYNC_MARKER)
+}
+
+/// Split the record after a marker at the front of `data` into its segment offset, its
+/// compressed bytes, and its length after the marker, or `None` if it is truncated or does
+/// not match its CRC
+fn split_record(data: &[u8]) -> Option<(usize, &[u8], usize)> {
+    let crc = u32::from_le_bytes(data.get(..CRC_LEN)?.try_into().ok()?);
+    let record = &data[CRC_LEN..];
+    let (offset, offset_len) = read_varint(record)?;
+    let (len, len_len) = read_varint(&record[offset_len..])?;
+    let header_len = offset_len + len_len;
+    let record = record.get(..header_len.checked_add(len)?)?;
+    if crc32(record) != crc {
+        return None;
+    }
+    Some((offset, &record[header_len..], CRC_LEN + record.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_after_bit_error() {
+        let input = &include_bytes!("heatshrink_encoder.rs")[..16 * 1024];
+        let interval = 2048;
+        let mut stream = encode_resync(input, interval, 8, 4);
+
+        let segments = decode_resync(&stream, 8, 4);
+        assert_eq!(segments.len(), input.len() / interval);
+        let decoded: Vec<u8> = segments.iter().flat_map(|s| s.data.clone()).collect();
+        assert_eq!(decoded, input);
+
+        // Flip one bit in the middle of segment 3, between its marker and the next one
+        let markers: Vec<usize> = (0..stream.len() - SYNC_MARKER.len())
+            .filter(|&i| stream[i..].starts_with(&SYNC_MARKER))
+            .collect();
+        assert_eq!(markers.len(), segments.len());
+        stream[(markers[3] + markers[4]) / 2] ^= 0x10;
+
+        // Damage the marker of segment 6 as well, so the decoder has to skip ahead to find 7
+        stream[markers[6] + 1] ^= 0xFF;
+
+        let segments = decode_resync(&stream, 8, 4);
+        let offsets: Vec<usize> = segments.iter().map(|s| s.offset).collect();
+        assert_eq!(
+            offsets,
+            [0, 1, 2, 4, 5, 7].map(|i| i * interval).to_vec(),
+            "only the damaged segments are lost"
+        );
+        for segment in &segments {
+            assert_eq!(
+                segment.data,
+                &input[segment.offset..segment.offset + interval]
+            );
+        }
+    }
+}
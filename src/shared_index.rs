@@ -0,0 +1,116 @@
+//!
+//! Compress many slices of one large read-only buffer against a search index that is built
+//! once over the whole buffer.
+//!
+//! Every `encode_all` call indexes its input from scratch. When the inputs are overlapping
+//! slices of the same buffer, e.g. records cut out of a log or a firmware image at many
+//! offsets, `SharedIndex` links every byte of the buffer once and `encode_slice` only walks
+//! the links that fall inside the slice. Each slice is still compressed as a standalone
+//! heatshrink stream that any decoder with the same window and lookahead sizes can read.
+//!
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use core::ops::Range;
+
+use crate::{
+    common::BitWriter,
+    heatshrink_encoder::{break_even_point, longest_match, valid_params, Link},
+};
+
+/// A search index over a borrowed buffer, shared by `encode_slice` calls on its slices
+pub struct SharedIndex<'a> {
+    data: &'a [u8],
+    /// previous position holding the same byte as each position of `data`
+    links: Vec<u32>,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+}
+
+impl<'a> SharedIndex<'a> {
+    /// Index all of `data` for the given window and lookahead sizes. Returns `None` if the
+    /// sizes are invalid or `data` does not fit a 32-bit index.
+    pub fn new(data: &'a [u8], window_sz2: u8, lookahead_sz2: u8) -> Option<Self> {
+        if !valid_params(window_sz2, lookahead_sz2) || data.len() >= u32::NONE as usize {
+            return None;
+        }
+        let mut last = [u32::NONE; 256];
+        let links = data
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| core::mem::replace(&mut last[v as usize], i as u32))
+            .collect();
+        Some(Self {
+            data,
+            links,
+            window_sz2,
+            lookahead_sz2,
+        })
+    }
+
+    /// The indexed buffer
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Compress `data()[range]` as a standalone stream, the same one `encode_all` produces
+    /// for it. Matches never reach in front of `range.start`, where the decoder has no data.
+    ///
+    /// Panics if `range` is out of bounds.
+    pub fn encode_slice(&self, range: Range<usize>) -> Vec<u8> {
+        let buf = &self.data[..range.end];
+        let Range { start, end } = range;
+        let window_length = 1 << self.window_sz2;
+        let lookahead_sz = 1 << self.lookahead_sz2;
+        let break_even = break_even_point(self.window_sz2, self.lookahead_sz2);
+
+        let mut out = BitWriter::with_capacity((end - start) / 2);
+        let mut pos = start;
+        while pos < end {
+            let from = pos.saturating_sub(window_length).max(start);
+            let maxlen = lookahead_sz.min(end - pos);
+            let (match_pos, match_len) = longest_match(buf, &self.links, from, pos, maxlen);
+            if match_len > break_even {
+                out.push(0, 1);
+                out.push(pos - match_pos - 1, self.window_sz2);
+                out.push(match_len - 1, self.lookahead_sz2);
+                pos += match_len;
+            } else {
+                out.push(1, 1);
+                out.push(buf[pos] as usize, 8);
+                pos += 1;
+            }
+        }
+        out.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_all;
+
+    #[test]
+    fn slices_roundtrip() {
+        let data = &include_bytes!("heatshrink_encoder.rs")[..24 * 1024];
+        for (window_sz2, lookahead_sz2) in [(4, 3), (8, 4), (11, 6), (15, 14)] {
+            let index = SharedIndex::new(data, window_sz2, lookahead_sz2).unwrap();
+            for range in [0..0, 0..1, 100..5000, 777..20_000, 12_000..data.len()] {
+                let compressed = index.encode_slice(range.clone());
+                assert_eq!(
+                    compressed,
+                    crate::encode_all(&data[range.clone()], window_sz2, lookahead_sz2, 512),
+                    "w{window_sz2} l{lookahead_sz2} {range:?}"
+                );
+                let decoded = decode_all(&compressed, 512, window_sz2, lookahead_sz2, 512).unwrap();
+                assert_eq!(decoded, &data[range]);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_params() {
+        assert!(SharedIndex::new(b"abc", 8, 8).is_none());
+        assert!(SharedIndex::new(b"abc", 3, 3).is_none());
+    }
+}
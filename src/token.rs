@@ -0,0 +1,17 @@
+/// A single decision in the compressed stream, as reported to a token observer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Token {
+    /// a byte emitted as-is
+    Literal(u8),
+    /// a copy of `length` bytes starting `distance` bytes back in the window
+    Backref { distance: Distance, length: usize },
+}
+
+/// Backreference distance, `u32` with the `wide-window` feature so that the distances of a
+/// 64 KiB window fit
+#[cfg(not(feature = "wide-window"))]
+pub type Distance = u16;
+/// Backreference distance, `u32` with the `wide-window` feature so that the distances of a
+/// 64 KiB window fit
+#[cfg(feature = "wide-window")]
+pub type Distance = u32;
@@ -0,0 +1,61 @@
+//!
+//! Checks that `Codec::encode` stores tiny inputs without allocating an encoder, by counting
+//! the bytes allocated through a global allocator. Kept in its own test binary with a single
+//! test, so no other test allocates while one is counted.
+//!
+use embedded_heatshrink::{Codec, DEFAULT_WINDOW_BITS, STORED_INPUT_LEN, STORED_MARKER};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The system allocator, adding the size of every allocation to `ALLOCATED`
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size.saturating_sub(layout.size()), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Bytes allocated while encoding `input`, and the encoded output
+fn allocated_by_encode(codec: &Codec, input: &[u8]) -> (usize, Vec<u8>) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let encoded = codec.encode(input);
+    (ALLOCATED.load(Ordering::Relaxed) - before, encoded)
+}
+
+#[test]
+fn tiny_inputs_skip_the_encoder_buffers() {
+    let codec = Codec::default();
+    // The encoder allocates a buffer and a search index of 2 << window_sz2 entries each
+    let encoder_buffers = 2 << DEFAULT_WINDOW_BITS;
+
+    for payload in [&b"a"[..], b"\x00\x00", b"abc"] {
+        let (allocated, stored) = allocated_by_encode(&codec, payload);
+        assert_eq!(stored[0], STORED_MARKER);
+        assert_eq!(allocated, 1 + payload.len(), "{payload:?}");
+    }
+
+    // The first input long enough to compress creates an encoder, which the counter sees
+    let payload = vec![b'a'; STORED_INPUT_LEN];
+    let (allocated, compressed) = allocated_by_encode(&codec, &payload);
+    assert_ne!(compressed[0], STORED_MARKER);
+    assert!(
+        allocated >= encoder_buffers,
+        "allocated {allocated} of {encoder_buffers} bytes"
+    );
+}
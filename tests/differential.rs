@@ -0,0 +1,189 @@
+//!
+//! Differential tests against a reference heatshrink.
+//!
+//! `reference` is a direct transcription of the C library's greedy search and its decoder,
+//! written for clarity instead of speed: every match is found by scanning the whole window
+//! from the nearest position back, and the longest match wins, the nearest one on ties.
+//!
+//! Intentional divergences from the C library:
+//!
+//! - The C encoder starts with a zero-filled window and matches against it, so a stream
+//!   that starts with zeros may begin with a backref into bytes that were never written.
+//!   This crate never reaches in front of the input, so its output does not depend on the
+//!   decoder zero-initializing the window. `encode` takes `zero_window` to model either
+//!   encoder: without it the output must match `encode_all` byte for byte, with it the
+//!   output must still decode with `decode_all`.
+//! - With `HeatshrinkEncoder::set_literal_runs` the encoder emits run headers that the C
+//!   decoder does not understand. `encode_all` never enables them, so its output is
+//!   checked against the reference with or without the `literal-runs` feature.
+//!
+use embedded_heatshrink::{decode_all, encode_all};
+use proptest::prelude::*;
+
+mod reference {
+    /// MSB-first bit writer, the last byte padded with zero bits
+    #[derive(Default)]
+    struct Bits {
+        bytes: Vec<u8>,
+        count: usize,
+    }
+
+    impl Bits {
+        fn push(&mut self, value: usize, count: u8) {
+            for i in (0..count).rev() {
+                if self.count.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                if (value >> i) & 1 == 1 {
+                    *self.bytes.last_mut().unwrap() |= 0x80 >> (self.count % 8);
+                }
+                self.count += 1;
+            }
+        }
+    }
+
+    /// Compress `input` like the C encoder. With `zero_window`, matches may reach into a
+    /// zero-filled window in front of the input, as they do in C.
+    pub fn encode(input: &[u8], window_sz2: u8, lookahead_sz2: u8, zero_window: bool) -> Vec<u8> {
+        let window = 1usize << window_sz2;
+        let lookahead = 1usize << lookahead_sz2;
+        let break_even = (1 + window_sz2 as usize + lookahead_sz2 as usize) / 8;
+
+        let pad = if zero_window { window } else { 0 };
+        let mut buf = vec![0u8; pad];
+        buf.extend_from_slice(input);
+
+        let mut out = Bits::default();
+        let mut pos = pad;
+        while pos < buf.len() {
+            let maxlen = lookahead.min(buf.len() - pos);
+            let (mut match_pos, mut match_len) = (0, 0);
+            for candidate in (pos.saturating_sub(window)..pos).rev() {
+                let len = (0..maxlen)
+                    .take_while(|&i| buf[candidate + i] == buf[pos + i])
+                    .count();
+                if len > match_len {
+                    (match_pos, match_len) = (candidate, len);
+                    if len == maxlen {
+                        break;
+                    }
+                }
+            }
+            if match_len > break_even {
+                out.push(0, 1);
+                out.push(pos - match_pos - 1, window_sz2);
+                out.push(match_len - 1, lookahead_sz2);
+                pos += match_len;
+            } else {
+                out.push(1, 1);
+                out.push(buf[pos] as usize, 8);
+                pos += 1;
+            }
+        }
+        out.bytes
+    }
+
+    /// Decompress `input` like the C decoder, with a zero-filled window in front of the
+    /// output. Returns `None` for a backref that reaches past the window.
+    pub fn decode(input: &[u8], window_sz2: u8, lookahead_sz2: u8) -> Option<Vec<u8>> {
+        let bits = input.len() * 8;
+        let mut pos = 0;
+        let mut read = |count: u8| {
+            let mut value = 0;
+            for _ in 0..count {
+                value = value << 1 | (input[pos / 8] >> (7 - pos % 8)) as usize & 1;
+                pos += 1;
+            }
+            value
+        };
+
+        let mut out: Vec<u8> = Vec::new();
+        let mut left = bits;
+        while left > 0 {
+            let backref_bits = 1 + window_sz2 as usize + lookahead_sz2 as usize;
+            if read(1) == 1 {
+                if left < 9 {
+                    break;
+                }
+                out.push(read(8) as u8);
+                left -= 9;
+            } else {
+                if left < backref_bits {
+                    break;
+                }
+                let distance = read(window_sz2) + 1;
+                let count = read(lookahead_sz2) + 1;
+                if distance > 1 << window_sz2 {
+                    return None;
+                }
+                for _ in 0..count {
+                    let byte = out.len().checked_sub(distance).map_or(0, |i| out[i]);
+                    out.push(byte);
+                }
+                left -= backref_bits;
+            }
+        }
+        Some(out)
+    }
+}
+
+/// Valid window and lookahead sizes of the standard format
+fn params() -> impl Strategy<Value = (u8, u8)> {
+    (4u8..=15).prop_flat_map(|window_sz2| (Just(window_sz2), 3..window_sz2))
+}
+
+/// Random bytes, and low-entropy bytes with zeros and long matches
+fn input() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        prop::collection::vec(any::<u8>(), 0..1024),
+        prop::collection::vec(0u8..4, 0..3072),
+        prop::collection::vec(prop::collection::vec(0u8..8, 1..64), 0..48).prop_map(|chunks| {
+            chunks
+                .iter()
+                .cycle()
+                .take(chunks.len() * 3)
+                .flatten()
+                .copied()
+                .collect()
+        }),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(96))]
+
+    #[test]
+    fn encoder_matches_reference(
+        input in input(),
+        (window_sz2, lookahead_sz2) in params(),
+        read_sz in 1usize..600,
+    ) {
+        let ours = encode_all(&input, window_sz2, lookahead_sz2, read_sz);
+        let theirs = reference::encode(&input, window_sz2, lookahead_sz2, false);
+
+        prop_assert_eq!(&ours, &theirs);
+        prop_assert_eq!(
+            reference::decode(&ours, window_sz2, lookahead_sz2),
+            Some(input.clone())
+        );
+        let decoded = decode_all(&ours, 256, window_sz2, lookahead_sz2, read_sz).unwrap();
+        prop_assert_eq!(&decoded, &input);
+        let decoded = decode_all(&theirs, 256, window_sz2, lookahead_sz2, read_sz).unwrap();
+        prop_assert_eq!(&decoded, &input);
+    }
+
+    #[test]
+    fn decoder_accepts_zero_window_streams(
+        input in input(),
+        (window_sz2, lookahead_sz2) in params(),
+        read_sz in 1usize..600,
+    ) {
+        let theirs = reference::encode(&input, window_sz2, lookahead_sz2, true);
+        prop_assert_eq!(
+            reference::decode(&theirs, window_sz2, lookahead_sz2),
+            Some(input.clone())
+        );
+        let decoded = decode_all(&theirs, 256, window_sz2, lookahead_sz2, read_sz).unwrap();
+        prop_assert_eq!(decoded, input);
+    }
+}